@@ -0,0 +1,44 @@
+use crate::frame::{FrameBuffer, FrameSize, BYTES_PER_PIXEL};
+
+pub struct FrameCompositor {
+    output: FrameBuffer,
+}
+
+const CLEAR_COLOR: [u8; 4] = [0, 0, 0, 255];
+
+impl FrameCompositor {
+    pub fn new(size: FrameSize) -> Self {
+        Self {
+            output: FrameBuffer::new(size),
+        }
+    }
+
+    pub fn process_frame(&mut self, frame: &FrameBuffer) -> &FrameBuffer {
+        // Frames that don't match our size are either padded or clamped,
+        // the same way the capture compositor handles window resizing.
+        let size = self.output.size;
+        let width = frame.size.width.min(size.width) as usize;
+        let height = frame.size.height.min(size.height);
+        let copy_len = width * BYTES_PER_PIXEL;
+        for y in 0..size.height {
+            let row = self.output.row_mut(y);
+            if y < height {
+                row[..copy_len].copy_from_slice(&frame.row(y)[..copy_len]);
+                clear(&mut row[copy_len..]);
+            } else {
+                clear(row);
+            }
+        }
+        &self.output
+    }
+
+    pub fn output(&self) -> &FrameBuffer {
+        &self.output
+    }
+}
+
+fn clear(bytes: &mut [u8]) {
+    for pixel in bytes.chunks_exact_mut(BYTES_PER_PIXEL) {
+        pixel.copy_from_slice(&CLEAR_COLOR);
+    }
+}
@@ -0,0 +1,552 @@
+use std::sync::Arc;
+
+use crate::{
+    backend::DiffThreshold,
+    frame::{DiffRect, FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+};
+
+use super::{
+    lut::{lab_to_i32, rgb2lab, PaletteIndexLUT},
+    regions::{DirtyTiles, TILE_SIZE},
+};
+
+/// Finds the area that changed between consecutive frames. This is the CPU
+/// equivalent of TextureDiff.hlsl.
+pub struct FrameDiffer {
+    // With a threshold, this only holds the pixels that counted as changed,
+    // so that small changes can't add up unnoticed.
+    previous_frame: FrameBuffer,
+    first_frame: bool,
+    frame_size: FrameSize,
+    tiles: DirtyTiles,
+    tolerance: Tolerance,
+}
+
+enum Tolerance {
+    Exact,
+    Channel(u8),
+    DeltaE {
+        lut: Arc<PaletteIndexLUT>,
+        // Whether each pair of palette indices is within the threshold, with
+        // 256 entries per index.
+        similar_colors: Vec<bool>,
+    },
+}
+
+impl Tolerance {
+    fn new(threshold: DiffThreshold, palette: &[u8], lut: Arc<PaletteIndexLUT>) -> Self {
+        match threshold {
+            DiffThreshold::Exact => Tolerance::Exact,
+            DiffThreshold::Channel(threshold) => Tolerance::Channel(threshold),
+            DiffThreshold::DeltaE(threshold) => {
+                let palette_lab: Vec<[i32; 3]> = palette
+                    .chunks_exact(3)
+                    .take(256)
+                    .map(|color| lab_to_i32(rgb2lab(color[0], color[1], color[2])))
+                    .collect();
+                let mut similar_colors = vec![false; 256 * 256];
+                for (i, first) in palette_lab.iter().enumerate() {
+                    for (j, second) in palette_lab.iter().enumerate() {
+                        let squared_distance: i32 =
+                            (0..3).map(|k| (first[k] - second[k]).pow(2)).sum();
+                        similar_colors[i * 256 + j] = (squared_distance as f32).sqrt() <= threshold;
+                    }
+                }
+                Tolerance::DeltaE {
+                    lut,
+                    similar_colors,
+                }
+            }
+        }
+    }
+
+    fn is_changed(&self, current: &[u8], previous: &[u8]) -> bool {
+        match self {
+            Tolerance::Exact => current != previous,
+            Tolerance::Channel(threshold) => current
+                .iter()
+                .zip(previous)
+                .any(|(current, previous)| current.abs_diff(*previous) > *threshold),
+            Tolerance::DeltaE {
+                lut,
+                similar_colors,
+            } => {
+                // Pixels are BGRA
+                let current = lut.lookup(current[2], current[1], current[0]) as usize;
+                let previous = lut.lookup(previous[2], previous[1], previous[0]) as usize;
+                !similar_colors[current * 256 + previous]
+            }
+        }
+    }
+}
+
+// Rows are compared in blocks, which lets the comparisons use SIMD and skip
+// over unchanged runs of pixels quickly.
+const BLOCK_SIZE: usize = 16 * BYTES_PER_PIXEL;
+
+impl FrameDiffer {
+    /// Creates a differ where any change to a pixel counts.
+    pub fn new(frame_size: FrameSize) -> Self {
+        Self {
+            previous_frame: FrameBuffer::new(frame_size),
+            first_frame: true,
+            frame_size,
+            tiles: DirtyTiles::new(frame_size),
+            tolerance: Tolerance::Exact,
+        }
+    }
+
+    /// Creates a differ that ignores changes below the threshold. CIELAB
+    /// thresholds compare the colors pixels are quantized to, using the given
+    /// palette and its LUT.
+    pub fn with_threshold(
+        frame_size: FrameSize,
+        threshold: DiffThreshold,
+        palette: &[u8],
+        lut: Arc<PaletteIndexLUT>,
+    ) -> Self {
+        Self {
+            tolerance: Tolerance::new(threshold, palette, lut),
+            ..Self::new(frame_size)
+        }
+    }
+
+    /// Compares the frame to the previous one. The first frame is always
+    /// reported as changed in its entirety, and `None` is returned if nothing
+    /// changed. Like TextureDiff.hlsl, right and bottom are the coordinates of
+    /// the last changed pixel.
+    pub fn process_frame(&mut self, frame: &FrameBuffer) -> Option<DiffRect> {
+        assert_eq!(
+            frame.size, self.frame_size,
+            "The frame size must match the size of the differ"
+        );
+        if self.first_frame {
+            self.first_frame = false;
+            self.copy_frame(frame);
+            Some(self.frame_size.full_rect())
+        } else {
+            let diff_rect = self.diff_frame(frame);
+            if let Some(bounds) = &diff_rect {
+                self.update_previous_frame(frame, bounds);
+            }
+            diff_rect
+        }
+    }
+
+    /// Like `process_frame`, but splits the changes into disjoint regions so
+    /// that unchanged areas between them don't need to be encoded. An empty
+    /// list is returned if nothing changed.
+    pub fn process_frame_regions(&mut self, frame: &FrameBuffer) -> Vec<DiffRect> {
+        assert_eq!(
+            frame.size, self.frame_size,
+            "The frame size must match the size of the differ"
+        );
+        if self.first_frame {
+            self.first_frame = false;
+            self.copy_frame(frame);
+            vec![self.frame_size.full_rect()]
+        } else if let Some(bounds) = self.diff_frame(frame) {
+            self.diff_tiles(frame, &bounds);
+            self.update_previous_frame(frame, &bounds);
+            self.tiles.regions()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn copy_frame(&mut self, frame: &FrameBuffer) {
+        for y in 0..self.frame_size.height {
+            self.previous_frame.row_mut(y).copy_from_slice(frame.row(y));
+        }
+    }
+
+    /// Stores the pixels within the bounds that counted as changed.
+    fn update_previous_frame(&mut self, frame: &FrameBuffer, bounds: &DiffRect) {
+        let begin = bounds.left as usize * BYTES_PER_PIXEL;
+        let end = (bounds.right as usize + 1) * BYTES_PER_PIXEL;
+        for y in bounds.top..=bounds.bottom {
+            let current_row = &frame.row(y)[begin..end];
+            let previous_row = &mut self.previous_frame.row_mut(y)[begin..end];
+            if let Tolerance::Exact = self.tolerance {
+                previous_row.copy_from_slice(current_row);
+                continue;
+            }
+            for (current, previous) in current_row
+                .chunks_exact(BYTES_PER_PIXEL)
+                .zip(previous_row.chunks_exact_mut(BYTES_PER_PIXEL))
+            {
+                if self.tolerance.is_changed(current, previous) {
+                    previous.copy_from_slice(current);
+                }
+            }
+        }
+    }
+
+    /// Finds the changes within each tile, only looking at the rows within
+    /// the bounds of all changes.
+    fn diff_tiles(&mut self, frame: &FrameBuffer, bounds: &DiffRect) {
+        self.tiles.clear();
+        let tile_bytes = TILE_SIZE as usize * BYTES_PER_PIXEL;
+        for y in bounds.top..=bounds.bottom {
+            let current_row = frame.row(y);
+            let previous_row = self.previous_frame.row(y);
+            for (column, (current, previous)) in current_row
+                .chunks(tile_bytes)
+                .zip(previous_row.chunks(tile_bytes))
+                .enumerate()
+            {
+                if let Some(first) = first_difference(current, previous, &self.tolerance) {
+                    let last = last_difference(current, previous, &self.tolerance).unwrap();
+                    let x = column as u32 * TILE_SIZE;
+                    self.tiles.add_changes(y, x + first as u32, x + last as u32);
+                }
+            }
+        }
+    }
+
+    fn diff_frame(&self, frame: &FrameBuffer) -> Option<DiffRect> {
+        let mut diff_rect: Option<DiffRect> = None;
+        for y in 0..self.frame_size.height {
+            let current_row = frame.row(y);
+            let previous_row = self.previous_frame.row(y);
+            if current_row == previous_row {
+                continue;
+            }
+            // Rows can differ without any change above the threshold
+            let Some(left) = first_difference(current_row, previous_row, &self.tolerance) else {
+                continue;
+            };
+
+            diff_rect = Some(if let Some(mut rect) = diff_rect {
+                // We only need to look for changes to the right of what we've
+                // already found.
+                rect.left = rect.left.min(left as u32);
+                let right = (rect.right as usize + 1) * BYTES_PER_PIXEL;
+                if let Some(x) = last_difference(
+                    &current_row[right..],
+                    &previous_row[right..],
+                    &self.tolerance,
+                ) {
+                    rect.right = (rect.right as usize + 1 + x) as u32;
+                }
+                rect.bottom = y;
+                rect
+            } else {
+                let right = last_difference(current_row, previous_row, &self.tolerance).unwrap();
+                DiffRect {
+                    left: left as u32,
+                    top: y,
+                    right: right as u32,
+                    bottom: y,
+                }
+            });
+        }
+        diff_rect
+    }
+}
+
+fn first_difference(current: &[u8], previous: &[u8], tolerance: &Tolerance) -> Option<usize> {
+    current
+        .chunks(BLOCK_SIZE)
+        .zip(previous.chunks(BLOCK_SIZE))
+        .enumerate()
+        .filter(|(_, (current, previous))| current != previous)
+        .find_map(|(block_index, (current_block, previous_block))| {
+            let pixel_index = current_block
+                .chunks_exact(BYTES_PER_PIXEL)
+                .zip(previous_block.chunks_exact(BYTES_PER_PIXEL))
+                .position(|(current, previous)| tolerance.is_changed(current, previous))?;
+            Some(block_index * (BLOCK_SIZE / BYTES_PER_PIXEL) + pixel_index)
+        })
+}
+
+fn last_difference(current: &[u8], previous: &[u8], tolerance: &Tolerance) -> Option<usize> {
+    current
+        .chunks(BLOCK_SIZE)
+        .zip(previous.chunks(BLOCK_SIZE))
+        .enumerate()
+        .rev()
+        .filter(|(_, (current, previous))| current != previous)
+        .find_map(|(block_index, (current_block, previous_block))| {
+            let pixel_index = current_block
+                .chunks_exact(BYTES_PER_PIXEL)
+                .zip(previous_block.chunks_exact(BYTES_PER_PIXEL))
+                .rposition(|(current, previous)| tolerance.is_changed(current, previous))?;
+            Some(block_index * (BLOCK_SIZE / BYTES_PER_PIXEL) + pixel_index)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, OnceLock};
+
+    use super::FrameDiffer;
+    use crate::{
+        backend::{cpu::PaletteIndexLUT, DiffThreshold},
+        frame::{DiffRect, FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+    };
+
+    const SIZE: FrameSize = FrameSize {
+        width: 101,
+        height: 37,
+    };
+    const SIZE_LARGE: FrameSize = FrameSize {
+        width: 640,
+        height: 480,
+    };
+
+    fn set_pixel(frame: &mut FrameBuffer, x: u32, y: u32, color: [u8; 4]) {
+        let begin = x as usize * BYTES_PER_PIXEL;
+        frame.row_mut(y)[begin..begin + BYTES_PER_PIXEL].copy_from_slice(&color);
+    }
+
+    fn assert_rect(rect: Option<DiffRect>, left: u32, top: u32, right: u32, bottom: u32) {
+        let rect = rect.expect("Expected a diff rect");
+        assert_eq!(
+            (rect.left, rect.top, rect.right, rect.bottom),
+            (left, top, right, bottom)
+        );
+    }
+
+    #[test]
+    fn first_frame_is_full_rect() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let frame = FrameBuffer::new(SIZE);
+        assert_rect(differ.process_frame(&frame), 0, 0, SIZE.width, SIZE.height);
+    }
+
+    #[test]
+    fn identical_frames_have_no_diff() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let mut frame = FrameBuffer::new(SIZE);
+        set_pixel(&mut frame, 3, 4, [1, 2, 3, 255]);
+        differ.process_frame(&frame);
+        assert!(differ.process_frame(&frame).is_none());
+        assert!(differ.process_frame(&frame.clone()).is_none());
+    }
+
+    #[test]
+    fn single_pixel_change() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+        set_pixel(&mut frame, 42, 17, [0, 0, 255, 255]);
+        assert_rect(differ.process_frame(&frame), 42, 17, 42, 17);
+        // The previous frame is updated after every call.
+        assert!(differ.process_frame(&frame).is_none());
+    }
+
+    #[test]
+    fn multiple_changes_are_bounded() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+        set_pixel(&mut frame, 70, 2, [255, 0, 0, 255]);
+        set_pixel(&mut frame, 5, 10, [255, 0, 0, 255]);
+        set_pixel(&mut frame, 90, 30, [255, 0, 0, 255]);
+        set_pixel(&mut frame, 20, 31, [255, 0, 0, 255]);
+        assert_rect(differ.process_frame(&frame), 5, 2, 90, 31);
+    }
+
+    #[test]
+    fn changes_at_the_edges() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+        set_pixel(&mut frame, 0, 0, [1, 1, 1, 1]);
+        set_pixel(&mut frame, SIZE.width - 1, SIZE.height - 1, [1, 1, 1, 1]);
+        assert_rect(
+            differ.process_frame(&frame),
+            0,
+            0,
+            SIZE.width - 1,
+            SIZE.height - 1,
+        );
+    }
+
+    #[test]
+    fn alpha_changes_are_detected() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+        set_pixel(&mut frame, 64, 8, [0, 0, 0, 1]);
+        assert_rect(differ.process_frame(&frame), 64, 8, 64, 8);
+    }
+
+    #[test]
+    fn stride_padding_is_ignored() {
+        let mut differ = FrameDiffer::new(SIZE);
+        let stride = SIZE.width as usize * BYTES_PER_PIXEL + 12;
+        let bytes = vec![0u8; stride * SIZE.height as usize];
+        let mut frame = FrameBuffer::from_bytes(SIZE, stride, bytes).unwrap();
+        differ.process_frame(&frame);
+
+        // Garbage in the padding at the end of each row isn't part of the frame.
+        for y in 0..SIZE.height as usize {
+            let padding_begin = y * stride + SIZE.width as usize * BYTES_PER_PIXEL;
+            for byte in &mut frame.bytes[padding_begin..(y + 1) * stride] {
+                *byte = 0xAB;
+            }
+        }
+        assert!(differ.process_frame(&frame).is_none());
+
+        set_pixel(&mut frame, 100, 36, [9, 9, 9, 9]);
+        assert_rect(differ.process_frame(&frame), 100, 36, 100, 36);
+    }
+
+    #[test]
+    fn distant_changes_are_separate_regions() {
+        let mut differ = FrameDiffer::new(SIZE_LARGE);
+        let mut frame = FrameBuffer::new(SIZE_LARGE);
+        assert_eq!(differ.process_frame_regions(&frame).len(), 1);
+        assert!(differ.process_frame_regions(&frame).is_empty());
+
+        // A cursor in one corner and a clock in the other
+        set_pixel(&mut frame, 3, 4, [255, 255, 255, 255]);
+        set_pixel(&mut frame, 3, 5, [255, 255, 255, 255]);
+        set_pixel(&mut frame, 600, 450, [255, 255, 255, 255]);
+        set_pixel(&mut frame, 610, 460, [255, 255, 255, 255]);
+        let mut regions = differ.process_frame_regions(&frame);
+        regions.sort_by_key(|region| region.left);
+        assert_eq!(regions.len(), 2);
+        assert_rect(Some(regions[0]), 3, 4, 3, 5);
+        assert_rect(Some(regions[1]), 600, 450, 610, 460);
+    }
+
+    #[test]
+    fn nearby_changes_are_merged() {
+        let mut differ = FrameDiffer::new(SIZE_LARGE);
+        let mut frame = FrameBuffer::new(SIZE_LARGE);
+        differ.process_frame_regions(&frame);
+
+        // Separate regions for these would cost more than encoding the gap
+        set_pixel(&mut frame, 100, 100, [255, 255, 255, 255]);
+        set_pixel(&mut frame, 140, 100, [255, 255, 255, 255]);
+        set_pixel(&mut frame, 100, 140, [255, 255, 255, 255]);
+        let regions = differ.process_frame_regions(&frame);
+        assert_eq!(regions.len(), 1);
+        assert_rect(Some(regions[0]), 100, 100, 140, 140);
+    }
+
+    #[test]
+    fn regions_are_disjoint_and_cover_every_change() {
+        let mut differ = FrameDiffer::new(SIZE_LARGE);
+        let mut frame = FrameBuffer::new(SIZE_LARGE);
+        differ.process_frame_regions(&frame);
+
+        let mut changes = Vec::new();
+        let mut seed = 7u32;
+        for _ in 0..40 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let x = (seed >> 8) % SIZE_LARGE.width;
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let y = (seed >> 8) % SIZE_LARGE.height;
+            set_pixel(&mut frame, x, y, [1, 2, 3, 4]);
+            changes.push((x, y));
+        }
+        let regions = differ.process_frame_regions(&frame);
+        assert!(!regions.is_empty());
+        for (i, a) in regions.iter().enumerate() {
+            for b in &regions[i + 1..] {
+                let overlaps = a.left <= b.right
+                    && b.left <= a.right
+                    && a.top <= b.bottom
+                    && b.top <= a.bottom;
+                assert!(!overlaps, "{:?} overlaps {:?}", a, b);
+            }
+        }
+        for (x, y) in changes {
+            assert!(regions.iter().any(|region| region.left <= x
+                && x <= region.right
+                && region.top <= y
+                && y <= region.bottom));
+        }
+    }
+
+    const GRAYS: [u8; 9] = [0, 0, 0, 12, 12, 12, 255, 255, 255];
+
+    fn threshold_differ(threshold: DiffThreshold) -> FrameDiffer {
+        // Building a LUT is slow in debug builds
+        static LUT: OnceLock<Arc<PaletteIndexLUT>> = OnceLock::new();
+        let lut = LUT.get_or_init(|| Arc::new(PaletteIndexLUT::new(&GRAYS)));
+        FrameDiffer::with_threshold(SIZE, threshold, &GRAYS, lut.clone())
+    }
+
+    #[test]
+    fn channel_threshold_ignores_small_changes() {
+        let mut differ = threshold_differ(DiffThreshold::Channel(4));
+        let mut frame = FrameBuffer::new(SIZE);
+        set_pixel(&mut frame, 10, 10, [100, 100, 100, 255]);
+        differ.process_frame(&frame);
+
+        set_pixel(&mut frame, 10, 10, [104, 96, 100, 255]);
+        assert!(differ.process_frame(&frame).is_none());
+        set_pixel(&mut frame, 10, 10, [100, 100, 105, 255]);
+        assert_rect(differ.process_frame(&frame), 10, 10, 10, 10);
+    }
+
+    #[test]
+    fn small_changes_add_up() {
+        let mut differ = threshold_differ(DiffThreshold::Channel(4));
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+
+        // Each step is below the threshold, but not the total
+        set_pixel(&mut frame, 50, 20, [3, 3, 3, 0]);
+        assert!(differ.process_frame(&frame).is_none());
+        set_pixel(&mut frame, 50, 20, [6, 6, 6, 0]);
+        assert_rect(differ.process_frame(&frame), 50, 20, 50, 20);
+        set_pixel(&mut frame, 50, 20, [8, 8, 8, 0]);
+        assert!(differ.process_frame(&frame).is_none());
+    }
+
+    #[test]
+    fn delta_e_threshold_compares_quantized_colors() {
+        let mut differ = threshold_differ(DiffThreshold::DeltaE(5.0));
+        let mut frame = FrameBuffer::new(SIZE);
+        differ.process_frame(&frame);
+
+        // Quantizes to the same color
+        set_pixel(&mut frame, 30, 5, [2, 1, 3, 255]);
+        assert!(differ.process_frame(&frame).is_none());
+        // Quantizes to a color close to the previous one
+        set_pixel(&mut frame, 30, 5, [12, 12, 12, 255]);
+        assert!(differ.process_frame(&frame).is_none());
+        set_pixel(&mut frame, 30, 5, [250, 250, 250, 255]);
+        assert_rect(differ.process_frame(&frame), 30, 5, 30, 5);
+    }
+
+    #[test]
+    fn large_frames_match_a_naive_diff() {
+        let size = FrameSize::new(3840, 2160);
+        let mut differ = FrameDiffer::new(size);
+        let mut frame = FrameBuffer::new(size);
+        differ.process_frame(&frame);
+
+        let changes = [(1920, 1080), (17, 2000), (3839, 5), (2500, 2159)];
+        let mut expected = DiffRect {
+            left: size.width,
+            top: size.height,
+            right: 0,
+            bottom: 0,
+        };
+        for &(x, y) in changes.iter() {
+            set_pixel(&mut frame, x, y, [255, 255, 255, 255]);
+            expected.left = expected.left.min(x);
+            expected.top = expected.top.min(y);
+            expected.right = expected.right.max(x);
+            expected.bottom = expected.bottom.max(y);
+            assert_rect(differ.process_frame(&frame), x, y, x, y);
+        }
+
+        let mut differ = FrameDiffer::new(size);
+        differ.process_frame(&FrameBuffer::new(size));
+        assert_rect(
+            differ.process_frame(&frame),
+            expected.left,
+            expected.top,
+            expected.right,
+            expected.bottom,
+        );
+    }
+}
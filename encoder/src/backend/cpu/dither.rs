@@ -0,0 +1,175 @@
+use crate::frame::{DiffRect, FrameBuffer, BYTES_PER_PIXEL};
+
+use super::lut::PaletteIndexLUT;
+
+/// How the quantization error of a pixel is spread to the pixels after it.
+pub(crate) struct Kernel {
+    /// The (x, y) offset of each neighbor along with its share of the error.
+    weights: &'static [(isize, usize, i32)],
+    divisor: i32,
+}
+
+pub(crate) const FLOYD_STEINBERG: Kernel = Kernel {
+    weights: &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+    divisor: 16,
+};
+
+pub(crate) const ATKINSON: Kernel = Kernel {
+    weights: &[
+        (1, 0, 1),
+        (2, 0, 1),
+        (-1, 1, 1),
+        (0, 1, 1),
+        (1, 1, 1),
+        (0, 2, 1),
+    ],
+    divisor: 8,
+};
+
+pub(crate) const SIERRA: Kernel = Kernel {
+    weights: &[
+        (1, 0, 5),
+        (2, 0, 3),
+        (-2, 1, 2),
+        (-1, 1, 4),
+        (0, 1, 5),
+        (1, 1, 4),
+        (2, 1, 2),
+        (-1, 2, 2),
+        (0, 2, 3),
+        (1, 2, 2),
+    ],
+    divisor: 32,
+};
+
+// How far the kernels reach to either side, and how many rows they cover.
+const MAX_SPREAD: usize = 2;
+const KERNEL_ROWS: usize = 3;
+
+/// Quantizes the given area of the frame, diffusing the error of every pixel
+/// to its neighbors within the area. Pixels outside of it are never touched,
+/// so the error doesn't depend on anything but the area itself.
+pub(crate) fn diffuse_errors(
+    frame: &FrameBuffer,
+    rect: &DiffRect,
+    lut: &PaletteIndexLUT,
+    palette: &[u8],
+    kernel: &Kernel,
+) -> Vec<u8> {
+    let width = rect.width() as usize;
+    let padded_width = width + 2 * MAX_SPREAD;
+
+    // The weighted error each pixel received, for the current row and the rows
+    // below it. Padding on both sides catches error spread past the edges.
+    let mut errors = vec![[0i32; 3]; padded_width * KERNEL_ROWS];
+
+    let mut indices = Vec::with_capacity(width * rect.height() as usize);
+    let begin = rect.left as usize * BYTES_PER_PIXEL;
+    let end = rect.right as usize * BYTES_PER_PIXEL;
+    for y in rect.top..rect.bottom {
+        let row = &frame.row(y)[begin..end];
+        for (x, pixel) in row.chunks_exact(BYTES_PER_PIXEL).enumerate() {
+            // Pixels are BGRA
+            let error = errors[x + MAX_SPREAD];
+            let color = [
+                apply_error(pixel[2], error[0], kernel.divisor),
+                apply_error(pixel[1], error[1], kernel.divisor),
+                apply_error(pixel[0], error[2], kernel.divisor),
+            ];
+            let index = lut.lookup(color[0], color[1], color[2]);
+            indices.push(index);
+
+            let position = index as usize * 3;
+            let error = [
+                color[0] as i32 - palette[position] as i32,
+                color[1] as i32 - palette[position + 1] as i32,
+                color[2] as i32 - palette[position + 2] as i32,
+            ];
+            for &(dx, dy, weight) in kernel.weights {
+                let neighbor =
+                    &mut errors[dy * padded_width + (x + MAX_SPREAD).wrapping_add_signed(dx)];
+                for (neighbor, error) in neighbor.iter_mut().zip(error) {
+                    *neighbor += error * weight;
+                }
+            }
+        }
+
+        // Move on to the next row's errors
+        errors.copy_within(padded_width.., 0);
+        let last_row = padded_width * (KERNEL_ROWS - 1);
+        errors[last_row..].fill([0; 3]);
+    }
+    indices
+}
+
+fn apply_error(channel: u8, error: i32, divisor: i32) -> u8 {
+    (channel as i32 + error / divisor).clamp(0, 255) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use super::{diffuse_errors, Kernel, ATKINSON, FLOYD_STEINBERG, SIERRA};
+    use crate::{
+        backend::cpu::PaletteIndexLUT,
+        frame::{DiffRect, FrameBuffer, FrameSize},
+    };
+
+    const BLACK_AND_WHITE: [u8; 6] = [0, 0, 0, 255, 255, 255];
+    const SIZE: FrameSize = FrameSize {
+        width: 64,
+        height: 48,
+    };
+
+    fn black_and_white_lut() -> &'static PaletteIndexLUT {
+        static LUT: OnceLock<PaletteIndexLUT> = OnceLock::new();
+        LUT.get_or_init(|| PaletteIndexLUT::new(&BLACK_AND_WHITE))
+    }
+
+    fn gray_frame(value: u8) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(SIZE);
+        frame.bytes.fill(value);
+        frame
+    }
+
+    fn quantize(frame: &FrameBuffer, rect: &DiffRect, kernel: &Kernel) -> Vec<u8> {
+        diffuse_errors(frame, rect, black_and_white_lut(), &BLACK_AND_WHITE, kernel)
+    }
+
+    #[test]
+    fn gray_keeps_its_brightness() {
+        let frame = gray_frame(64);
+        for kernel in [&FLOYD_STEINBERG, &SIERRA] {
+            let indices = quantize(&frame, &SIZE.full_rect(), kernel);
+            let white = indices.iter().filter(|index| **index == 1).count();
+            let ratio = white as f64 / indices.len() as f64;
+            assert!((ratio - 0.25).abs() < 0.02, "{} white", ratio);
+        }
+
+        // Atkinson drops some of the error, but still mixes both colors
+        let indices = quantize(&frame, &SIZE.full_rect(), &ATKINSON);
+        assert!(indices.contains(&0) && indices.contains(&1));
+    }
+
+    #[test]
+    fn pixels_outside_the_rect_are_ignored() {
+        let rect = DiffRect {
+            left: 10,
+            top: 5,
+            right: 30,
+            bottom: 20,
+        };
+        let mut frame = gray_frame(100);
+        let expected = quantize(&frame, &rect, &FLOYD_STEINBERG);
+        assert_eq!(expected.len(), 20 * 15);
+
+        for y in 0..SIZE.height {
+            for x in (0..SIZE.width).filter(|x| *x < rect.left || *x >= rect.right) {
+                let begin = x as usize * 4;
+                frame.row_mut(y)[begin..begin + 4].fill(255);
+            }
+        }
+        assert_eq!(quantize(&frame, &rect, &FLOYD_STEINBERG), expected);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    sync::atomic::{AtomicU16, Ordering},
+    thread,
+};
+
+/// A 256x256x256 lookup table that maps a color to the index of the closest
+/// color in a palette. This is the CPU equivalent of LUTGeneration.hlsl.
+///
+/// The table is addressed by (r, g, b), with red varying fastest. This is the
+/// same layout as the 3D texture generated by the shader, where the texel at
+/// (x, y, z) holds the palette index for the color (r, g, b).
+pub struct PaletteIndexLUT {
+    lut: Vec<u8>,
+}
+
+const LUT_SIZE: usize = 256 * 256 * 256;
+const LUT_PLANE_SIZE: usize = 256 * 256;
+
+// Colors are compared in CIELAB space after rounding each component, so the
+// closest palette index for a color only depends on its rounded Lab value.
+// Caching by Lab value means we only search the palette once for every
+// distinct Lab value instead of once for every color.
+const LAB_L_SIZE: usize = 101;
+const LAB_AB_SIZE: usize = 256;
+const LAB_CACHE_SIZE: usize = LAB_L_SIZE * LAB_AB_SIZE * LAB_AB_SIZE;
+const LAB_CACHE_EMPTY: u16 = u16::MAX;
+
+impl PaletteIndexLUT {
+    pub fn new(palette: &[u8]) -> Self {
+        let palette_lab: Vec<[i32; 3]> = palette
+            .chunks_exact(3)
+            .take(256)
+            .map(|color| lab_to_i32(rgb2lab(color[0], color[1], color[2])))
+            .collect();
+        let lab_cache: Vec<AtomicU16> = (0..LAB_CACHE_SIZE)
+            .map(|_| AtomicU16::new(LAB_CACHE_EMPTY))
+            .collect();
+
+        // Split the LUT into runs of whole planes, one for each thread.
+        let thread_count = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+            .min(256);
+        let planes_per_thread = 256usize.div_ceil(thread_count);
+        let chunk_size = planes_per_thread * LUT_PLANE_SIZE;
+
+        let mut lut = vec![0u8; LUT_SIZE];
+        thread::scope(|scope| {
+            for (chunk_index, chunk) in lut.chunks_mut(chunk_size).enumerate() {
+                let palette_lab = &palette_lab;
+                let lab_cache = &lab_cache;
+                scope.spawn(move || {
+                    let offset = chunk_index * chunk_size;
+                    for (i, index) in chunk.iter_mut().enumerate() {
+                        let (r, g, b) = lut_position_to_color(offset + i);
+                        *index = lookup_closest_color(palette_lab, lab_cache, r, g, b);
+                    }
+                });
+            }
+        });
+
+        Self { lut }
+    }
+
+    pub fn lookup(&self, r: u8, g: u8, b: u8) -> u8 {
+        self.lut[color_to_lut_position(r, g, b)]
+    }
+
+    /// The raw table, laid out as described on [`PaletteIndexLUT`].
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.lut
+    }
+}
+
+fn color_to_lut_position(r: u8, g: u8, b: u8) -> usize {
+    ((b as usize) << 16) | ((g as usize) << 8) | r as usize
+}
+
+fn lut_position_to_color(position: usize) -> (u8, u8, u8) {
+    let r = (position & 0xFF) as u8;
+    let g = ((position >> 8) & 0xFF) as u8;
+    let b = ((position >> 16) & 0xFF) as u8;
+    (r, g, b)
+}
+
+fn lookup_closest_color(
+    palette_lab: &[[i32; 3]],
+    lab_cache: &[AtomicU16],
+    r: u8,
+    g: u8,
+    b: u8,
+) -> u8 {
+    let color_lab = lab_to_i32(rgb2lab(r, g, b));
+    let cache_index = {
+        let l = color_lab[0].clamp(0, LAB_L_SIZE as i32 - 1) as usize;
+        let a = (color_lab[1] + 128).clamp(0, LAB_AB_SIZE as i32 - 1) as usize;
+        let b = (color_lab[2] + 128).clamp(0, LAB_AB_SIZE as i32 - 1) as usize;
+        (l * LAB_AB_SIZE + a) * LAB_AB_SIZE + b
+    };
+    // Multiple threads may race to fill the same entry, but they will all
+    // compute the same value.
+    let cached = lab_cache[cache_index].load(Ordering::Relaxed);
+    if cached != LAB_CACHE_EMPTY {
+        return cached as u8;
+    }
+    let index = find_closest_color(palette_lab, color_lab);
+    lab_cache[cache_index].store(index as u16, Ordering::Relaxed);
+    index
+}
+
+pub(crate) fn find_closest_color(palette_lab: &[[i32; 3]], color_lab: [i32; 3]) -> u8 {
+    // Lab components are whole numbers, so comparing squared distances gives
+    // the same result as comparing the distances the shader computes.
+    let mut min_distance = i32::MAX;
+    let mut closest_color_index = 0;
+    for (i, palette_color_lab) in palette_lab.iter().enumerate() {
+        let distance = compute_squared_distance(&color_lab, palette_color_lab);
+        if distance < min_distance {
+            min_distance = distance;
+            closest_color_index = i;
+        }
+        if distance == 0 {
+            break;
+        }
+    }
+    closest_color_index as u8
+}
+
+fn compute_rgb_channel(channel: u8) -> f32 {
+    let mut result = channel as f32 / 255.0;
+    if result > 0.04045 {
+        result = ((result + 0.055) / 1.055).powf(2.4);
+    } else {
+        result /= 12.92;
+    }
+    100.0 * result
+}
+
+fn compute_xyz_channel(channel: f32) -> f32 {
+    if channel > 0.008856 {
+        channel.powf(1.0 / 3.0)
+    } else {
+        (7.787 * channel) + (16.0 / 116.0)
+    }
+}
+
+/// Converts an sRGB color to CIELAB, rounding each component to the nearest
+/// whole number the same way HLSL's `round` does.
+pub fn rgb2lab(r: u8, g: u8, b: u8) -> [f32; 3] {
+    let r = compute_rgb_channel(r);
+    let g = compute_rgb_channel(g);
+    let b = compute_rgb_channel(b);
+
+    let x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+    let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+    let z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+
+    // Observer= 2°, Illuminant= D65
+    let x = compute_xyz_channel(x / 95.0470);
+    let y = compute_xyz_channel(y / 100.0);
+    let z = compute_xyz_channel(z / 108.883);
+
+    [
+        ((116.0 * y) - 16.0).round_ties_even(), // L
+        (500.0 * (x - y)).round_ties_even(),    // a
+        (200.0 * (y - z)).round_ties_even(),    // b
+    ]
+}
+
+pub(crate) fn lab_to_i32(lab: [f32; 3]) -> [i32; 3] {
+    [lab[0] as i32, lab[1] as i32, lab[2] as i32]
+}
+
+fn compute_squared_distance(point1: &[i32; 3], point2: &[i32; 3]) -> i32 {
+    (point1[0] - point2[0]).pow(2) + (point1[1] - point2[1]).pow(2) + (point1[2] - point2[2]).pow(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+
+    use super::PaletteIndexLUT;
+    use crate::encoder::palette::DEFAULT_PALETTE;
+
+    fn default_palette_lut() -> &'static PaletteIndexLUT {
+        static LUT: OnceLock<PaletteIndexLUT> = OnceLock::new();
+        LUT.get_or_init(|| PaletteIndexLUT::new(&DEFAULT_PALETTE))
+    }
+
+    fn fnv1a(bytes: &[u8]) -> u64 {
+        let mut hash = 0xcbf29ce484222325u64;
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    #[test]
+    fn default_palette_lut_is_stable() {
+        let lut = default_palette_lut();
+        assert_eq!(lut.as_bytes().len(), 256 * 256 * 256);
+        assert_eq!(fnv1a(lut.as_bytes()), 9554674860133271619);
+    }
+
+    #[test]
+    fn palette_colors_map_to_themselves() {
+        let lut = default_palette_lut();
+        for color in DEFAULT_PALETTE.chunks_exact(3) {
+            let index = lut.lookup(color[0], color[1], color[2]) as usize;
+            // Duplicate palette entries resolve to the first occurrence.
+            assert_eq!(&DEFAULT_PALETTE[index * 3..index * 3 + 3], color);
+        }
+    }
+
+    #[test]
+    fn lut_is_addressed_by_rgb() {
+        let lut = default_palette_lut();
+        assert_eq!(lut.lookup(255, 0, 0), 9);
+        assert_eq!(lut.lookup(0, 255, 0), 10);
+        assert_eq!(lut.lookup(0, 0, 255), 12);
+        assert_eq!(lut.as_bytes()[0xFF], 9);
+        assert_eq!(lut.as_bytes()[0xFF << 16], 12);
+    }
+
+    #[test]
+    fn off_palette_colors_are_stable() {
+        let lut = default_palette_lut();
+        let colors = [
+            (1, 2, 3),
+            (100, 149, 237),
+            (250, 128, 114),
+            (12, 200, 40),
+            (77, 77, 80),
+            (255, 250, 240),
+        ];
+        let indices: Vec<u8> = colors
+            .iter()
+            .map(|&(r, g, b)| lut.lookup(r, g, b))
+            .collect();
+        assert_eq!(indices, vec![0, 68, 210, 34, 239, 15]);
+    }
+}
@@ -0,0 +1,67 @@
+mod compositor;
+mod diff;
+mod dither;
+mod lut;
+mod ordered;
+mod quantizer;
+mod regions;
+
+use std::sync::Arc;
+
+use crate::{
+    error::Result,
+    frame::{DiffRect, FrameBuffer, FrameSize},
+};
+
+use super::{Backend, DiffThreshold, Dither};
+
+pub(crate) use self::compositor::FrameCompositor;
+pub(crate) use self::lut::{find_closest_color, lab_to_i32, rgb2lab};
+#[cfg(feature = "d3d")]
+pub(crate) use self::ordered::{ordered_dither_spread, ThresholdMap};
+pub(crate) use self::quantizer::ColorQuantizer;
+#[cfg(feature = "d3d")]
+pub(crate) use self::regions::{DirtyTiles, EMPTY_TILE};
+pub use self::{diff::FrameDiffer, lut::PaletteIndexLUT};
+
+pub struct CpuBackend {
+    compositor: FrameCompositor,
+    differ: FrameDiffer,
+    quantizer: ColorQuantizer,
+}
+
+impl CpuBackend {
+    pub fn new(
+        palette: &[u8],
+        frame_size: FrameSize,
+        diff_threshold: DiffThreshold,
+        dither: Dither,
+    ) -> Self {
+        // The differ needs the LUT to compare the quantized colors
+        let lut = Arc::new(PaletteIndexLUT::new(palette));
+        Self {
+            compositor: FrameCompositor::new(frame_size),
+            differ: FrameDiffer::with_threshold(frame_size, diff_threshold, palette, lut.clone()),
+            quantizer: ColorQuantizer::new(lut, palette, dither),
+        }
+    }
+}
+
+impl Backend for CpuBackend {
+    fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()> {
+        self.compositor.process_frame(frame);
+        Ok(())
+    }
+
+    fn loaded_frame(&mut self, _rect: &DiffRect) -> Result<&FrameBuffer> {
+        Ok(self.compositor.output())
+    }
+
+    fn diff(&mut self) -> Result<Vec<DiffRect>> {
+        Ok(self.differ.process_frame_regions(self.compositor.output()))
+    }
+
+    fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>> {
+        Ok(self.quantizer.quantize(self.compositor.output(), rect))
+    }
+}
@@ -0,0 +1,373 @@
+use std::sync::OnceLock;
+
+use crate::{
+    backend::Dither,
+    frame::{DiffRect, FrameBuffer, BYTES_PER_PIXEL},
+};
+
+use super::lut::PaletteIndexLUT;
+
+// Large enough that the blue noise doesn't visibly repeat.
+const BLUE_NOISE_SIZE: usize = 64;
+// How far the blue noise generator looks for neighbors.
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+/// A square matrix of thresholds between 0 and 1 that is tiled over the
+/// frame. LUTLookup_PS.hlsl uses the same matrices.
+#[derive(Clone)]
+pub(crate) struct ThresholdMap {
+    size: usize,
+    thresholds: Vec<f32>,
+}
+
+impl ThresholdMap {
+    /// Returns the threshold map for ordered dithering modes, or `None` for
+    /// the others.
+    pub fn for_dither(dither: Dither) -> Option<Self> {
+        match dither {
+            Dither::Bayer2 => Some(Self::bayer(2)),
+            Dither::Bayer4 => Some(Self::bayer(4)),
+            Dither::Bayer8 => Some(Self::bayer(8)),
+            Dither::BlueNoise => {
+                static BLUE_NOISE: OnceLock<ThresholdMap> = OnceLock::new();
+                Some(BLUE_NOISE.get_or_init(Self::blue_noise).clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// A single threshold that doesn't move colors at all.
+    #[cfg(feature = "d3d")]
+    pub fn none() -> Self {
+        Self::from_ranks(1, &[0])
+    }
+
+    /// Builds a Bayer matrix by recursively splitting the previous one.
+    fn bayer(size: usize) -> Self {
+        let mut matrix = vec![0usize];
+        let mut current = 1;
+        while current < size {
+            let next = current * 2;
+            let mut next_matrix = vec![0; next * next];
+            for y in 0..current {
+                for x in 0..current {
+                    let value = matrix[y * current + x] * 4;
+                    next_matrix[y * next + x] = value;
+                    next_matrix[y * next + x + current] = value + 2;
+                    next_matrix[(y + current) * next + x] = value + 3;
+                    next_matrix[(y + current) * next + x + current] = value + 1;
+                }
+            }
+            matrix = next_matrix;
+            current = next;
+        }
+        Self::from_ranks(size, &matrix)
+    }
+
+    /// Generates a blue noise mask with the void-and-cluster method, which
+    /// ranks every pixel by how far it is from the pixels ranked before it.
+    fn blue_noise() -> Self {
+        let size = BLUE_NOISE_SIZE;
+        let count = size * size;
+        let mut pattern = VoidAndCluster::new(size);
+
+        // Start from a sparse pseudo random pattern
+        let initial_count = count / 10;
+        let mut seed = 0x2545_f491u32;
+        while pattern.ones < initial_count {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let position = (seed >> 8) as usize % count;
+            if !pattern.pattern[position] {
+                pattern.toggle(position);
+            }
+        }
+
+        // Move the pixels out of clusters until they're evenly spread out
+        loop {
+            let cluster = pattern.tightest_cluster();
+            pattern.toggle(cluster);
+            let void = pattern.largest_void();
+            pattern.toggle(void);
+            if void == cluster {
+                break;
+            }
+        }
+
+        // Rank the initial pixels by removing them from the tightest
+        // clusters first, then fill the largest voids with the rest.
+        let mut ranks = vec![0; count];
+        let mut removed = pattern.clone();
+        while removed.ones > 0 {
+            let cluster = removed.tightest_cluster();
+            removed.toggle(cluster);
+            ranks[cluster] = removed.ones;
+        }
+        while pattern.ones < count {
+            let void = pattern.largest_void();
+            ranks[void] = pattern.ones;
+            pattern.toggle(void);
+        }
+        Self::from_ranks(size, &ranks)
+    }
+
+    fn from_ranks(size: usize, ranks: &[usize]) -> Self {
+        let count = (size * size) as f32;
+        Self {
+            size,
+            thresholds: ranks
+                .iter()
+                .map(|rank| (*rank as f32 + 0.5) / count)
+                .collect(),
+        }
+    }
+
+    #[cfg(any(feature = "d3d", test))]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The thresholds in row order.
+    #[cfg(any(feature = "d3d", test))]
+    pub fn thresholds(&self) -> &[f32] {
+        &self.thresholds
+    }
+
+    fn threshold(&self, x: u32, y: u32) -> f32 {
+        let size = self.size as u32;
+        self.thresholds[((y % size) * size + (x % size)) as usize]
+    }
+}
+
+/// How far ordered dithering moves colors around, which is the mean distance
+/// from each palette color to the closest other one, in the channel where they
+/// differ the most. Moving further than that adds noise without making the
+/// mix of colors any more accurate.
+pub(crate) fn ordered_dither_spread(palette: &[u8]) -> f32 {
+    let colors: Vec<&[u8]> = palette.chunks_exact(3).collect();
+    if colors.len() < 2 {
+        return 0.0;
+    }
+    let total: u32 = colors
+        .iter()
+        .enumerate()
+        .map(|(i, color)| {
+            colors
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| i != *j)
+                .map(|(_, other)| {
+                    color
+                        .iter()
+                        .zip(other.iter())
+                        .map(|(a, b)| a.abs_diff(*b) as u32)
+                        .max()
+                        .unwrap()
+                })
+                .min()
+                .unwrap()
+        })
+        .sum();
+    total as f32 / colors.len() as f32
+}
+
+/// Quantizes the given area of the frame, offsetting every pixel by the
+/// threshold at its position in the frame.
+pub(crate) fn ordered_dither(
+    frame: &FrameBuffer,
+    rect: &DiffRect,
+    lut: &PaletteIndexLUT,
+    map: &ThresholdMap,
+    spread: f32,
+) -> Vec<u8> {
+    let mut indices = Vec::with_capacity(rect.width() as usize * rect.height() as usize);
+    let begin = rect.left as usize * BYTES_PER_PIXEL;
+    let end = rect.right as usize * BYTES_PER_PIXEL;
+    for y in rect.top..rect.bottom {
+        let row = &frame.row(y)[begin..end];
+        for (x, pixel) in (rect.left..).zip(row.chunks_exact(BYTES_PER_PIXEL)) {
+            let offset = (map.threshold(x, y) - 0.5) * spread;
+            let apply_offset =
+                |channel: u8| (channel as f32 + offset).round().clamp(0.0, 255.0) as u8;
+            // Pixels are BGRA
+            indices.push(lut.lookup(
+                apply_offset(pixel[2]),
+                apply_offset(pixel[1]),
+                apply_offset(pixel[0]),
+            ));
+        }
+    }
+    indices
+}
+
+/// A binary pattern along with how crowded the neighborhood of each pixel is.
+#[derive(Clone)]
+struct VoidAndCluster {
+    size: usize,
+    pattern: Vec<bool>,
+    ones: usize,
+    // The sum of the weights of the set pixels around each pixel
+    energy: Vec<f32>,
+    // The weight of a set pixel at each offset, wrapping around the edges
+    weights: Vec<f32>,
+}
+
+impl VoidAndCluster {
+    fn new(size: usize) -> Self {
+        let wrapped_distance = |offset: usize| offset.min(size - offset) as f32;
+        let weights = (0..size * size)
+            .map(|offset| {
+                let dx = wrapped_distance(offset % size);
+                let dy = wrapped_distance(offset / size);
+                (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp()
+            })
+            .collect();
+        Self {
+            size,
+            pattern: vec![false; size * size],
+            ones: 0,
+            energy: vec![0.0; size * size],
+            weights,
+        }
+    }
+
+    fn toggle(&mut self, position: usize) {
+        let set = !self.pattern[position];
+        self.pattern[position] = set;
+        let sign = if set {
+            self.ones += 1;
+            1.0
+        } else {
+            self.ones -= 1;
+            -1.0
+        };
+        let (px, py) = (position % self.size, position / self.size);
+        for (index, energy) in self.energy.iter_mut().enumerate() {
+            let dx = (index % self.size + self.size - px) % self.size;
+            let dy = (index / self.size + self.size - py) % self.size;
+            *energy += sign * self.weights[dy * self.size + dx];
+        }
+    }
+
+    /// The set pixel with the most set pixels around it.
+    fn tightest_cluster(&self) -> usize {
+        self.find_extreme(true, |energy, best| energy > best)
+    }
+
+    /// The unset pixel with the fewest set pixels around it.
+    fn largest_void(&self) -> usize {
+        self.find_extreme(false, |energy, best| energy < best)
+    }
+
+    fn find_extreme(&self, set: bool, is_better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best: Option<usize> = None;
+        for (position, energy) in self.energy.iter().enumerate() {
+            if self.pattern[position] != set {
+                continue;
+            }
+            if best.is_none_or(|best| is_better(*energy, self.energy[best])) {
+                best = Some(position);
+            }
+        }
+        best.unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ordered_dither, ordered_dither_spread, ThresholdMap};
+    use crate::{
+        backend::{cpu::PaletteIndexLUT, Dither},
+        frame::{DiffRect, FrameBuffer, FrameSize},
+    };
+
+    fn assert_is_permutation(map: &ThresholdMap) {
+        let count = map.size() * map.size();
+        let mut ranks: Vec<usize> = map
+            .thresholds()
+            .iter()
+            .map(|threshold| (threshold * count as f32) as usize)
+            .collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bayer_matrices_match_the_reference() {
+        let map = ThresholdMap::for_dither(Dither::Bayer2).unwrap();
+        assert_eq!(map.thresholds(), &[0.125, 0.625, 0.875, 0.375]);
+
+        let map = ThresholdMap::for_dither(Dither::Bayer4).unwrap();
+        let ranks: Vec<u32> = map
+            .thresholds()
+            .iter()
+            .map(|threshold| (threshold * 16.0) as u32)
+            .collect();
+        assert_eq!(
+            ranks,
+            [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5]
+        );
+        assert_is_permutation(&ThresholdMap::for_dither(Dither::Bayer8).unwrap());
+    }
+
+    #[test]
+    fn blue_noise_is_evenly_spread() {
+        let map = ThresholdMap::for_dither(Dither::BlueNoise).unwrap();
+        assert_is_permutation(&map);
+
+        // Every 8x8 block of the darkest 1/8 of the pixels should have some
+        // of them, which white noise wouldn't manage.
+        let size = map.size() as u32;
+        for block_y in (0..size).step_by(8) {
+            for block_x in (0..size).step_by(8) {
+                let dark = (0..64)
+                    .filter(|i| map.threshold(block_x + i % 8, block_y + i / 8) < 0.125)
+                    .count();
+                assert!((4..=12).contains(&dark), "{} dark pixels", dark);
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_areas_are_dithered_the_same_way() {
+        // A gray gradient, which black and white can only show by dithering
+        let mut frame = FrameBuffer::new(FrameSize::new(16, 16));
+        for y in 0..16 {
+            for (x, pixel) in frame.row_mut(y).chunks_exact_mut(4).enumerate() {
+                let value = (x * 16 + y as usize) as u8;
+                pixel.copy_from_slice(&[value, value, value, 255]);
+            }
+        }
+        let palette = [0, 0, 0, 255, 255, 255];
+        let lut = PaletteIndexLUT::new(&palette);
+        let map = ThresholdMap::for_dither(Dither::Bayer4).unwrap();
+        let spread = ordered_dither_spread(&palette);
+
+        let first = DiffRect {
+            left: 0,
+            top: 0,
+            right: 12,
+            bottom: 12,
+        };
+        let second = DiffRect {
+            left: 5,
+            top: 3,
+            right: 16,
+            bottom: 16,
+        };
+        let first_indices = ordered_dither(&frame, &first, &lut, &map, spread);
+        let second_indices = ordered_dither(&frame, &second, &lut, &map, spread);
+        let index = |indices: &[u8], rect: &DiffRect, x: u32, y: u32| {
+            indices[((y - rect.top) * rect.width() + x - rect.left) as usize]
+        };
+
+        let mut shared = Vec::new();
+        for y in 3..12 {
+            for x in 5..12 {
+                let expected = index(&first_indices, &first, x, y);
+                assert_eq!(index(&second_indices, &second, x, y), expected);
+                shared.push(expected);
+            }
+        }
+        assert!(shared.contains(&0) && shared.contains(&1));
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Arc;
+
+use crate::{
+    backend::Dither,
+    frame::{DiffRect, FrameBuffer, BYTES_PER_PIXEL},
+};
+
+use super::{
+    dither::{diffuse_errors, Kernel, ATKINSON, FLOYD_STEINBERG, SIERRA},
+    lut::PaletteIndexLUT,
+    ordered::{ordered_dither, ordered_dither_spread, ThresholdMap},
+};
+
+pub struct ColorQuantizer {
+    lut: Arc<PaletteIndexLUT>,
+    palette: Vec<u8>,
+    method: Method,
+}
+
+enum Method {
+    Nearest,
+    ErrorDiffusion(&'static Kernel),
+    Ordered { map: ThresholdMap, spread: f32 },
+}
+
+impl ColorQuantizer {
+    pub fn new(lut: Arc<PaletteIndexLUT>, palette: &[u8], dither: Dither) -> Self {
+        let method = match dither {
+            Dither::None => Method::Nearest,
+            Dither::FloydSteinberg => Method::ErrorDiffusion(&FLOYD_STEINBERG),
+            Dither::Atkinson => Method::ErrorDiffusion(&ATKINSON),
+            Dither::Sierra => Method::ErrorDiffusion(&SIERRA),
+            Dither::Bayer2 | Dither::Bayer4 | Dither::Bayer8 | Dither::BlueNoise => {
+                Method::Ordered {
+                    map: ThresholdMap::for_dither(dither).unwrap(),
+                    spread: ordered_dither_spread(palette),
+                }
+            }
+        };
+        Self {
+            lut,
+            palette: palette.to_vec(),
+            method,
+        }
+    }
+
+    pub fn quantize(&self, frame: &FrameBuffer, rect: &DiffRect) -> Vec<u8> {
+        match &self.method {
+            Method::Nearest => self.quantize_nearest(frame, rect),
+            Method::ErrorDiffusion(kernel) => {
+                diffuse_errors(frame, rect, &self.lut, &self.palette, kernel)
+            }
+            Method::Ordered { map, spread } => ordered_dither(frame, rect, &self.lut, map, *spread),
+        }
+    }
+
+    fn quantize_nearest(&self, frame: &FrameBuffer, rect: &DiffRect) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((rect.width() * rect.height()) as usize);
+        let begin = rect.left as usize * BYTES_PER_PIXEL;
+        let end = rect.right as usize * BYTES_PER_PIXEL;
+        for y in rect.top..rect.bottom {
+            let row = &frame.row(y)[begin..end];
+            bytes.extend(
+                row.chunks_exact(BYTES_PER_PIXEL)
+                    .map(|pixel| self.lut.lookup(pixel[2], pixel[1], pixel[0])),
+            );
+        }
+        bytes
+    }
+}
@@ -0,0 +1,198 @@
+use crate::frame::{DiffRect, FrameSize};
+
+/// The width and height of the tiles frames are diffed in. TextureDiff.hlsl
+/// uses the same size.
+pub(crate) const TILE_SIZE: u32 = 32;
+
+// Every region becomes its own gif sub-image, which has a header and restarts
+// the LZW compression. We count that as encoding this many extra pixels.
+const REGION_OVERHEAD: u64 = 2 * TILE_SIZE as u64 * TILE_SIZE as u64;
+
+// Frames with changes all over the place aren't worth splitting up further.
+const MAX_REGIONS: usize = 16;
+
+/// The rect of a tile without changes, which is what TextureDiff.hlsl expects
+/// every tile to start out as.
+pub(crate) const EMPTY_TILE: DiffRect = DiffRect {
+    left: u32::MAX,
+    top: u32::MAX,
+    right: 0,
+    bottom: 0,
+};
+
+/// Tracks the area that changed within each tile of a frame. Tiles without
+/// changes have an invalid rect, the same way TextureDiff.hlsl leaves them.
+pub(crate) struct DirtyTiles {
+    columns: u32,
+    rows: u32,
+    tiles: Vec<DiffRect>,
+}
+
+impl DirtyTiles {
+    pub fn new(frame_size: FrameSize) -> Self {
+        let columns = frame_size.width.div_ceil(TILE_SIZE);
+        let rows = frame_size.height.div_ceil(TILE_SIZE);
+        Self {
+            columns,
+            rows,
+            tiles: vec![EMPTY_TILE; (columns * rows) as usize],
+        }
+    }
+
+    /// Uses the per-tile bounds produced by TextureDiff.hlsl, in row order.
+    #[cfg(feature = "d3d")]
+    pub fn from_tiles(frame_size: FrameSize, tiles: Vec<DiffRect>) -> Self {
+        let mut dirty_tiles = Self::new(frame_size);
+        assert_eq!(tiles.len(), dirty_tiles.tiles.len());
+        dirty_tiles.tiles = tiles;
+        dirty_tiles
+    }
+
+    /// The number of tiles in a frame, which TextureDiff.hlsl needs a rect
+    /// for each of.
+    #[cfg(feature = "d3d")]
+    pub fn tile_count(frame_size: FrameSize) -> usize {
+        (frame_size.width.div_ceil(TILE_SIZE) * frame_size.height.div_ceil(TILE_SIZE)) as usize
+    }
+
+    /// Records that the pixels from `left` to `right` changed in row `y`.
+    pub fn add_changes(&mut self, y: u32, left: u32, right: u32) {
+        let row = y / TILE_SIZE;
+        for column in left / TILE_SIZE..=right / TILE_SIZE {
+            let tile = &mut self.tiles[(row * self.columns + column) as usize];
+            tile.left = tile.left.min(left.max(column * TILE_SIZE));
+            tile.right = tile.right.max(right.min((column + 1) * TILE_SIZE - 1));
+            tile.top = tile.top.min(y);
+            tile.bottom = tile.bottom.max(y);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tiles.iter_mut().for_each(|tile| *tile = EMPTY_TILE);
+    }
+
+    /// Groups the changes into disjoint regions. Like the rects produced by
+    /// the differs, right and bottom are the coordinates of the last changed
+    /// pixel in each region.
+    pub fn regions(&self) -> Vec<DiffRect> {
+        // Find the runs of dirty tiles in each row, extending the run above
+        // when it covers the same columns.
+        let mut regions: Vec<DiffRect> = Vec::new();
+        let mut open: Vec<usize> = Vec::new();
+        for row in 0..self.rows {
+            let mut still_open = Vec::new();
+            let mut column = 0;
+            while column < self.columns {
+                if !self.is_dirty(column, row) {
+                    column += 1;
+                    continue;
+                }
+                let first = column;
+                while column < self.columns && self.is_dirty(column, row) {
+                    column += 1;
+                }
+                let last = column - 1;
+
+                let above = open.iter().copied().find(|index| {
+                    let region = &regions[*index];
+                    region.left == first && region.right == last
+                });
+                if let Some(index) = above {
+                    regions[index].bottom = row;
+                    still_open.push(index);
+                } else {
+                    still_open.push(regions.len());
+                    regions.push(DiffRect {
+                        left: first,
+                        top: row,
+                        right: last,
+                        bottom: row,
+                    });
+                }
+            }
+            open = still_open;
+        }
+
+        // Convert from tiles to the pixels that changed within them
+        let mut regions: Vec<DiffRect> = regions
+            .iter()
+            .map(|region| {
+                let mut bounds = EMPTY_TILE;
+                for row in region.top..=region.bottom {
+                    for column in region.left..=region.right {
+                        bounds = union(&bounds, self.tile(column, row));
+                    }
+                }
+                bounds
+            })
+            .collect();
+        merge_regions(&mut regions, MAX_REGIONS);
+        regions
+    }
+
+    fn tile(&self, column: u32, row: u32) -> &DiffRect {
+        &self.tiles[(row * self.columns + column) as usize]
+    }
+
+    fn is_dirty(&self, column: u32, row: u32) -> bool {
+        self.tile(column, row).is_valid()
+    }
+}
+
+/// Merges disjoint regions for as long as encoding them together is cheaper
+/// than encoding them separately, and until there are at most `max_regions`.
+pub(crate) fn merge_regions(regions: &mut Vec<DiffRect>, max_regions: usize) {
+    while regions.len() > 1 {
+        let mut cheapest = (0, 0, i64::MAX);
+        for i in 0..regions.len() {
+            for j in i + 1..regions.len() {
+                let cost = merge_cost(&regions[i], &regions[j]);
+                if cost < cheapest.2 {
+                    cheapest = (i, j, cost);
+                }
+            }
+        }
+        let (i, j, cost) = cheapest;
+        if cost > 0 && regions.len() <= max_regions {
+            break;
+        }
+
+        // Removing the later region first keeps the index of the other intact
+        let second = regions.swap_remove(j);
+        let first = regions.swap_remove(i);
+        let mut merged = union(&first, &second);
+
+        // The merged region can overlap others, which it then absorbs
+        while let Some(index) = regions.iter().position(|region| overlaps(region, &merged)) {
+            merged = union(&merged, &regions.swap_remove(index));
+        }
+        regions.push(merged);
+    }
+}
+
+/// How many more pixels we'd encode by merging the regions, minus the
+/// overhead of the region we'd save.
+fn merge_cost(first: &DiffRect, second: &DiffRect) -> i64 {
+    let merged = union(first, second);
+    area(&merged) as i64 - area(first) as i64 - area(second) as i64 - REGION_OVERHEAD as i64
+}
+
+fn area(rect: &DiffRect) -> u64 {
+    (rect.right - rect.left + 1) as u64 * (rect.bottom - rect.top + 1) as u64
+}
+
+fn union(first: &DiffRect, second: &DiffRect) -> DiffRect {
+    DiffRect {
+        left: first.left.min(second.left),
+        top: first.top.min(second.top),
+        right: first.right.max(second.right),
+        bottom: first.bottom.max(second.bottom),
+    }
+}
+
+fn overlaps(first: &DiffRect, second: &DiffRect) -> bool {
+    first.left <= second.right
+        && second.left <= first.right
+        && first.top <= second.bottom
+        && second.top <= first.bottom
+}
@@ -0,0 +1,366 @@
+use windows::{
+    core::{Interface, Result},
+    Graphics::SizeInt32,
+    Win32::Graphics::{
+        Direct3D11::{
+            ID3D11Buffer, ID3D11Device, ID3D11DeviceContext, ID3D11ShaderResourceView,
+            ID3D11Texture1D, ID3D11Texture2D, D3D11_BIND_CONSTANT_BUFFER,
+            D3D11_BIND_SHADER_RESOURCE, D3D11_BIND_UNORDERED_ACCESS, D3D11_BUFFER_DESC,
+            D3D11_BUFFER_UAV, D3D11_CPU_ACCESS_READ, D3D11_RESOURCE_MISC_BUFFER_STRUCTURED,
+            D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC, D3D11_UAV_DIMENSION_BUFFER,
+            D3D11_UNORDERED_ACCESS_VIEW_DESC, D3D11_UNORDERED_ACCESS_VIEW_DESC_0,
+            D3D11_USAGE_DEFAULT, D3D11_USAGE_IMMUTABLE, D3D11_USAGE_STAGING,
+        },
+        Dxgi::Common::{DXGI_FORMAT_R32_UINT, DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC},
+    },
+};
+use zerocopy::AsBytes;
+
+use crate::{
+    backend::{
+        cpu::{DirtyTiles, EMPTY_TILE},
+        DiffThreshold,
+    },
+    frame::{DiffRect, FrameSize},
+    util::d3d::{read_from_buffer, read_slice_from_buffer, Direct3D11MultiThread},
+};
+
+pub struct TextureDiffer {
+    d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    multithread: Direct3D11MultiThread,
+    diff_buffer: ID3D11Buffer,
+    diff_default_buffer: ID3D11Buffer,
+    diff_staging_buffer: ID3D11Buffer,
+    tile_buffer: ID3D11Buffer,
+    tile_default_buffer: ID3D11Buffer,
+    tile_staging_buffer: ID3D11Buffer,
+    tile_count: usize,
+    lut_srv: ID3D11ShaderResourceView,
+    palette_srv: ID3D11ShaderResourceView,
+    params_buffer: ID3D11Buffer,
+    first_frame_params_buffer: ID3D11Buffer,
+    first_frame: bool,
+    texture_size: SizeInt32,
+}
+
+// Must match the DiffParams cbuffer in TextureDiff.hlsl
+#[repr(C)]
+#[derive(Clone, Copy, AsBytes)]
+struct DiffParams {
+    threshold_mode: u32,
+    channel_threshold: u32,
+    delta_e_threshold: f32,
+    copy_all: u32,
+}
+
+unsafe impl Send for TextureDiffer {}
+impl TextureDiffer {
+    /// CIELAB thresholds compare the colors pixels are quantized to, which
+    /// is why the differ needs the LUT and the palette.
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        d3d_context: &ID3D11DeviceContext,
+        texture_size: SizeInt32,
+        diff_threshold: DiffThreshold,
+        lut_srv: &ID3D11ShaderResourceView,
+        palette_texture: &ID3D11Texture1D,
+    ) -> Result<Self> {
+        // With a threshold, small changes aren't written to the reference
+        // texture, so they can't add up unnoticed. Typed UAV loads are only
+        // guaranteed for R32_UINT, so each pixel is packed into one.
+        let reference_texture = {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: texture_size.Width as u32,
+                Height: texture_size.Height as u32,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_R32_UINT,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    ..Default::default()
+                },
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_UNORDERED_ACCESS,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture2D(&desc, std::ptr::null())? }
+        };
+        let palette_srv =
+            unsafe { d3d_device.CreateShaderResourceView(palette_texture, std::ptr::null())? };
+
+        let params = match diff_threshold {
+            DiffThreshold::Exact => DiffParams {
+                threshold_mode: 0,
+                channel_threshold: 0,
+                delta_e_threshold: 0.0,
+                copy_all: 0,
+            },
+            DiffThreshold::Channel(threshold) => DiffParams {
+                threshold_mode: 1,
+                channel_threshold: threshold as u32,
+                delta_e_threshold: 0.0,
+                copy_all: 0,
+            },
+            DiffThreshold::DeltaE(threshold) => DiffParams {
+                threshold_mode: 2,
+                channel_threshold: 0,
+                delta_e_threshold: threshold,
+                copy_all: 0,
+            },
+        };
+        let params_buffer = create_params_buffer(d3d_device, params)?;
+        let first_frame_params_buffer = create_params_buffer(
+            d3d_device,
+            DiffParams {
+                copy_all: 1,
+                ..params
+            },
+        )?;
+        let diff_buffer_padded_size = (std::mem::size_of::<DiffRect>() + 3) & !0x03;
+        //println!(
+        //    "{} -> {}",
+        //    std::mem::size_of::<DiffRect>(),
+        //    diff_buffer_padded_size
+        //);
+        let diff_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: diff_buffer_padded_size as u32,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_UNORDERED_ACCESS.0,
+                MiscFlags: D3D11_RESOURCE_MISC_BUFFER_STRUCTURED.0,
+                StructureByteStride: diff_buffer_padded_size as u32,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, std::ptr::null())? }
+        };
+        let diff_default_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: diff_buffer_padded_size as u32,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE.0,
+                ..Default::default()
+            };
+            let init_rect = DiffRect {
+                left: texture_size.Width as u32,
+                top: texture_size.Height as u32,
+                right: 0,
+                bottom: 0,
+            };
+            let mut data: Vec<u8> = init_rect.as_bytes().iter().map(|x| *x).collect();
+            while data.len() < diff_buffer_padded_size {
+                data.push(0);
+            }
+            let init_data = D3D11_SUBRESOURCE_DATA {
+                pSysMem: data.as_mut_ptr() as *mut _ as *mut _,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, &init_data)? }
+        };
+        let diff_staging_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: diff_buffer_padded_size as u32,
+                Usage: D3D11_USAGE_STAGING,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ.0,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, std::ptr::null())? }
+        };
+
+        // Every tile gets its own rect, which together make up the regions
+        let frame_size = FrameSize::new(texture_size.Width as u32, texture_size.Height as u32);
+        let tile_count = DirtyTiles::tile_count(frame_size);
+        let tile_buffer_size = std::mem::size_of::<DiffRect>() * tile_count;
+        let tile_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: tile_buffer_size as u32,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_UNORDERED_ACCESS.0,
+                MiscFlags: D3D11_RESOURCE_MISC_BUFFER_STRUCTURED.0,
+                StructureByteStride: std::mem::size_of::<DiffRect>() as u32,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, std::ptr::null())? }
+        };
+        let tile_default_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: tile_buffer_size as u32,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE.0,
+                ..Default::default()
+            };
+            let mut data: Vec<u8> = Vec::with_capacity(tile_buffer_size);
+            for _ in 0..tile_count {
+                data.extend_from_slice(EMPTY_TILE.as_bytes());
+            }
+            let init_data = D3D11_SUBRESOURCE_DATA {
+                pSysMem: data.as_mut_ptr() as *mut _ as *mut _,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, &init_data)? }
+        };
+        let tile_staging_buffer = {
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: tile_buffer_size as u32,
+                Usage: D3D11_USAGE_STAGING,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ.0,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, std::ptr::null())? }
+        };
+        unsafe {
+            let diff_uav = {
+                let desc = D3D11_UNORDERED_ACCESS_VIEW_DESC {
+                    Format: DXGI_FORMAT_UNKNOWN,
+                    ViewDimension: D3D11_UAV_DIMENSION_BUFFER,
+                    Anonymous: D3D11_UNORDERED_ACCESS_VIEW_DESC_0 {
+                        Buffer: D3D11_BUFFER_UAV {
+                            FirstElement: 0,
+                            NumElements: 1,
+                            Flags: 0,
+                        },
+                    },
+                };
+                d3d_device.CreateUnorderedAccessView(&diff_buffer, &desc)?
+            };
+            let reference_uav =
+                d3d_device.CreateUnorderedAccessView(&reference_texture, std::ptr::null())?;
+            let tile_uav = {
+                let desc = D3D11_UNORDERED_ACCESS_VIEW_DESC {
+                    Format: DXGI_FORMAT_UNKNOWN,
+                    ViewDimension: D3D11_UAV_DIMENSION_BUFFER,
+                    Anonymous: D3D11_UNORDERED_ACCESS_VIEW_DESC_0 {
+                        Buffer: D3D11_BUFFER_UAV {
+                            FirstElement: 0,
+                            NumElements: tile_count as u32,
+                            Flags: 0,
+                        },
+                    },
+                };
+                d3d_device.CreateUnorderedAccessView(&tile_buffer, &desc)?
+            };
+
+            let diff_shader_bytes = gifshaders::texture_diff_shader();
+            let diff_shader = d3d_device.CreateComputeShader(diff_shader_bytes, None)?;
+
+            d3d_context.CSSetShader(diff_shader, &[]);
+            d3d_context.CSSetUnorderedAccessViews(
+                0,
+                3,
+                &[diff_uav, tile_uav, reference_uav] as *const _ as *const _,
+                std::ptr::null(),
+            );
+        }
+        Ok(Self {
+            d3d_device: d3d_device.clone(),
+            d3d_context: d3d_context.clone(),
+            multithread: Direct3D11MultiThread::new(d3d_device.cast()?),
+            diff_buffer,
+            diff_default_buffer,
+            diff_staging_buffer,
+            tile_buffer,
+            tile_default_buffer,
+            tile_staging_buffer,
+            tile_count,
+            lut_srv: lut_srv.clone(),
+            palette_srv,
+            params_buffer,
+            first_frame_params_buffer,
+            first_frame: true,
+            texture_size,
+        })
+    }
+
+    /// Returns the disjoint regions that changed since the previous frame.
+    pub fn process_frame(&mut self, frame_texture: &ID3D11Texture2D) -> Result<Vec<DiffRect>> {
+        let _lock = self.multithread.lock();
+        let current_texture_srv = unsafe {
+            self.d3d_device
+                .CreateShaderResourceView(frame_texture, std::ptr::null())?
+        };
+        unsafe {
+            self.d3d_context.CSSetShaderResources(
+                0,
+                &[
+                    Some(current_texture_srv),
+                    Some(self.lut_srv.clone()),
+                    Some(self.palette_srv.clone()),
+                ],
+            );
+        }
+        let regions = if self.first_frame {
+            self.first_frame = false;
+            // Only fill the reference texture
+            unsafe {
+                self.d3d_context
+                    .CSSetConstantBuffers(0, &[Some(self.first_frame_params_buffer.clone())]);
+                self.dispatch();
+            }
+            vec![DiffRect {
+                left: 0,
+                top: 0,
+                right: self.texture_size.Width as u32,
+                bottom: self.texture_size.Height as u32,
+            }]
+        } else {
+            let diff_rect = unsafe {
+                self.d3d_context
+                    .CopyResource(&self.diff_buffer, &self.diff_default_buffer);
+                self.d3d_context
+                    .CopyResource(&self.tile_buffer, &self.tile_default_buffer);
+                self.d3d_context
+                    .CSSetConstantBuffers(0, &[Some(self.params_buffer.clone())]);
+                self.dispatch();
+
+                self.d3d_context
+                    .CopyResource(&self.diff_staging_buffer, &self.diff_buffer);
+                self.d3d_context
+                    .CopyResource(&self.tile_staging_buffer, &self.tile_buffer);
+
+                let diff_rect: DiffRect =
+                    read_from_buffer(&self.d3d_context, &self.diff_staging_buffer)?;
+                diff_rect
+            };
+            if !diff_rect.is_valid() {
+                Vec::new()
+            } else {
+                let tiles = read_slice_from_buffer(
+                    &self.d3d_context,
+                    &self.tile_staging_buffer,
+                    self.tile_count,
+                )?;
+                let frame_size = FrameSize::new(
+                    self.texture_size.Width as u32,
+                    self.texture_size.Height as u32,
+                );
+                DirtyTiles::from_tiles(frame_size, tiles).regions()
+            }
+        };
+        Ok(regions)
+    }
+
+    fn dispatch(&self) {
+        // Each thread group covers 2x2 pixels, rounding up for odd sizes
+        unsafe {
+            self.d3d_context.Dispatch(
+                (self.texture_size.Width as u32).div_ceil(2),
+                (self.texture_size.Height as u32).div_ceil(2),
+                1,
+            );
+        }
+    }
+}
+
+fn create_params_buffer(d3d_device: &ID3D11Device, params: DiffParams) -> Result<ID3D11Buffer> {
+    let desc = D3D11_BUFFER_DESC {
+        ByteWidth: std::mem::size_of::<DiffParams>() as u32,
+        Usage: D3D11_USAGE_IMMUTABLE,
+        BindFlags: D3D11_BIND_CONSTANT_BUFFER.0,
+        ..Default::default()
+    };
+    let init_data = D3D11_SUBRESOURCE_DATA {
+        pSysMem: params.as_bytes().as_ptr() as *mut _,
+        ..Default::default()
+    };
+    unsafe { d3d_device.CreateBuffer(&desc, &init_data) }
+}
@@ -0,0 +1,288 @@
+mod diff;
+mod lut;
+mod quantizer;
+
+use std::sync::Arc;
+
+use windows::{
+    core::Interface,
+    Graphics::{RectInt32, SizeInt32},
+    Win32::Graphics::{
+        Direct3D11::{
+            ID3D11Device, ID3D11DeviceContext, ID3D11Texture1D, ID3D11Texture2D,
+            D3D11_BIND_SHADER_RESOURCE, D3D11_BOX, D3D11_CPU_ACCESS_READ, D3D11_SUBRESOURCE_DATA,
+            D3D11_TEXTURE1D_DESC, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
+        },
+        Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R8G8B8A8_UINT, DXGI_SAMPLE_DESC},
+    },
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::{DiffRect, FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+    util::d3d::{get_bytes_from_texture, Direct3D11MultiThread},
+};
+
+use self::{diff::TextureDiffer, lut::PaletteIndexLUT, quantizer::ColorQuantizer};
+
+use super::{cpu, cpu::FrameCompositor, Backend, DiffThreshold, Dither};
+
+pub struct D3DBackend {
+    _d3d_device: ID3D11Device,
+    d3d_context: ID3D11DeviceContext,
+    multithread: Direct3D11MultiThread,
+    _palette_texture: ID3D11Texture1D,
+    frame_texture: ID3D11Texture2D,
+    // Parts of textures loaded with `load_texture` are read back through here
+    staging_texture: ID3D11Texture2D,
+    compositor: FrameCompositor,
+    // Whether the compositor's output holds the loaded frame, or it was
+    // loaded as a texture and only `readback` has parts of it
+    frame_in_memory: bool,
+    readback: FrameBuffer,
+    differ: TextureDiffer,
+    quantizer: ColorQuantizer,
+    // Error diffusion runs on the CPU, since every pixel depends on the ones
+    // before it.
+    dither_quantizer: Option<cpu::ColorQuantizer>,
+}
+
+unsafe impl Send for D3DBackend {}
+impl D3DBackend {
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        palette: &[u8],
+        frame_size: FrameSize,
+        diff_threshold: DiffThreshold,
+        dither: Dither,
+    ) -> Result<Self> {
+        let d3d_context = unsafe {
+            let mut d3d_context = None;
+            d3d_device.GetImmediateContext(&mut d3d_context);
+            d3d_context
+                .ok_or_else(|| Error::Backend("The device has no immediate context".to_owned()))?
+        };
+        let texture_size = SizeInt32 {
+            Width: frame_size.width as i32,
+            Height: frame_size.height as i32,
+        };
+
+        // Create the palette buffer. LUT generation uses the width of the
+        // texture as the number of colors in the palette.
+        let palette_colors = palette.len() / 3;
+        let palette_texture = {
+            let desc = D3D11_TEXTURE1D_DESC {
+                Width: palette_colors as u32,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_R8G8B8A8_UINT,
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE.0,
+                ..Default::default()
+            };
+            // TODO: pSysMem shouldn't be *mut _
+            let mut palette_with_alpha = {
+                let mut palette_with_alpha: Vec<u8> = Vec::with_capacity(palette_colors * 4);
+                for chunk in palette.chunks_exact(3) {
+                    palette_with_alpha.push(chunk[0]);
+                    palette_with_alpha.push(chunk[1]);
+                    palette_with_alpha.push(chunk[2]);
+                    palette_with_alpha.push(255);
+                }
+                palette_with_alpha
+            };
+            let subresource_data = D3D11_SUBRESOURCE_DATA {
+                pSysMem: palette_with_alpha.as_mut_ptr() as *mut _,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture1D(&desc, &subresource_data)? }
+        };
+
+        // Create the texture our frames are uploaded to
+        let frame_texture = {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: frame_size.width,
+                Height: frame_size.height,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    ..Default::default()
+                },
+                Usage: D3D11_USAGE_DEFAULT,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture2D(&desc, std::ptr::null())? }
+        };
+        let staging_texture = {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: frame_size.width,
+                Height: frame_size.height,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    ..Default::default()
+                },
+                Usage: D3D11_USAGE_STAGING,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture2D(&desc, std::ptr::null())? }
+        };
+
+        // Create a 3d texture for our LUT
+        let lut = PaletteIndexLUT::new(d3d_device, &d3d_context, &palette_texture)?;
+        let lut_srv = lut.shader_resource_view();
+
+        // Create our color quantizer
+        let quantizer =
+            ColorQuantizer::new(d3d_device, &d3d_context, lut, texture_size, dither, palette)?;
+
+        let error_diffusion = matches!(
+            dither,
+            Dither::FloydSteinberg | Dither::Atkinson | Dither::Sierra
+        );
+        let dither_quantizer = if error_diffusion {
+            let lut = Arc::new(cpu::PaletteIndexLUT::new(palette));
+            Some(cpu::ColorQuantizer::new(lut, palette, dither))
+        } else {
+            None
+        };
+
+        // Create our differ
+        let differ = TextureDiffer::new(
+            d3d_device,
+            &d3d_context,
+            texture_size,
+            diff_threshold,
+            &lut_srv,
+            &palette_texture,
+        )?;
+
+        Ok(Self {
+            _d3d_device: d3d_device.clone(),
+            d3d_context,
+            multithread: Direct3D11MultiThread::new(d3d_device.cast()?),
+            _palette_texture: palette_texture,
+            frame_texture,
+            staging_texture,
+            compositor: FrameCompositor::new(frame_size),
+            frame_in_memory: true,
+            readback: FrameBuffer::new(frame_size),
+            differ,
+            quantizer,
+            dither_quantizer,
+        })
+    }
+
+    /// Makes sure the given area of the loaded frame is in memory, and
+    /// returns the frame it's in.
+    fn read_rect(&mut self, rect: &DiffRect) -> Result<&FrameBuffer> {
+        if self.frame_in_memory {
+            return Ok(self.compositor.output());
+        }
+        if rect.width() == 0 || rect.height() == 0 {
+            return Ok(&self.readback);
+        }
+        let bytes = {
+            let _lock = self.multithread.lock();
+            let region = D3D11_BOX {
+                left: rect.left,
+                top: rect.top,
+                right: rect.right,
+                bottom: rect.bottom,
+                front: 0,
+                back: 1,
+            };
+            unsafe {
+                self.d3d_context.CopySubresourceRegion(
+                    &self.staging_texture,
+                    0,
+                    rect.left,
+                    rect.top,
+                    0,
+                    &self.frame_texture,
+                    0,
+                    &region,
+                );
+            }
+            get_bytes_from_texture(
+                &self.d3d_context,
+                &self.staging_texture,
+                BYTES_PER_PIXEL as u32,
+                RectInt32 {
+                    X: rect.left as i32,
+                    Y: rect.top as i32,
+                    Width: rect.width() as i32,
+                    Height: rect.height() as i32,
+                },
+            )?
+        };
+        let row_length = rect.width() as usize * BYTES_PER_PIXEL;
+        let begin = rect.left as usize * BYTES_PER_PIXEL;
+        for (y, row) in (rect.top..rect.bottom).zip(bytes.chunks_exact(row_length)) {
+            self.readback.row_mut(y)[begin..begin + row_length].copy_from_slice(row);
+        }
+        Ok(&self.readback)
+    }
+}
+
+impl Backend for D3DBackend {
+    fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()> {
+        // Composing on the CPU lets us upload the whole frame in one go.
+        let composed = self.compositor.process_frame(frame);
+        let _lock = self.multithread.lock();
+        unsafe {
+            self.d3d_context.UpdateSubresource(
+                &self.frame_texture,
+                0,
+                std::ptr::null(),
+                composed.bytes.as_ptr() as *const _,
+                composed.stride as u32,
+                0,
+            );
+        }
+        self.frame_in_memory = true;
+        Ok(())
+    }
+
+    fn accepts_textures(&self) -> bool {
+        true
+    }
+
+    fn load_texture(&mut self, texture: &ID3D11Texture2D) -> Result<()> {
+        let _lock = self.multithread.lock();
+        unsafe {
+            self.d3d_context.CopyResource(&self.frame_texture, texture);
+        }
+        self.frame_in_memory = false;
+        Ok(())
+    }
+
+    fn loaded_frame(&mut self, rect: &DiffRect) -> Result<&FrameBuffer> {
+        self.read_rect(rect)
+    }
+
+    fn diff(&mut self) -> Result<Vec<DiffRect>> {
+        Ok(self.differ.process_frame(&self.frame_texture)?)
+    }
+
+    fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>> {
+        if self.dither_quantizer.is_some() {
+            self.read_rect(rect)?;
+        }
+        if let Some(dither_quantizer) = &self.dither_quantizer {
+            let frame = if self.frame_in_memory {
+                self.compositor.output()
+            } else {
+                &self.readback
+            };
+            return Ok(dither_quantizer.quantize(frame, rect));
+        }
+        Ok(self.quantizer.quantize(&self.frame_texture, rect)?)
+    }
+}
@@ -6,23 +6,32 @@ use windows::{
         Direct3D::D3D_PRIMITIVE_TOPOLOGY_TRIANGLELIST,
         Direct3D11::{
             ID3D11Buffer, ID3D11Device, ID3D11DeviceContext, ID3D11RenderTargetView,
-            ID3D11SamplerState, ID3D11ShaderResourceView, ID3D11Texture2D, D3D11_BIND_INDEX_BUFFER,
-            D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BIND_VERTEX_BUFFER,
-            D3D11_BOX, D3D11_BUFFER_DESC, D3D11_COMPARISON_NEVER, D3D11_CPU_ACCESS_READ,
-            D3D11_FILTER_MIN_MAG_MIP_POINT, D3D11_INPUT_ELEMENT_DESC, D3D11_INPUT_PER_VERTEX_DATA,
-            D3D11_SAMPLER_DESC, D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC,
-            D3D11_TEXTURE_ADDRESS_WRAP, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING, D3D11_VIEWPORT,
+            ID3D11SamplerState, ID3D11ShaderResourceView, ID3D11Texture2D,
+            D3D11_BIND_CONSTANT_BUFFER, D3D11_BIND_INDEX_BUFFER, D3D11_BIND_RENDER_TARGET,
+            D3D11_BIND_SHADER_RESOURCE, D3D11_BIND_VERTEX_BUFFER, D3D11_BOX, D3D11_BUFFER_DESC,
+            D3D11_COMPARISON_NEVER, D3D11_CPU_ACCESS_READ, D3D11_FILTER_MIN_MAG_MIP_POINT,
+            D3D11_INPUT_ELEMENT_DESC, D3D11_INPUT_PER_VERTEX_DATA, D3D11_SAMPLER_DESC,
+            D3D11_SUBRESOURCE_DATA, D3D11_TEXTURE2D_DESC, D3D11_TEXTURE_ADDRESS_WRAP,
+            D3D11_USAGE_DEFAULT, D3D11_USAGE_IMMUTABLE, D3D11_USAGE_STAGING, D3D11_VIEWPORT,
         },
         Dxgi::Common::{
             DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_FORMAT_R16_UINT, DXGI_FORMAT_R32G32B32_FLOAT,
-            DXGI_FORMAT_R32G32_FLOAT, DXGI_FORMAT_R8_UINT, DXGI_SAMPLE_DESC,
+            DXGI_FORMAT_R32G32_FLOAT, DXGI_FORMAT_R32_FLOAT, DXGI_FORMAT_R8_UINT, DXGI_SAMPLE_DESC,
         },
     },
 };
+use zerocopy::AsBytes;
 
-use crate::util::d3d::{get_bytes_from_texture, Direct3D11MultiThread};
+use crate::{
+    backend::{
+        cpu::{ordered_dither_spread, ThresholdMap},
+        Dither,
+    },
+    frame::DiffRect,
+    util::d3d::{get_bytes_from_texture, Direct3D11MultiThread},
+};
 
-use super::{diff::DiffRect, lut::PaletteIndexLUT};
+use super::lut::PaletteIndexLUT;
 
 pub struct ColorQuantizer {
     input_texture: ID3D11Texture2D,
@@ -32,19 +41,33 @@ pub struct ColorQuantizer {
     _output_texture_render_target_view: ID3D11RenderTargetView,
     _vertex_buffer: ID3D11Buffer,
     _index_buffer: ID3D11Buffer,
+    _dither_shader_resource_view: ID3D11ShaderResourceView,
+    _dither_params_buffer: ID3D11Buffer,
     staging_texture: ID3D11Texture2D,
     d3d_context: ID3D11DeviceContext,
     multithread: Direct3D11MultiThread,
     _lut: PaletteIndexLUT,
 }
 
+// Must match the DitherParams cbuffer in LUTLookup_PS.hlsl
+#[repr(C)]
+#[derive(AsBytes)]
+struct DitherParams {
+    spread: f32,
+    _padding: [f32; 3],
+}
+
 unsafe impl Send for ColorQuantizer {}
 impl ColorQuantizer {
+    /// Ordered dithering is done by the lookup shader, other kinds of
+    /// dithering aren't supported.
     pub fn new(
         d3d_device: &ID3D11Device,
         d3d_context: &ID3D11DeviceContext,
         lut: PaletteIndexLUT,
         capture_size: SizeInt32,
+        dither: Dither,
+        palette: &[u8],
     ) -> Result<Self> {
         // Create a texture as the input to the lookup shader
         let input_texture = {
@@ -139,6 +162,53 @@ impl ColorQuantizer {
 
             (vertex_buffer, index_buffer)
         };
+        // Without ordered dithering, a single threshold that's never applied
+        let (dither_map, dither_spread) = match ThresholdMap::for_dither(dither) {
+            Some(map) => (map, ordered_dither_spread(palette)),
+            None => (ThresholdMap::none(), 0.0),
+        };
+        let dither_texture = {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: dither_map.size() as u32,
+                Height: dither_map.size() as u32,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_R32_FLOAT,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    ..Default::default()
+                },
+                Usage: D3D11_USAGE_IMMUTABLE,
+                BindFlags: D3D11_BIND_SHADER_RESOURCE,
+                ..Default::default()
+            };
+            let subresource_data = D3D11_SUBRESOURCE_DATA {
+                pSysMem: dither_map.thresholds().as_ptr() as *mut _,
+                SysMemPitch: (dither_map.size() * std::mem::size_of::<f32>()) as u32,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture2D(&desc, &subresource_data)? }
+        };
+        let dither_shader_resource_view =
+            unsafe { d3d_device.CreateShaderResourceView(&dither_texture, std::ptr::null())? };
+        let dither_params_buffer = {
+            let params = DitherParams {
+                spread: dither_spread,
+                _padding: [0.0; 3],
+            };
+            let desc = D3D11_BUFFER_DESC {
+                ByteWidth: std::mem::size_of::<DitherParams>() as u32,
+                Usage: D3D11_USAGE_IMMUTABLE,
+                BindFlags: D3D11_BIND_CONSTANT_BUFFER.0,
+                ..Default::default()
+            };
+            let subresource_data = D3D11_SUBRESOURCE_DATA {
+                pSysMem: params.as_bytes().as_ptr() as *mut _,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateBuffer(&desc, &subresource_data)? }
+        };
+
         unsafe {
             // Load LUT lookup shaders
             let lut_lookup_pixel_shader_bytes = gifshaders::lut_lookup_pixel_shader();
@@ -200,8 +270,10 @@ impl ColorQuantizer {
                 &[
                     Some(input_shader_resource_view.clone()),
                     Some(lut.shader_resource_view()),
+                    Some(dither_shader_resource_view.clone()),
                 ],
             );
+            d3d_context.PSSetConstantBuffers(0, &[Some(dither_params_buffer.clone())]);
             d3d_context.OMSetRenderTargets(&[Some(output_texture_render_target_view.clone())], None)
         }
 
@@ -232,6 +304,8 @@ impl ColorQuantizer {
             _output_texture_render_target_view: output_texture_render_target_view,
             _vertex_buffer: vertex_buffer,
             _index_buffer: index_buffer,
+            _dither_shader_resource_view: dither_shader_resource_view,
+            _dither_params_buffer: dither_params_buffer,
             staging_texture,
             d3d_context: d3d_context.clone(),
             multithread: Direct3D11MultiThread::new(d3d_device.cast()?),
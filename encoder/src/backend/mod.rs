@@ -0,0 +1,241 @@
+pub mod cpu;
+#[cfg(feature = "d3d")]
+pub mod d3d;
+
+#[cfg(feature = "d3d")]
+use robmikh_common::universal::d3d::create_d3d_device;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "d3d")]
+use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D};
+
+#[cfg(feature = "d3d")]
+use crate::error::Error;
+use crate::{
+    error::Result,
+    frame::{DiffRect, FrameBuffer, FrameSize},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum BackendType {
+    Cpu,
+    #[cfg(feature = "d3d")]
+    #[cfg_attr(feature = "serde", serde(rename = "d3d"))]
+    Direct3D,
+}
+
+/// Direct3D when it's available.
+impl Default for BackendType {
+    #[cfg(feature = "d3d")]
+    fn default() -> Self {
+        BackendType::Direct3D
+    }
+
+    #[cfg(not(feature = "d3d"))]
+    fn default() -> Self {
+        BackendType::Cpu
+    }
+}
+
+/// How the backend diffs and quantizes frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct BackendOptions {
+    pub backend_type: BackendType,
+    pub diff_threshold: DiffThreshold,
+    pub dither: Dither,
+    /// Reports the whole frame as changed every time. Only meant for
+    /// debugging the diffing.
+    pub disable_frame_diff: bool,
+}
+
+/// How different a pixel needs to be from the previous frame to count as
+/// changed. Pixels below the threshold keep their old color, so noise and
+/// subtle gradients don't have to be encoded every frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum DiffThreshold {
+    /// Any change counts.
+    #[default]
+    Exact,
+    /// Changes larger than this in any channel count.
+    Channel(u8),
+    /// Changes count when the palette colors the pixels are quantized to are
+    /// further apart than this CIELAB distance.
+    DeltaE(f32),
+}
+
+/// How colors that aren't in the palette are approximated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Dither {
+    /// Every pixel gets the closest palette color.
+    #[default]
+    None,
+    /// Error diffusion, which spreads what's lost by quantizing each pixel to
+    /// its neighbors. Done on the CPU, even with the Direct3D backend.
+    FloydSteinberg,
+    /// Error diffusion that only spreads 3/4 of the error, which keeps more
+    /// contrast at the cost of detail in highlights and shadows.
+    Atkinson,
+    /// Error diffusion over a wider area than Floyd–Steinberg, which gives
+    /// smoother results.
+    Sierra,
+    /// Ordered dithering with a 2x2 Bayer matrix. Unlike error diffusion, the
+    /// pattern only depends on the position of a pixel, so areas that didn't
+    /// change are dithered the same way in every frame.
+    Bayer2,
+    /// Ordered dithering with a 4x4 Bayer matrix.
+    Bayer4,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    Bayer8,
+    /// Ordered dithering with a tileable blue noise mask, which avoids the
+    /// cross-hatched look of Bayer matrices.
+    BlueNoise,
+}
+
+/// Composes, diffs and quantizes frames for the encoder.
+///
+/// Frames are first loaded into the backend, after which the backend can
+/// report what changed since the last diffed frame and produce palette indices
+/// for any part of the loaded frame.
+pub trait Backend: Send {
+    /// Composes the given frame onto the backend's canvas. Frames that are
+    /// smaller than the canvas are padded with black, larger frames are clamped.
+    fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()>;
+
+    /// Whether `load_texture` can be used instead of `load_frame`.
+    #[cfg(feature = "d3d")]
+    fn accepts_textures(&self) -> bool {
+        false
+    }
+
+    /// Loads a BGRA8 texture the size of the canvas, which has to be on the
+    /// backend's device. The frame stays on the GPU, only the areas asked for
+    /// are read back.
+    #[cfg(feature = "d3d")]
+    fn load_texture(&mut self, _texture: &ID3D11Texture2D) -> Result<()> {
+        Err(Error::Backend(
+            "This backend can only load frames from memory".to_owned(),
+        ))
+    }
+
+    /// The loaded frame, after it was composed onto the canvas. Only the
+    /// pixels within `rect` are guaranteed to be up to date.
+    fn loaded_frame(&mut self, rect: &DiffRect) -> Result<&FrameBuffer>;
+
+    /// Returns the disjoint areas of the loaded frame that changed since the
+    /// last time this was called, which is empty if nothing changed.
+    fn diff(&mut self) -> Result<Vec<DiffRect>>;
+
+    /// Returns the palette indices for the given area of the loaded frame.
+    fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>>;
+}
+
+/// Creates a backend of the given type for frames of the given size.
+pub fn create_backend(
+    options: &BackendOptions,
+    palette: &[u8],
+    frame_size: FrameSize,
+) -> Result<Box<dyn Backend>> {
+    match options.backend_type {
+        BackendType::Cpu => Ok(wrap_backend(
+            options,
+            Box::new(cpu::CpuBackend::new(
+                palette,
+                frame_size,
+                options.diff_threshold,
+                options.dither,
+            )),
+            frame_size,
+        )),
+        #[cfg(feature = "d3d")]
+        BackendType::Direct3D => {
+            let d3d_device = create_d3d_device()?;
+            create_backend_on_device(options, palette, frame_size, &d3d_device)
+        }
+    }
+}
+
+/// Like `create_backend`, but a Direct3D backend uses the given device, so
+/// that it can load textures from a source on the same device.
+#[cfg(feature = "d3d")]
+pub fn create_backend_on_device(
+    options: &BackendOptions,
+    palette: &[u8],
+    frame_size: FrameSize,
+    d3d_device: &ID3D11Device,
+) -> Result<Box<dyn Backend>> {
+    let backend: Box<dyn Backend> = match options.backend_type {
+        BackendType::Cpu => return create_backend(options, palette, frame_size),
+        BackendType::Direct3D => Box::new(d3d::D3DBackend::new(
+            d3d_device,
+            palette,
+            frame_size,
+            options.diff_threshold,
+            options.dither,
+        )?),
+    };
+    Ok(wrap_backend(options, backend, frame_size))
+}
+
+fn wrap_backend(
+    options: &BackendOptions,
+    backend: Box<dyn Backend>,
+    frame_size: FrameSize,
+) -> Box<dyn Backend> {
+    if options.disable_frame_diff {
+        return Box::new(FullFrameBackend {
+            backend,
+            frame_size,
+        });
+    }
+    backend
+}
+
+/// Wraps a backend so that every frame is diffed as a whole.
+struct FullFrameBackend {
+    backend: Box<dyn Backend>,
+    frame_size: FrameSize,
+}
+
+impl Backend for FullFrameBackend {
+    fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()> {
+        self.backend.load_frame(frame)
+    }
+
+    #[cfg(feature = "d3d")]
+    fn accepts_textures(&self) -> bool {
+        self.backend.accepts_textures()
+    }
+
+    #[cfg(feature = "d3d")]
+    fn load_texture(&mut self, texture: &ID3D11Texture2D) -> Result<()> {
+        self.backend.load_texture(texture)
+    }
+
+    fn loaded_frame(&mut self, rect: &DiffRect) -> Result<&FrameBuffer> {
+        self.backend.loaded_frame(rect)
+    }
+
+    fn diff(&mut self) -> Result<Vec<DiffRect>> {
+        Ok(vec![self.frame_size.full_rect()])
+    }
+
+    fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>> {
+        self.backend.quantize(rect)
+    }
+}
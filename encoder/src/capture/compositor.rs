@@ -2,24 +2,30 @@ use robmikh_common::universal::d3d::get_d3d_interface_from_object;
 use windows::{
     core::{Interface, Result},
     Foundation::TimeSpan,
-    Graphics::{Capture::Direct3D11CaptureFrame, SizeInt32},
+    Graphics::{Capture::Direct3D11CaptureFrame, RectInt32, SizeInt32},
     Win32::Graphics::{
         Direct3D11::{
             ID3D11Device, ID3D11DeviceContext, ID3D11Multithread, ID3D11RenderTargetView,
             ID3D11Texture2D, D3D11_BIND_RENDER_TARGET, D3D11_BIND_SHADER_RESOURCE, D3D11_BOX,
-            D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT,
+            D3D11_CPU_ACCESS_READ, D3D11_TEXTURE2D_DESC, D3D11_USAGE_DEFAULT, D3D11_USAGE_STAGING,
         },
         Dxgi::Common::{DXGI_FORMAT_B8G8R8A8_UNORM, DXGI_SAMPLE_DESC},
     },
 };
 
-use crate::util::d3d::Direct3D11MultiThread;
+use crate::{
+    error,
+    frame::{FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+    util::d3d::{get_bytes_from_texture, Direct3D11MultiThread},
+};
 
 pub struct FrameCompositor {
     multithread: Direct3D11MultiThread,
     d3d_context: ID3D11DeviceContext,
     output_texture: ID3D11Texture2D,
     output_rtv: ID3D11RenderTargetView,
+    staging_texture: ID3D11Texture2D,
+    size: SizeInt32,
 }
 
 pub struct ComposedFrame<'a> {
@@ -58,12 +64,31 @@ impl FrameCompositor {
         };
         let output_rtv =
             unsafe { d3d_device.CreateRenderTargetView(&output_texture, std::ptr::null())? };
+        let staging_texture = {
+            let desc = D3D11_TEXTURE2D_DESC {
+                Width: size.Width as u32,
+                Height: size.Height as u32,
+                MipLevels: 1,
+                ArraySize: 1,
+                Format: DXGI_FORMAT_B8G8R8A8_UNORM,
+                SampleDesc: DXGI_SAMPLE_DESC {
+                    Count: 1,
+                    ..Default::default()
+                },
+                Usage: D3D11_USAGE_STAGING,
+                CPUAccessFlags: D3D11_CPU_ACCESS_READ,
+                ..Default::default()
+            };
+            unsafe { d3d_device.CreateTexture2D(&desc, std::ptr::null())? }
+        };
 
         Ok(Self {
             multithread,
             d3d_context: d3d_context.clone(),
             output_texture,
             output_rtv,
+            staging_texture,
+            size,
         })
     }
 
@@ -119,10 +144,24 @@ impl FrameCompositor {
         })
     }
 
-    pub fn repeat_frame<'a>(&'a self, system_relative_time: TimeSpan) -> ComposedFrame<'a> {
-        ComposedFrame {
-            texture: &self.output_texture,
-            system_relative_time,
+    pub fn read_frame(&self, frame: &ComposedFrame) -> error::Result<FrameBuffer> {
+        let _lock = self.multithread.lock();
+        unsafe {
+            self.d3d_context
+                .CopyResource(&self.staging_texture, frame.texture);
         }
+        let bytes = get_bytes_from_texture(
+            &self.d3d_context,
+            &self.staging_texture,
+            BYTES_PER_PIXEL as u32,
+            RectInt32 {
+                X: 0,
+                Y: 0,
+                Width: self.size.Width,
+                Height: self.size.Height,
+            },
+        )?;
+        let size = FrameSize::new(self.size.Width as u32, self.size.Height as u32);
+        FrameBuffer::from_bytes(size, size.width as usize * BYTES_PER_PIXEL, bytes)
     }
 }
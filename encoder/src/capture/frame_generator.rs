@@ -1,11 +1,14 @@
 use std::{
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
     time::Duration,
 };
 
 use windows::{
     core::{IInspectable, Result},
-    Foundation::{TimeSpan, TypedEventHandler},
+    Foundation::TypedEventHandler,
     Graphics::{
         Capture::{
             Direct3D11CaptureFrame, Direct3D11CaptureFramePool, GraphicsCaptureItem,
@@ -16,11 +19,14 @@ use windows::{
     },
 };
 
+use crate::{error, source::pacing::FramePacer};
+
 pub struct CaptureFrameGenerator {
     _d3d_device: IDirect3DDevice,
     _item: GraphicsCaptureItem,
     frame_pool: Direct3D11CaptureFramePool,
     session: GraphicsCaptureSession,
+    pacer: Arc<Mutex<FramePacer<Direct3D11CaptureFrame>>>,
     sender: Sender<Option<Direct3D11CaptureFrame>>,
     receiver: Receiver<Option<Direct3D11CaptureFrame>>,
 }
@@ -32,6 +38,7 @@ impl CaptureFrameGenerator {
         item: GraphicsCaptureItem,
         size: SizeInt32,
         number_of_buffers: u32,
+        pacer: FramePacer<Direct3D11CaptureFrame>,
     ) -> Result<Self> {
         let frame_pool = Direct3D11CaptureFramePool::CreateFreeThreaded(
             &d3d_device,
@@ -41,32 +48,24 @@ impl CaptureFrameGenerator {
         )?;
         let session = frame_pool.CreateCaptureSession(&item)?;
 
+        let pacer = Arc::new(Mutex::new(pacer));
         let (sender, receiver) = channel();
         frame_pool.FrameArrived(
             TypedEventHandler::<Direct3D11CaptureFramePool, IInspectable>::new({
                 let session = session.clone();
+                let pacer = pacer.clone();
                 let sender = sender.clone();
-                let mut last_timestamp: Option<TimeSpan> = None;
                 move |frame_pool, _| {
                     let frame_pool = frame_pool.as_ref().unwrap();
                     let frame = frame_pool.TryGetNextFrame()?;
-                    let timestamp = frame.SystemRelativeTime()?;
-                    let mut update = true;
-                    if let Some(last_timestamp) = &last_timestamp {
-                        let time_since_last_update = TimeSpan {
-                            Duration: timestamp.Duration - last_timestamp.Duration,
-                        };
-                        let duration: Duration = time_since_last_update.into();
-                        if duration.as_millis() < 33 {
-                            update = false;
-                        }
-                    }
+                    let timestamp: Duration = frame.SystemRelativeTime()?.into();
 
-                    if update {
-                        last_timestamp = Some(timestamp);
+                    let mut pacer = pacer.lock().unwrap();
+                    for frame in pacer.push(timestamp, frame) {
                         if sender.send(Some(frame)).is_err() {
                             frame_pool.Close()?;
                             session.Close()?;
+                            break;
                         }
                     }
                     Ok(())
@@ -79,6 +78,7 @@ impl CaptureFrameGenerator {
             _item: item,
             frame_pool,
             session,
+            pacer,
             sender,
             receiver,
         })
@@ -87,52 +87,44 @@ impl CaptureFrameGenerator {
     pub fn session(&self) -> CaptureFrameGeneratorSession {
         CaptureFrameGeneratorSession {
             session: self.session.clone(),
+            pacer: self.pacer.clone(),
             sender: self.sender.clone(),
         }
     }
 
     pub fn wait_for_next_frame(&mut self) -> Result<Option<Direct3D11CaptureFrame>> {
-        if let Some(frame) = self.receiver.recv().unwrap() {
-            Ok(Some(frame))
-        } else {
-            Ok(None)
-        }
-    }
-
-    pub fn try_get_next_frame(&mut self) -> Result<Option<Direct3D11CaptureFrame>> {
-        let result = self.receiver.try_recv();
-        if let Ok(result) = result {
-            if let Some(frame) = result {
-                Ok(Some(frame))
-            } else {
-                Ok(None)
-            }
-        } else {
-            Ok(None)
-        }
+        // We hold on to a sender, so the channel is never disconnected
+        Ok(self.receiver.recv().unwrap_or(None))
     }
 }
 
 impl Drop for CaptureFrameGenerator {
     fn drop(&mut self) {
         let _ = self.sender.send(None);
-        self.session.Close().unwrap();
-        self.frame_pool.Close().unwrap();
+        // There's no one left to report a failure to
+        let _ = self.session.Close();
+        let _ = self.frame_pool.Close();
     }
 }
 
 pub struct CaptureFrameGeneratorSession {
     session: GraphicsCaptureSession,
+    pacer: Arc<Mutex<FramePacer<Direct3D11CaptureFrame>>>,
     sender: Sender<Option<Direct3D11CaptureFrame>>,
 }
 
 impl CaptureFrameGeneratorSession {
-    pub fn start(&self) -> Result<()> {
-        self.session.StartCapture()
+    pub fn start(&self) -> error::Result<()> {
+        self.session.StartCapture()?;
+        Ok(())
     }
 
-    pub fn stop(self) -> Result<()> {
+    pub fn stop(self) -> error::Result<()> {
         self.session.Close()?;
+        // The last frame may still be waiting to see if a closer one arrives
+        if let Some(frame) = self.pacer.lock().unwrap().flush() {
+            let _ = self.sender.send(Some(frame));
+        }
         let _ = self.sender.send(None);
         Ok(())
     }
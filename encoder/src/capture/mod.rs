@@ -1 +1,3 @@
+pub mod compositor;
 pub mod frame_generator;
+pub mod source;
@@ -0,0 +1,119 @@
+use robmikh_common::universal::d3d::create_direct3d_device;
+use windows::{
+    Graphics::{Capture::GraphicsCaptureItem, SizeInt32},
+    Win32::Graphics::Direct3D11::ID3D11Device,
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::FrameSize,
+    source::{
+        pacing::{FramePacer, FrameRate},
+        FrameSource, SourceFrame, SourceTexture,
+    },
+};
+
+use super::{
+    compositor::FrameCompositor,
+    frame_generator::{CaptureFrameGenerator, CaptureFrameGeneratorSession},
+};
+
+/// A frame source backed by Windows.Graphics.Capture.
+pub struct CaptureFrameSource {
+    d3d_device: ID3D11Device,
+    frame_generator: CaptureFrameGenerator,
+    frame_compositor: FrameCompositor,
+    frame_size: FrameSize,
+}
+
+unsafe impl Send for CaptureFrameSource {}
+impl CaptureFrameSource {
+    /// Frames are taken at up to the given frame rate, picking the one
+    /// closest to each tick when the screen changes more often than that.
+    pub fn new(
+        d3d_device: &ID3D11Device,
+        capture_item: GraphicsCaptureItem,
+        capture_size: SizeInt32,
+        frame_rate: FrameRate,
+    ) -> Result<Self> {
+        let pacer = FramePacer::new(frame_rate)?;
+        let capture_size = ensure_even_size(capture_size);
+
+        let d3d_context = unsafe {
+            let mut d3d_context = None;
+            d3d_device.GetImmediateContext(&mut d3d_context);
+            d3d_context
+                .ok_or_else(|| Error::Source("The device has no immediate context".to_owned()))?
+        };
+        let device = create_direct3d_device(d3d_device)?;
+
+        // Create our compositor
+        let frame_compositor = FrameCompositor::new(d3d_device, &d3d_context, capture_size)?;
+
+        // Setup capture. The pacer can hold on to a frame while waiting for
+        // its tick, which takes up one of the buffers.
+        let frame_generator =
+            CaptureFrameGenerator::new(device, capture_item, capture_size, 3, pacer)?;
+
+        Ok(Self {
+            d3d_device: d3d_device.clone(),
+            frame_generator,
+            frame_compositor,
+            frame_size: FrameSize::new(capture_size.Width as u32, capture_size.Height as u32),
+        })
+    }
+
+    /// Returns the session used to start and stop the capture. Stopping the
+    /// session ends the source once the frames captured so far are consumed.
+    pub fn session(&self) -> CaptureFrameGeneratorSession {
+        self.frame_generator.session()
+    }
+}
+
+impl FrameSource for CaptureFrameSource {
+    fn frame_size(&self) -> FrameSize {
+        self.frame_size
+    }
+
+    fn next_frame(&mut self) -> Result<Option<SourceFrame>> {
+        if let Some(frame) = self.frame_generator.wait_for_next_frame()? {
+            let composed_frame = self.frame_compositor.process_frame(&frame)?;
+            let timestamp = composed_frame.system_relative_time.into();
+            let buffer = self.frame_compositor.read_frame(&composed_frame)?;
+            Ok(Some(SourceFrame { buffer, timestamp }))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn d3d_device(&self) -> Option<ID3D11Device> {
+        Some(self.d3d_device.clone())
+    }
+
+    fn next_texture(&mut self) -> Result<Option<SourceTexture>> {
+        if let Some(frame) = self.frame_generator.wait_for_next_frame()? {
+            let composed_frame = self.frame_compositor.process_frame(&frame)?;
+            Ok(Some(SourceTexture {
+                texture: composed_frame.texture.clone(),
+                timestamp: composed_frame.system_relative_time.into(),
+            }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn ensure_even(value: i32) -> i32 {
+    if value % 2 == 0 {
+        value
+    } else {
+        value + 1
+    }
+}
+
+fn ensure_even_size(size: SizeInt32) -> SizeInt32 {
+    SizeInt32 {
+        Width: ensure_even(size.Width),
+        Height: ensure_even(size.Height),
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::Write;
+
+use png::{chunk, BitDepth, BlendOp, ColorType, DisposeOp};
+
+use crate::{
+    error::Result,
+    frame::{DiffRect, FrameSize},
+};
+
+use super::frame_encoder::LoopCount;
+
+const PNG_SIGNATURE_LENGTH: usize = 8;
+
+/// How the pixels of an APNG are stored.
+pub(crate) enum ApngColors {
+    /// Four bytes per pixel, where pixels with no alpha keep the color shown
+    /// by the previous frame.
+    Rgba,
+    /// One palette index per pixel. Pixels with the transparent index keep
+    /// the color shown by the previous frame.
+    Indexed {
+        palette: Vec<u8>,
+        transparent_index: Option<u8>,
+    },
+}
+
+impl ApngColors {
+    fn is_transparent(&self) -> bool {
+        match self {
+            ApngColors::Rgba => true,
+            ApngColors::Indexed {
+                transparent_index, ..
+            } => transparent_index.is_some(),
+        }
+    }
+
+    pub fn configure<W: Write>(&self, encoder: &mut png::Encoder<W>) {
+        encoder.set_depth(BitDepth::Eight);
+        match self {
+            ApngColors::Rgba => encoder.set_color(ColorType::Rgba),
+            ApngColors::Indexed {
+                palette,
+                transparent_index,
+            } => {
+                encoder.set_color(ColorType::Indexed);
+                encoder.set_palette(palette.clone());
+                if let Some(transparent_index) = transparent_index {
+                    let mut alphas = vec![255; *transparent_index as usize + 1];
+                    alphas[*transparent_index as usize] = 0;
+                    encoder.set_trns(alphas);
+                }
+            }
+        }
+    }
+}
+
+struct ApngFrame {
+    rect: DiffRect,
+    delay: u16,
+    // The compressed image data, as it would appear in IDAT chunks
+    data: Vec<u8>,
+}
+
+/// Writes frames to an animated PNG. APNGs start with the number of frames,
+/// so frames are compressed as they come in and the file is only written
+/// once it's finished.
+pub(crate) struct ApngWriter<W: Write> {
+    output: W,
+    frame_size: FrameSize,
+    colors: ApngColors,
+    loop_count: LoopCount,
+    frames: Vec<ApngFrame>,
+}
+
+impl<W: Write> ApngWriter<W> {
+    pub fn new(
+        output: W,
+        frame_size: FrameSize,
+        colors: ApngColors,
+        loop_count: LoopCount,
+    ) -> Self {
+        Self {
+            output,
+            frame_size,
+            colors,
+            loop_count,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Adds a frame covering the given area, which is shown for `delay`
+    /// centiseconds. The first frame has to cover the whole canvas.
+    pub fn write_frame(&mut self, rect: &DiffRect, pixels: &[u8], delay: u16) -> Result<()> {
+        debug_assert!(
+            !self.frames.is_empty()
+                || (rect.width(), rect.height()) == (self.frame_size.width, self.frame_size.height),
+            "The first frame of an APNG has to cover the whole canvas"
+        );
+        let data = self.compress(rect, pixels)?;
+        self.frames.push(ApngFrame {
+            rect: *rect,
+            delay,
+            data,
+        });
+        Ok(())
+    }
+
+    /// Writes the whole APNG to the output.
+    pub fn finish(self) -> Result<()> {
+        let mut encoder =
+            png::Encoder::new(self.output, self.frame_size.width, self.frame_size.height);
+        self.colors.configure(&mut encoder);
+        let num_plays = match self.loop_count {
+            LoopCount::Infinite => 0,
+            LoopCount::Finite(count) => count as u32 + 1,
+        };
+        encoder.set_animated(self.frames.len() as u32, num_plays)?;
+        let mut writer = encoder.write_header()?;
+
+        // Transparent pixels only work if frames are blended over the
+        // previous ones
+        let blend_op = if self.colors.is_transparent() {
+            BlendOp::Over
+        } else {
+            BlendOp::Source
+        };
+
+        // Frame control and frame data chunks share the sequence numbers
+        let mut sequence_number = 0;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let frame_control = frame_control(sequence_number, frame, blend_op);
+            writer.write_chunk(chunk::fcTL, &frame_control)?;
+            sequence_number += 1;
+
+            // The first frame doubles as the image shown by plain PNG decoders
+            if i == 0 {
+                writer.write_chunk(chunk::IDAT, &frame.data)?;
+            } else {
+                let mut frame_data = Vec::with_capacity(frame.data.len() + 4);
+                frame_data.extend_from_slice(&sequence_number.to_be_bytes());
+                frame_data.extend_from_slice(&frame.data);
+                writer.write_chunk(chunk::fdAT, &frame_data)?;
+                sequence_number += 1;
+            }
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Compresses the pixels by encoding them as a standalone PNG and taking
+    /// its image data.
+    fn compress(&self, rect: &DiffRect, pixels: &[u8]) -> Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        let mut encoder = png::Encoder::new(&mut encoded, rect.width(), rect.height());
+        self.colors.configure(&mut encoder);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(pixels)?;
+        writer.finish()?;
+
+        let mut data = Vec::new();
+        let mut chunks = &encoded[PNG_SIGNATURE_LENGTH..];
+        while chunks.len() >= 12 {
+            let length = u32::from_be_bytes([chunks[0], chunks[1], chunks[2], chunks[3]]) as usize;
+            let chunk_type = &chunks[4..8];
+            if chunk_type == chunk::IDAT.0 {
+                data.extend_from_slice(&chunks[8..8 + length]);
+            }
+            // Skip the length, type, data and CRC
+            chunks = &chunks[12 + length..];
+        }
+        Ok(data)
+    }
+}
+
+fn frame_control(sequence_number: u32, frame: &ApngFrame, blend_op: BlendOp) -> Vec<u8> {
+    let mut data = Vec::with_capacity(26);
+    data.extend_from_slice(&sequence_number.to_be_bytes());
+    data.extend_from_slice(&frame.rect.width().to_be_bytes());
+    data.extend_from_slice(&frame.rect.height().to_be_bytes());
+    data.extend_from_slice(&frame.rect.left.to_be_bytes());
+    data.extend_from_slice(&frame.rect.top.to_be_bytes());
+    // Delays are in centiseconds, like gifs
+    data.extend_from_slice(&frame.delay.to_be_bytes());
+    data.extend_from_slice(&100u16.to_be_bytes());
+    data.push(DisposeOp::None as u8);
+    data.push(blend_op as u8);
+    data
+}
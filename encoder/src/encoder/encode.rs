@@ -0,0 +1,334 @@
+use std::io::Write;
+
+#[cfg(feature = "d3d")]
+use crate::backend::create_backend_on_device;
+use crate::{
+    backend::create_backend,
+    error::{Error, Result},
+    palette::{
+        create_palette_builder, mean_quantization_error, validate_color_count, ColorHistogram,
+        Palette, PaletteAlgorithm, PaletteSampling,
+    },
+    source::{FrameSource, SourceFrame},
+};
+
+use super::{
+    frame_encoder::{FrameEncoder, MAX_OPAQUE_COLORS},
+    options::EncoderOptions,
+    palette::DEFAULT_PALETTE,
+};
+
+/// Details about a finished gif.
+#[derive(Clone, Debug)]
+pub struct EncodeSummary {
+    /// The palette the gif was encoded with, as a list of RGB colors. This
+    /// doesn't include the index reserved for transparency.
+    pub palette: Vec<u8>,
+    /// For generated palettes, the mean CIELAB distance between the sampled
+    /// colors and the palette entries they were quantized to.
+    pub palette_error: Option<f64>,
+    /// The last color of a fixed palette with 256 colors, which makes room
+    /// for the transparent index. Not set for `DEFAULT_PALETTE`, whose last
+    /// color is one of its grays.
+    pub dropped_color: Option<[u8; 3]>,
+}
+
+/// Encodes every frame of the source to a gif, an APNG or a WebP on the
+/// calling thread, and writes it to the output. Unlike `CaptureGifEncoder`,
+/// this is meant for sources that end on their own, like an image sequence.
+pub fn encode_gif<S: FrameSource, W: Write>(
+    mut source: S,
+    output: W,
+    options: &EncoderOptions,
+) -> Result<EncodeSummary> {
+    encode_frames(&mut source, output, options)
+}
+
+/// Like `encode_gif`, but returns the encoded file instead of writing it
+/// anywhere.
+pub fn encode_gif_to_vec<S: FrameSource>(
+    source: S,
+    options: &EncoderOptions,
+) -> Result<(Vec<u8>, EncodeSummary)> {
+    let mut bytes = Vec::new();
+    let summary = encode_gif(source, &mut bytes, options)?;
+    Ok((bytes, summary))
+}
+
+/// Encodes every frame of the source, generating the palette from the
+/// source first if needed.
+pub(crate) fn encode_frames<S: FrameSource + ?Sized, W: Write>(
+    source: &mut S,
+    output: W,
+    options: &EncoderOptions,
+) -> Result<EncodeSummary> {
+    let frame_size = source.frame_size();
+
+    // Sampling may hold on to frames that still need to be encoded
+    let mut dropped_color = None;
+    let (colors, palette_error, sampled_frames) = match &options.palette {
+        Palette::Fixed(colors) => {
+            validate_palette(colors)?;
+            // Full palettes give up their last color for transparency
+            let (opaque, dropped) = colors.split_at(colors.len().min(MAX_OPAQUE_COLORS * 3));
+            if !dropped.is_empty() && colors[..] != DEFAULT_PALETTE[..] {
+                dropped_color = Some([dropped[0], dropped[1], dropped[2]]);
+            }
+            (opaque.to_vec(), None, Vec::new())
+        }
+        Palette::Adaptive {
+            algorithm,
+            sampling,
+            max_colors,
+        } => {
+            validate_color_count(*max_colors)?;
+            let (histogram, sampled_frames) = sample_colors(source, *sampling)?;
+            // Leave room for the transparent index within the requested size
+            let opaque_colors = (*max_colors - 1).max(1);
+            let colors = create_palette_builder(*algorithm, opaque_colors)?.build(&histogram);
+            let palette_error = mean_quantization_error(&histogram, &colors);
+            (colors, Some(palette_error), sampled_frames)
+        }
+    };
+
+    // Create the backend that diffs and quantizes our frames. Sources on the
+    // GPU share their device with it, so that frames can stay there.
+    #[cfg(feature = "d3d")]
+    let backend = match source.d3d_device() {
+        Some(d3d_device) => {
+            create_backend_on_device(&options.backend, &colors, frame_size, &d3d_device)?
+        }
+        None => create_backend(&options.backend, &colors, frame_size)?,
+    };
+    #[cfg(not(feature = "d3d"))]
+    let backend = create_backend(&options.backend, &colors, frame_size)?;
+
+    // Setup the encoder
+    let mut encoder = FrameEncoder::new(
+        backend,
+        &colors,
+        frame_size,
+        output,
+        options.local_palette_threshold,
+        options.playback,
+        options.format.clone(),
+    )?;
+    for frame in sampled_frames {
+        encoder.encode_frame(&frame.buffer, frame.timestamp)?;
+    }
+    encoder.encode_source(source)?;
+
+    Ok(EncodeSummary {
+        palette: colors,
+        palette_error,
+        dropped_color,
+    })
+}
+
+/// Generates a palette with `max_colors` entries from the frames of the
+/// source without encoding them. Returns the palette as a list of RGB colors.
+pub fn generate_palette<S: FrameSource + ?Sized>(
+    source: &mut S,
+    algorithm: PaletteAlgorithm,
+    sampling: PaletteSampling,
+    max_colors: usize,
+) -> Result<Vec<u8>> {
+    validate_color_count(max_colors)?;
+    let (histogram, _) = sample_colors(source, sampling)?;
+    Ok(create_palette_builder(algorithm, max_colors)?.build(&histogram))
+}
+
+fn validate_palette(colors: &[u8]) -> Result<()> {
+    if colors.len() % 3 != 0 {
+        return Err(Error::Palette(format!(
+            "A palette must be a list of RGB colors, found {} bytes",
+            colors.len()
+        )));
+    }
+    let color_count = colors.len() / 3;
+    if !(1..=256).contains(&color_count) {
+        return Err(Error::Palette(format!(
+            "A palette must have between 1 and 256 colors, found {}",
+            color_count
+        )));
+    }
+    Ok(())
+}
+
+fn sample_colors<S: FrameSource + ?Sized>(
+    source: &mut S,
+    sampling: PaletteSampling,
+) -> Result<(ColorHistogram, Vec<SourceFrame>)> {
+    let mut histogram = ColorHistogram::new();
+    let mut frames = Vec::new();
+    match sampling {
+        PaletteSampling::FirstFrames(count) => {
+            while frames.len() < count.max(1) {
+                match source.next_frame()? {
+                    Some(frame) => {
+                        histogram.add_frame(&frame.buffer);
+                        frames.push(frame);
+                    }
+                    None => break,
+                }
+            }
+        }
+        PaletteSampling::AllFrames => {
+            // Restarting before reading anything tells us whether the source
+            // can be read twice, otherwise we need to keep every frame.
+            let can_restart = source.restart()?;
+            while let Some(frame) = source.next_frame()? {
+                histogram.add_frame(&frame.buffer);
+                if !can_restart {
+                    frames.push(frame);
+                }
+            }
+            if can_restart {
+                source.restart()?;
+            }
+        }
+    }
+    Ok((histogram, frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::encode_gif_to_vec;
+    use crate::{
+        backend::BackendType,
+        encoder::{frame_encoder::OutputFormat, options::EncoderOptions, palette::DEFAULT_PALETTE},
+        error::Error,
+        frame::FrameSize,
+        palette::{Palette, PaletteAlgorithm, PaletteSampling},
+        source::raw::{PixelFormat, RawVideoSource},
+    };
+
+    /// Three frames that get brighter, as tightly packed BGRA pixels.
+    fn brightening_video(frame_size: FrameSize) -> RawVideoSource<Cursor<Vec<u8>>> {
+        let mut video = Vec::new();
+        for value in [0u8, 120, 240] {
+            for _ in 0..frame_size.width * frame_size.height {
+                video.extend_from_slice(&[value, value, value, 255]);
+            }
+        }
+        RawVideoSource::new(Cursor::new(video), frame_size, PixelFormat::Bgra8, 10.0).unwrap()
+    }
+
+    /// Returns the number of entries in the global color table, along with
+    /// the LZW minimum code size of every image.
+    fn read_code_sizes(gif: &[u8]) -> (usize, Vec<u8>) {
+        // The header and logical screen descriptor
+        let packed = gif[10];
+        assert!(packed & 0x80 != 0, "Missing the global color table");
+        let table_size = 2 << (packed & 7);
+        let mut position = 13 + table_size * 3;
+
+        let skip_sub_blocks = |mut position: usize| {
+            while gif[position] != 0 {
+                position += gif[position] as usize + 1;
+            }
+            position + 1
+        };
+        let mut code_sizes = Vec::new();
+        loop {
+            match gif[position] {
+                0x21 => position = skip_sub_blocks(position + 2),
+                0x2C => {
+                    let packed = gif[position + 9];
+                    position += 10;
+                    if packed & 0x80 != 0 {
+                        position += (2 << (packed & 7)) * 3;
+                    }
+                    code_sizes.push(gif[position]);
+                    position = skip_sub_blocks(position + 1);
+                }
+                0x3B => break,
+                block => panic!("Unexpected block {:#x}", block),
+            }
+        }
+        (table_size, code_sizes)
+    }
+
+    #[test]
+    fn encodes_to_memory() {
+        let source = brightening_video(FrameSize::new(16, 8));
+        let options = EncoderOptions::builder()
+            .backend_type(BackendType::Cpu)
+            .format(OutputFormat::Gif)
+            .build();
+        let (bytes, summary) = encode_gif_to_vec(source, &options).unwrap();
+        assert!(summary.palette_error.is_none());
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(bytes.as_slice())
+            .unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (16, 8));
+        let mut frame_count = 0;
+        while decoder.read_next_frame().unwrap().is_some() {
+            frame_count += 1;
+        }
+        assert_eq!(frame_count, 3);
+    }
+
+    #[test]
+    fn small_palettes_use_short_codes() {
+        // The transparent index counts towards the size of the table. Codes
+        // only need to be long enough for the indices a frame uses, but gifs
+        // can't have codes shorter than 2 bits.
+        for (color_count, table_size, max_code_size) in [(2, 2, 2), (4, 4, 2), (16, 16, 4)] {
+            let fixed: Vec<u8> = (0..(color_count - 1) * 3).map(|i| (i * 40) as u8).collect();
+            let adaptive = Palette::Adaptive {
+                algorithm: PaletteAlgorithm::MedianCut,
+                sampling: PaletteSampling::AllFrames,
+                max_colors: color_count,
+            };
+            for palette in [Palette::Fixed(fixed), adaptive] {
+                let options = EncoderOptions::builder()
+                    .palette(palette)
+                    .backend_type(BackendType::Cpu)
+                    .format(OutputFormat::Gif)
+                    .build();
+                let (bytes, _) =
+                    encode_gif_to_vec(brightening_video(FrameSize::new(16, 8)), &options).unwrap();
+                let (actual_table_size, code_sizes) = read_code_sizes(&bytes);
+                assert_eq!(actual_table_size, table_size);
+                assert_eq!(code_sizes.len(), 3);
+                assert!(code_sizes
+                    .iter()
+                    .all(|size| (2..=max_code_size).contains(size)));
+            }
+        }
+    }
+
+    #[test]
+    fn full_fixed_palettes_report_the_dropped_color() {
+        let encode = |palette: Palette| {
+            let options = EncoderOptions::builder()
+                .palette(palette)
+                .backend_type(BackendType::Cpu)
+                .build();
+            encode_gif_to_vec(brightening_video(FrameSize::new(4, 4)), &options)
+        };
+        let mut colors: Vec<u8> = (0..256 * 3).map(|i| (i / 3) as u8).collect();
+        let (_, summary) = encode(Palette::Fixed(colors.clone())).unwrap();
+        assert_eq!(summary.palette, colors[..255 * 3]);
+        assert_eq!(summary.dropped_color, Some([255, 255, 255]));
+
+        colors.truncate(255 * 3);
+        let (_, summary) = encode(Palette::Fixed(colors)).unwrap();
+        assert_eq!(summary.dropped_color, None);
+        let (_, summary) = encode(Palette::Fixed(DEFAULT_PALETTE.to_vec())).unwrap();
+        assert_eq!(summary.dropped_color, None);
+
+        assert!(matches!(
+            encode(Palette::Fixed(vec![0; 4])),
+            Err(Error::Palette(_))
+        ));
+        assert!(matches!(
+            encode(Palette::Fixed(vec![0; 257 * 3])),
+            Err(Error::Palette(_))
+        ));
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use crate::{
+    error::Result,
+    frame::{DiffRect, FrameSize},
+};
+
+use super::apng::ApngColors;
+
+struct DumpedFrame {
+    file_name: String,
+    rect: DiffRect,
+    // In centiseconds
+    delay: u16,
+    timestamp: Duration,
+}
+
+/// Writes every sub-image as an indexed PNG to a directory, and a JSON
+/// manifest describing them to the output once it's finished.
+pub(crate) struct FrameDumpWriter<W: Write> {
+    manifest: W,
+    directory: PathBuf,
+    frame_size: FrameSize,
+    // The global palette, including the transparent color
+    palette: Vec<u8>,
+    frames: Vec<DumpedFrame>,
+}
+
+impl<W: Write> FrameDumpWriter<W> {
+    pub fn new(manifest: W, directory: PathBuf, frame_size: FrameSize, palette: Vec<u8>) -> Self {
+        Self {
+            manifest,
+            directory,
+            frame_size,
+            palette,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Writes the palette indices of the given area, along with the palette
+    /// they index into, which is the global one unless the area has its own.
+    /// `timestamp` is the time of the source frame.
+    pub fn write_frame(
+        &mut self,
+        rect: &DiffRect,
+        indices: &[u8],
+        local_palette: Option<&[u8]>,
+        transparent_index: Option<u8>,
+        delay: u16,
+        timestamp: Duration,
+    ) -> Result<()> {
+        let file_name = format!("frame_{:05}.png", self.frames.len());
+        let file = BufWriter::new(File::create(self.directory.join(&file_name))?);
+        let mut encoder = png::Encoder::new(file, rect.width(), rect.height());
+        let colors = ApngColors::Indexed {
+            palette: local_palette.unwrap_or(&self.palette).to_vec(),
+            transparent_index,
+        };
+        colors.configure(&mut encoder);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(indices)?;
+        writer.finish()?;
+
+        self.frames.push(DumpedFrame {
+            file_name,
+            rect: *rect,
+            delay,
+            timestamp,
+        });
+        Ok(())
+    }
+
+    /// Writes the manifest, which lists the frames in the order they're
+    /// shown.
+    pub fn finish(mut self) -> Result<()> {
+        let manifest = &mut self.manifest;
+        writeln!(manifest, "{{")?;
+        writeln!(manifest, "  \"width\": {},", self.frame_size.width)?;
+        writeln!(manifest, "  \"height\": {},", self.frame_size.height)?;
+        writeln!(manifest, "  \"frames\": [")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let separator = if i + 1 < self.frames.len() { "," } else { "" };
+            writeln!(
+                manifest,
+                "    {{ \"file\": \"{}\", \"left\": {}, \"top\": {}, \"width\": {}, \"height\": {}, \"delay_cs\": {}, \"timestamp_ms\": {:.3} }}{}",
+                frame.file_name,
+                frame.rect.left,
+                frame.rect.top,
+                frame.rect.width(),
+                frame.rect.height(),
+                frame.delay,
+                frame.timestamp.as_secs_f64() * 1000.0,
+                separator
+            )?;
+        }
+        writeln!(manifest, "  ]")?;
+        writeln!(manifest, "}}")?;
+        manifest.flush()?;
+        Ok(())
+    }
+}
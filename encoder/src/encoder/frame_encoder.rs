@@ -0,0 +1,1206 @@
+use std::{
+    borrow::Cow,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use gif::{DisposalMethod, Frame, Repeat};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "d3d")]
+use windows::Win32::Graphics::Direct3D11::ID3D11Texture2D;
+
+use crate::{
+    backend::Backend,
+    error::{Error, Result},
+    frame::{DiffRect, FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+    palette::local::LocalPalettes,
+    source::FrameSource,
+};
+
+use super::{
+    apng::{ApngColors, ApngWriter},
+    frame_dump::FrameDumpWriter,
+    webp::{WebPQuality, WebPWriter},
+};
+
+// The color stored in the palette at the transparent index, which is never
+// displayed.
+const TRANSPARENT_COLOR: [u8; 3] = [0, 0, 0];
+
+// One palette index is reserved for transparent pixels.
+pub(crate) const MAX_OPAQUE_COLORS: usize = 255;
+
+// The shortest delay browsers respect, in centiseconds. Most of them show
+// anything shorter for 10cs instead.
+const MIN_DELAY: u16 = 2;
+
+/// How the gif plays back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct PlaybackOptions {
+    /// Frames that would be shown for less than 2cs are merged into the next
+    /// frame, so browsers don't slow them down.
+    pub merge_short_frames: bool,
+    pub loop_count: LoopCount,
+    /// How long the last frame is shown for, on top of its own duration.
+    pub final_hold: Duration,
+    /// How long the first frame is shown for before the rest of the gif
+    /// plays.
+    pub initial_delay: Duration,
+}
+
+/// How many times the animation plays.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum LoopCount {
+    /// The animation loops forever.
+    #[default]
+    Infinite,
+    /// The animation plays once, then repeats this many times. Animations
+    /// that don't repeat stop on their last frame.
+    Finite(u16),
+}
+
+/// The file format frames are encoded to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum OutputFormat {
+    #[default]
+    Gif,
+    /// An animated PNG with full color frames.
+    Apng,
+    /// An animated PNG with the same palette a gif would use, which makes for
+    /// smaller files.
+    #[cfg_attr(feature = "serde", serde(rename = "apng-indexed"))]
+    IndexedApng,
+    /// An animated WebP with full color frames.
+    #[cfg_attr(feature = "serde", serde(rename = "webp"))]
+    WebP(WebPQuality),
+    /// Every sub-image as an indexed PNG in the directory, quantized the same
+    /// way as for a gif. The output gets a JSON manifest with the area, delay
+    /// and timestamp of each one. Meant for debugging and for handing frames
+    /// to other tools.
+    #[cfg_attr(feature = "serde", serde(rename = "png-frames"))]
+    FrameDump { directory: PathBuf },
+}
+
+impl OutputFormat {
+    /// Picks the format based on the extension of the path. PNGs are full
+    /// color and WebPs are lossless.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "gif" => Some(OutputFormat::Gif),
+            "png" | "apng" => Some(OutputFormat::Apng),
+            "webp" => Some(OutputFormat::WebP(WebPQuality::Lossless)),
+            _ => None,
+        }
+    }
+}
+
+enum Output<W: Write> {
+    Gif(gif::Encoder<W>),
+    Apng(ApngWriter<W>),
+    WebP(WebPWriter<W>),
+    FrameDump(FrameDumpWriter<W>),
+}
+
+/// A sub-image that's ready to be written once its delay is known.
+struct SubImage {
+    rect: DiffRect,
+    // Palette indices, or RGBA pixels for full color formats
+    pixels: Vec<u8>,
+    // A local palette, including the transparent color
+    palette: Option<Vec<u8>>,
+    transparent: Option<u8>,
+    // The timestamp of the frame the sub-image is part of
+    timestamp: Duration,
+}
+
+/// Turns a sequence of frames into a gif, an APNG or a WebP using the given
+/// backend.
+pub struct FrameEncoder<W: Write> {
+    backend: Box<dyn Backend>,
+    output: Output<W>,
+    format: OutputFormat,
+    palette: Vec<u8>,
+    frame_size: FrameSize,
+    local_palettes: Option<LocalPalettes>,
+    transparent_index: u8,
+    // The color currently shown at each pixel, if anything has been drawn there.
+    displayed_colors: Vec<Option<[u8; 3]>>,
+    playback_options: PlaybackOptions,
+    delays: FrameDelays,
+    // The sub-images of the last frame with changes. A frame is shown until
+    // the next one with changes, so they're only written once that arrives.
+    pending: Vec<SubImage>,
+    // Set when a frame was merged into the next one without being diffed
+    skipped_frame: bool,
+    last_frame_timestamp: Option<Duration>,
+    // The time between the last two frames
+    last_frame_duration: Option<Duration>,
+}
+
+impl<W: Write> FrameEncoder<W> {
+    /// When `local_palette_threshold` is set, frames whose mean CIELAB
+    /// distance to the palette is above it are given a local color table.
+    ///
+    /// The palette can have at most 255 colors, since the index after them
+    /// is reserved for transparency. It's used for pixels that didn't change
+    /// since the previous frame. Full color APNGs and WebPs use the alpha
+    /// channel instead, and don't have local palettes.
+    ///
+    /// APNGs and WebPs are only written to the output once the encoder is
+    /// finished.
+    pub fn new(
+        backend: Box<dyn Backend>,
+        palette: &[u8],
+        frame_size: FrameSize,
+        output: W,
+        local_palette_threshold: Option<f64>,
+        playback_options: PlaybackOptions,
+        format: OutputFormat,
+    ) -> Result<Self> {
+        // The backend never picks the transparent index, since it only knows
+        // about the colors before it.
+        let color_count = palette.len() / 3;
+        if color_count == 0 || color_count > MAX_OPAQUE_COLORS {
+            return Err(Error::InvalidOptions(format!(
+                "The encoder needs 1 to {} colors to leave room for transparency, found {}",
+                MAX_OPAQUE_COLORS, color_count
+            )));
+        }
+        if frame_size.width == 0 || frame_size.height == 0 || frame_size.is_too_large() {
+            return Err(Error::InvalidOptions(format!(
+                "Can't encode frames that are {}x{}",
+                frame_size.width, frame_size.height
+            )));
+        }
+        let transparent_index = color_count as u8;
+        let mut gif_palette = palette.to_vec();
+        gif_palette.extend_from_slice(&TRANSPARENT_COLOR);
+
+        let loop_count = playback_options.loop_count;
+        let output = match &format {
+            OutputFormat::Gif => {
+                let mut encoder = gif::Encoder::new(
+                    output,
+                    frame_size.width as u16,
+                    frame_size.height as u16,
+                    &gif_palette,
+                )?;
+                // Gifs without a repeat count only play once
+                encoder.set_repeat(match loop_count {
+                    LoopCount::Infinite => Repeat::Infinite,
+                    LoopCount::Finite(count) => Repeat::Finite(count),
+                })?;
+                Output::Gif(encoder)
+            }
+            OutputFormat::Apng => Output::Apng(ApngWriter::new(
+                output,
+                frame_size,
+                ApngColors::Rgba,
+                loop_count,
+            )),
+            OutputFormat::IndexedApng => {
+                let colors = ApngColors::Indexed {
+                    palette: gif_palette,
+                    transparent_index: Some(transparent_index),
+                };
+                Output::Apng(ApngWriter::new(output, frame_size, colors, loop_count))
+            }
+            OutputFormat::WebP(_) => Output::WebP(WebPWriter::new(output, frame_size, loop_count)),
+            OutputFormat::FrameDump { directory } => Output::FrameDump(FrameDumpWriter::new(
+                output,
+                directory.clone(),
+                frame_size,
+                gif_palette,
+            )),
+        };
+
+        // Local palettes need to leave room for the transparent index too. APNGs
+        // and WebPs only have the one palette.
+        let local_palette_threshold = local_palette_threshold
+            .filter(|_| matches!(format, OutputFormat::Gif | OutputFormat::FrameDump { .. }));
+        let local_palettes = local_palette_threshold
+            .map(|threshold| LocalPalettes::new(palette, threshold, MAX_OPAQUE_COLORS))
+            .transpose()?;
+
+        Ok(Self {
+            backend,
+            output,
+            format,
+            palette: palette.to_vec(),
+            frame_size,
+            local_palettes,
+            transparent_index,
+            displayed_colors: vec![None; frame_size.width as usize * frame_size.height as usize],
+            playback_options,
+            delays: FrameDelays::default(),
+            pending: Vec::new(),
+            skipped_frame: false,
+            last_frame_timestamp: None,
+            last_frame_duration: None,
+        })
+    }
+
+    pub fn encode_frame(&mut self, frame: &FrameBuffer, timestamp: Duration) -> Result<()> {
+        self.backend.load_frame(frame)?;
+        self.process_frame(timestamp)
+    }
+
+    /// Like `encode_frame`, for a frame that's on the backend's device.
+    #[cfg(feature = "d3d")]
+    pub fn encode_texture(&mut self, texture: &ID3D11Texture2D, timestamp: Duration) -> Result<()> {
+        self.backend.load_texture(texture)?;
+        self.process_frame(timestamp)
+    }
+
+    /// Encodes every frame of the source until it ends, then finishes the
+    /// output. Frames of sources on the GPU stay there if the backend can
+    /// take them, in which case the backend has to be on the source's device.
+    pub fn encode_source<S: FrameSource + ?Sized>(mut self, source: &mut S) -> Result<()> {
+        #[cfg(feature = "d3d")]
+        if self.backend.accepts_textures() && source.d3d_device().is_some() {
+            while let Some(frame) = source.next_texture()? {
+                self.encode_texture(&frame.texture, frame.timestamp)?;
+            }
+            return self.finish_source(source);
+        }
+        while let Some(frame) = source.next_frame()? {
+            self.encode_frame(&frame.buffer, frame.timestamp)?;
+        }
+        self.finish_source(source)
+    }
+
+    /// Shows the last frame for as long as the one before it, along with the
+    /// final hold, then writes the end of the output.
+    pub fn finish(self) -> Result<()> {
+        let end_timestamp = match (self.last_frame_timestamp, self.last_frame_duration) {
+            (Some(timestamp), Some(duration)) => timestamp + duration,
+            (timestamp, _) => timestamp.unwrap_or_default(),
+        };
+        self.finish_at(end_timestamp)
+    }
+
+    /// Like `finish`, but shows the last frame until the given timestamp.
+    pub fn finish_at(mut self, end_timestamp: Duration) -> Result<()> {
+        // A frame that was merged into the next one is the last, so it still
+        // needs to be shown
+        if let (true, Some(timestamp)) = (self.skipped_frame, self.last_frame_timestamp) {
+            self.diff_frame(timestamp)?;
+        }
+        self.write_pending(end_timestamp, true)?;
+        match self.output {
+            // The gif encoder writes the trailer when it's dropped
+            Output::Gif(_) => Ok(()),
+            Output::Apng(writer) => writer.finish(),
+            Output::WebP(writer) => writer.finish(),
+            Output::FrameDump(writer) => writer.finish(),
+        }
+    }
+
+    fn finish_source<S: FrameSource + ?Sized>(self, source: &S) -> Result<()> {
+        match source.end_timestamp() {
+            Some(end_timestamp) => self.finish_at(end_timestamp),
+            None => self.finish(),
+        }
+    }
+
+    fn process_frame(&mut self, timestamp: Duration) -> Result<()> {
+        if let Some(last_frame_timestamp) = self.last_frame_timestamp {
+            self.last_frame_duration = Some(timestamp.saturating_sub(last_frame_timestamp));
+        }
+        self.last_frame_timestamp = Some(timestamp);
+        self.delays.start(timestamp);
+
+        // Skipping the diff leaves the changes for the next frame, and the
+        // frame before it is shown for longer instead
+        if self.playback_options.merge_short_frames && !self.pending.is_empty() {
+            let (delay, padding) = self.pending_delay(timestamp, false);
+            if delay.saturating_add(padding) < MIN_DELAY {
+                self.skipped_frame = true;
+                return Ok(());
+            }
+        }
+        self.diff_frame(timestamp)
+    }
+
+    fn diff_frame(&mut self, timestamp: Duration) -> Result<()> {
+        self.skipped_frame = false;
+        let regions = self.backend.diff()?;
+
+        // If there's no change, the last frame stays on screen for longer
+        if regions.is_empty() {
+            return Ok(());
+        }
+
+        self.write_pending(timestamp, false)?;
+        for rect in regions {
+            let sub_image = self.prepare_sub_image(rect, timestamp)?;
+            self.pending.push(sub_image);
+        }
+        Ok(())
+    }
+
+    /// The delay of the pending frame if it's shown until the given
+    /// timestamp, along with the time that isn't part of the recording.
+    fn pending_delay(&self, end_timestamp: Duration, last: bool) -> (u16, u16) {
+        let mut padding = 0;
+        if !self.delays.has_written() {
+            padding += centiseconds(self.playback_options.initial_delay);
+        }
+        if last {
+            padding += centiseconds(self.playback_options.final_hold);
+        }
+        let padding = padding.min(u16::MAX as u64) as u16;
+        (self.delays.delay_until(end_timestamp), padding)
+    }
+
+    /// Writes the sub-images of the pending frame, which is shown until the
+    /// given timestamp.
+    fn write_pending(&mut self, end_timestamp: Duration, last: bool) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let (mut delay, padding) = self.pending_delay(end_timestamp, last);
+        if self.playback_options.merge_short_frames && delay.saturating_add(padding) < MIN_DELAY {
+            // Frames that can't be merged into the next one are held for as
+            // long as browsers allow, which the next delay makes up for
+            delay = MIN_DELAY - padding;
+        }
+        self.delays.advance(delay);
+
+        // The regions of a frame are shown at once: every sub-image but the
+        // last has no delay, and the last one gets the frame's whole delay
+        // along with the padding.
+        let frame_delay = delay.saturating_add(padding);
+        let sub_images = std::mem::take(&mut self.pending);
+        let last_index = sub_images.len() - 1;
+        for (i, sub_image) in sub_images.into_iter().enumerate() {
+            let delay = if i == last_index { frame_delay } else { 0 };
+            self.write_sub_image(sub_image, delay)?;
+        }
+        Ok(())
+    }
+
+    fn prepare_sub_image(&mut self, mut rect: DiffRect, timestamp: Duration) -> Result<SubImage> {
+        // Inflate our rect to eliminate artifacts
+        let inflate_amount = 1;
+        let left = rect.left as i32 - inflate_amount;
+        let top = rect.top as i32 - inflate_amount;
+        let right = rect.right as i32 + inflate_amount;
+        let bottom = rect.bottom as i32 + inflate_amount;
+        rect.left = left.max(0) as u32;
+        rect.top = top.max(0) as u32;
+        rect.right = (right as u32).min(self.frame_size.width);
+        rect.bottom = (bottom as u32).min(self.frame_size.height);
+
+        // Full color formats skip the palette altogether
+        let rounded_bits = match &self.format {
+            OutputFormat::Apng => Some(0),
+            OutputFormat::WebP(quality) => {
+                // WebP frames can only start at even offsets
+                rect.left &= !1;
+                rect.top &= !1;
+                Some(quality.rounded_bits())
+            }
+            OutputFormat::Gif | OutputFormat::IndexedApng | OutputFormat::FrameDump { .. } => None,
+        };
+        if let Some(rounded_bits) = rounded_bits {
+            let pixels = rgba_pixels(
+                &mut self.displayed_colors,
+                self.backend.loaded_frame(&rect)?,
+                &rect,
+                rounded_bits,
+            );
+            return Ok(SubImage {
+                rect,
+                pixels,
+                palette: None,
+                transparent: None,
+                timestamp,
+            });
+        }
+
+        let mut bytes = self.backend.quantize(&rect)?;
+
+        // Regions the global palette can't represent well get their own
+        let mut palette = None;
+        if let Some(local_palettes) = &self.local_palettes {
+            let frame = self.backend.loaded_frame(&rect)?;
+            if let Some((local_palette, local_bytes)) =
+                local_palettes.quantize(frame, &rect, &bytes)
+            {
+                palette = Some(local_palette);
+                bytes = local_bytes;
+            }
+        }
+
+        // Pixels that look the same as what's already displayed are made
+        // transparent, which compresses much better
+        let (colors, transparent_index) = match &palette {
+            Some(local_palette) => (local_palette.as_slice(), (local_palette.len() / 3) as u8),
+            None => (self.palette.as_slice(), self.transparent_index),
+        };
+        hide_unchanged_pixels(
+            &mut self.displayed_colors,
+            self.frame_size.width as usize,
+            &rect,
+            colors,
+            transparent_index,
+            &mut bytes,
+        );
+        if let Some(local_palette) = &mut palette {
+            local_palette.extend_from_slice(&TRANSPARENT_COLOR);
+        }
+        Ok(SubImage {
+            rect,
+            pixels: bytes,
+            palette,
+            transparent: Some(transparent_index),
+            timestamp,
+        })
+    }
+
+    fn write_sub_image(&mut self, sub_image: SubImage, delay: u16) -> Result<()> {
+        let SubImage {
+            rect,
+            pixels,
+            palette,
+            transparent,
+            timestamp,
+        } = sub_image;
+        let encoder = match &mut self.output {
+            Output::Gif(encoder) => encoder,
+            Output::Apng(writer) => return writer.write_frame(&rect, &pixels, delay),
+            Output::WebP(writer) => return writer.write_frame(&rect, &pixels, delay),
+            Output::FrameDump(writer) => {
+                let local_palette = palette.as_deref();
+                return writer.write_frame(
+                    &rect,
+                    &pixels,
+                    local_palette,
+                    transparent,
+                    delay,
+                    timestamp,
+                );
+            }
+        };
+
+        // Build our gif frame
+        let width = rect.width();
+        let height = rect.height();
+        let mut gif_frame =
+            create_gif_frame(width as u16, height as u16, &pixels, palette, transparent);
+        gif_frame.left = rect.left as u16;
+        gif_frame.top = rect.top as u16;
+        // Transparent pixels and the other regions of the frame rely on the
+        // previous sub-images staying in place
+        gif_frame.dispose = DisposalMethod::Keep;
+        gif_frame.delay = delay;
+
+        // Write our frame to disk
+        encoder.write_frame(&gif_frame)?;
+        Ok(())
+    }
+}
+
+/// Turns timestamps into delays. Every delay is rounded from the time since
+/// the first frame rather than on its own, so rounding errors don't add up
+/// over long recordings.
+#[derive(Default)]
+struct FrameDelays {
+    first_timestamp: Option<Duration>,
+    // The total delay written so far, in centiseconds
+    elapsed: u64,
+    written: bool,
+}
+
+impl FrameDelays {
+    /// Counts from the given timestamp, unless a frame came before it.
+    fn start(&mut self, timestamp: Duration) {
+        self.first_timestamp.get_or_insert(timestamp);
+    }
+
+    /// The delay from the end of the last frame written until the given
+    /// timestamp, in centiseconds.
+    fn delay_until(&self, timestamp: Duration) -> u16 {
+        let first_timestamp = self.first_timestamp.unwrap_or(timestamp);
+        let target = centiseconds(timestamp.saturating_sub(first_timestamp));
+        // Anything past the longest delay is carried over to the next frame
+        target.saturating_sub(self.elapsed).min(u16::MAX as u64) as u16
+    }
+
+    fn advance(&mut self, delay: u16) {
+        self.elapsed += delay as u64;
+        self.written = true;
+    }
+
+    fn has_written(&self) -> bool {
+        self.written
+    }
+}
+
+fn centiseconds(duration: Duration) -> u64 {
+    (duration.as_micros() as u64 + 5_000) / 10_000
+}
+
+/// Replaces the indices of pixels whose color is already displayed with the
+/// transparent index, and records the color of every other pixel.
+fn hide_unchanged_pixels(
+    displayed_colors: &mut [Option<[u8; 3]>],
+    frame_width: usize,
+    rect: &DiffRect,
+    palette: &[u8],
+    transparent_index: u8,
+    indices: &mut [u8],
+) {
+    let rect_width = rect.width() as usize;
+    for (y, row) in indices.chunks_exact_mut(rect_width).enumerate() {
+        let begin = (rect.top as usize + y) * frame_width + rect.left as usize;
+        let displayed_row = &mut displayed_colors[begin..begin + rect_width];
+        for (index, displayed) in row.iter_mut().zip(displayed_row) {
+            let position = *index as usize * 3;
+            let color = [
+                palette[position],
+                palette[position + 1],
+                palette[position + 2],
+            ];
+            if *displayed == Some(color) {
+                *index = transparent_index;
+            } else {
+                *displayed = Some(color);
+            }
+        }
+    }
+}
+
+/// Returns the RGBA colors of the given area of the frame, with the lowest
+/// `rounded_bits` of each channel rounded away. Pixels whose color is already
+/// displayed are left fully transparent, the others are recorded as
+/// displayed.
+fn rgba_pixels(
+    displayed_colors: &mut [Option<[u8; 3]>],
+    frame: &FrameBuffer,
+    rect: &DiffRect,
+    rounded_bits: u8,
+) -> Vec<u8> {
+    // Rounding up can't go past the largest value with those bits cleared
+    let step = 1u16 << rounded_bits;
+    let round = |value: u8| ((value as u16 + step / 2) / step * step).min(256 - step) as u8;
+    let frame_width = frame.size.width as usize;
+    let mut pixels = Vec::with_capacity(rect.width() as usize * rect.height() as usize * 4);
+    let begin = rect.left as usize * BYTES_PER_PIXEL;
+    let end = rect.right as usize * BYTES_PER_PIXEL;
+    for y in rect.top..rect.bottom {
+        let row = &frame.row(y)[begin..end];
+        let displayed_begin = y as usize * frame_width + rect.left as usize;
+        let displayed_row = &mut displayed_colors[displayed_begin..];
+        for (pixel, displayed) in row.chunks_exact(BYTES_PER_PIXEL).zip(displayed_row) {
+            // Pixels are BGRA
+            let color = [round(pixel[2]), round(pixel[1]), round(pixel[0])];
+            if *displayed == Some(color) {
+                pixels.extend_from_slice(&[0, 0, 0, 0]);
+            } else {
+                *displayed = Some(color);
+                pixels.extend_from_slice(&[color[0], color[1], color[2], 255]);
+            }
+        }
+    }
+    pixels
+}
+
+fn create_gif_frame<'a>(
+    width: u16,
+    height: u16,
+    pixels: &'a [u8],
+    palette: Option<Vec<u8>>,
+    transparent: Option<u8>,
+) -> Frame<'a> {
+    assert_eq!(
+        width as usize * height as usize,
+        pixels.len(),
+        "Too many or too little pixels for the given width and height to create a GIF Frame"
+    );
+
+    Frame {
+        width,
+        height,
+        buffer: Cow::Borrowed(pixels),
+        palette,
+        transparent,
+        ..Frame::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{codecs::webp::WebPDecoder, AnimationDecoder};
+
+    use super::{FrameEncoder, LoopCount, OutputFormat, PlaybackOptions, MIN_DELAY};
+    use crate::{
+        backend::Backend,
+        encoder::{palette::DEFAULT_PALETTE, webp::WebPQuality},
+        error::{Error, Result},
+        frame::{DiffRect, FrameBuffer, FrameSize},
+        source::raw::{PixelFormat, RawVideoSource},
+    };
+
+    const SIZE: FrameSize = FrameSize {
+        width: 8,
+        height: 8,
+    };
+
+    /// Reports the area that changed since the last frame diffed, and
+    /// quantizes everything to the first color.
+    struct ChangedAreaBackend {
+        frame: FrameBuffer,
+        previous: Option<FrameBuffer>,
+    }
+
+    impl Backend for ChangedAreaBackend {
+        fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()> {
+            self.frame = frame.clone();
+            Ok(())
+        }
+
+        fn loaded_frame(&mut self, _rect: &DiffRect) -> Result<&FrameBuffer> {
+            Ok(&self.frame)
+        }
+
+        fn diff(&mut self) -> Result<Vec<DiffRect>> {
+            let mut changed: Option<DiffRect> = None;
+            for y in 0..SIZE.height {
+                for x in 0..SIZE.width {
+                    let begin = (y * SIZE.width + x) as usize * 4;
+                    let pixel = &self.frame.bytes[begin..begin + 4];
+                    if self
+                        .previous
+                        .as_ref()
+                        .is_some_and(|previous| &previous.bytes[begin..begin + 4] == pixel)
+                    {
+                        continue;
+                    }
+                    let rect = changed.get_or_insert(DiffRect {
+                        left: x,
+                        top: y,
+                        right: x + 1,
+                        bottom: y + 1,
+                    });
+                    rect.left = rect.left.min(x);
+                    rect.top = rect.top.min(y);
+                    rect.right = rect.right.max(x + 1);
+                    rect.bottom = rect.bottom.max(y + 1);
+                }
+            }
+            self.previous = Some(self.frame.clone());
+            Ok(changed.into_iter().collect())
+        }
+
+        fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>> {
+            Ok(vec![0; rect.width() as usize * rect.height() as usize])
+        }
+    }
+
+    /// Reports two opposite corners of the frame as changed every time, far
+    /// enough apart that they're worth encoding as separate regions.
+    struct CornersBackend {
+        frame: FrameBuffer,
+    }
+
+    impl CornersBackend {
+        const SIZE: FrameSize = FrameSize {
+            width: 128,
+            height: 128,
+        };
+    }
+
+    impl Backend for CornersBackend {
+        fn load_frame(&mut self, frame: &FrameBuffer) -> Result<()> {
+            self.frame = frame.clone();
+            Ok(())
+        }
+
+        fn loaded_frame(&mut self, _rect: &DiffRect) -> Result<&FrameBuffer> {
+            Ok(&self.frame)
+        }
+
+        fn diff(&mut self) -> Result<Vec<DiffRect>> {
+            let (width, height) = (Self::SIZE.width, Self::SIZE.height);
+            Ok(vec![
+                DiffRect {
+                    left: 0,
+                    top: 0,
+                    right: 4,
+                    bottom: 4,
+                },
+                DiffRect {
+                    left: width - 4,
+                    top: height - 4,
+                    right: width,
+                    bottom: height,
+                },
+            ])
+        }
+
+        fn quantize(&mut self, rect: &DiffRect) -> Result<Vec<u8>> {
+            Ok(vec![0; rect.width() as usize * rect.height() as usize])
+        }
+    }
+
+    /// Encodes a frame that changes at every timestamp.
+    fn encode(
+        timestamps: &[Duration],
+        playback_options: PlaybackOptions,
+        format: OutputFormat,
+    ) -> Vec<u8> {
+        let frames: Vec<FrameBuffer> = (0..timestamps.len())
+            .map(|i| {
+                let mut frame = FrameBuffer::new(SIZE);
+                frame.bytes.fill(i as u8);
+                frame
+            })
+            .collect();
+        encode_frames(&frames, timestamps, playback_options, format)
+    }
+
+    fn encode_frames(
+        frames: &[FrameBuffer],
+        timestamps: &[Duration],
+        playback_options: PlaybackOptions,
+        format: OutputFormat,
+    ) -> Vec<u8> {
+        let backend = ChangedAreaBackend {
+            frame: FrameBuffer::new(SIZE),
+            previous: None,
+        };
+        encode_with_backend(
+            Box::new(backend),
+            frames,
+            timestamps,
+            playback_options,
+            format,
+        )
+    }
+
+    fn encode_with_backend(
+        backend: Box<dyn Backend>,
+        frames: &[FrameBuffer],
+        timestamps: &[Duration],
+        playback_options: PlaybackOptions,
+        format: OutputFormat,
+    ) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = FrameEncoder::new(
+            backend,
+            &[0, 0, 0, 255, 255, 255],
+            frames[0].size,
+            &mut output,
+            None,
+            playback_options,
+            format,
+        )
+        .unwrap();
+        for (frame, timestamp) in frames.iter().zip(timestamps) {
+            encoder.encode_frame(frame, *timestamp).unwrap();
+        }
+        encoder.finish().unwrap();
+        output
+    }
+
+    /// Returns the delay of each sub-image.
+    fn read_delays(gif: &[u8]) -> Vec<u16> {
+        let mut decoder = gif::DecodeOptions::new().read_info(gif).unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        delays
+    }
+
+    /// Returns the delay of each frame in milliseconds, along with the RGBA
+    /// pixels shown once it's drawn.
+    fn read_webp(webp: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let frames = WebPDecoder::new(webp)
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        frames
+            .into_iter()
+            .map(|frame| {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                (numerator / denominator, frame.into_buffer().into_raw())
+            })
+            .collect()
+    }
+
+    fn total(delays: &[u16]) -> u32 {
+        delays.iter().map(|delay| *delay as u32).sum()
+    }
+
+    #[test]
+    fn playback_matches_the_capture_duration() {
+        // 30 fps for ten seconds, which truncating each frame to 3cs would
+        // play back a tenth too fast
+        let timestamps: Vec<Duration> = (0..300)
+            .map(|i| Duration::from_nanos(i * 33_333_333))
+            .collect();
+        let delays = read_delays(&encode(
+            &timestamps,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        ));
+        assert_eq!(delays.len(), 300);
+        assert_eq!(total(&delays), 1000);
+        assert!(delays.iter().all(|delay| (3..=4).contains(delay)));
+    }
+
+    #[test]
+    fn frames_are_shown_until_the_next_one() {
+        let frames: Vec<FrameBuffer> = (0..3)
+            .map(|i| {
+                let mut frame = FrameBuffer::new(SIZE);
+                frame.bytes.fill(i);
+                frame
+            })
+            .collect();
+        let backend = ChangedAreaBackend {
+            frame: FrameBuffer::new(SIZE),
+            previous: None,
+        };
+        let mut output = Vec::new();
+        let mut encoder = FrameEncoder::new(
+            Box::new(backend),
+            &[0, 0, 0],
+            SIZE,
+            &mut output,
+            None,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        )
+        .unwrap();
+        for (frame, timestamp) in frames.iter().zip([0, 500, 600]) {
+            encoder
+                .encode_frame(frame, Duration::from_millis(timestamp))
+                .unwrap();
+        }
+        encoder.finish_at(Duration::from_millis(900)).unwrap();
+        assert_eq!(read_delays(&output), [50, 10, 30]);
+    }
+
+    #[test]
+    fn sources_set_how_long_the_last_frame_is_shown() {
+        // 7 frames at 3 fps, so the gif should last 7/3 seconds
+        let bytes: Vec<u8> = (0..7)
+            .flat_map(|i| vec![i; SIZE.width as usize * SIZE.height as usize * 4])
+            .collect();
+        let mut source =
+            RawVideoSource::new(bytes.as_slice(), SIZE, PixelFormat::Bgra8, 3.0).unwrap();
+        let backend = ChangedAreaBackend {
+            frame: FrameBuffer::new(SIZE),
+            previous: None,
+        };
+        let mut output = Vec::new();
+        FrameEncoder::new(
+            Box::new(backend),
+            &[0, 0, 0],
+            SIZE,
+            &mut output,
+            None,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        )
+        .unwrap()
+        .encode_source(&mut source)
+        .unwrap();
+        let delays = read_delays(&output);
+        assert_eq!(delays.len(), 7);
+        assert_eq!(total(&delays), 233);
+    }
+
+    #[test]
+    fn short_frames_are_merged() {
+        // 140 fps, so most frames are shorter than browsers allow
+        let timestamps: Vec<Duration> = (0..150).map(|i| Duration::from_millis(i * 7)).collect();
+
+        let delays = read_delays(&encode(
+            &timestamps,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        ));
+        assert_eq!(delays.len(), 150);
+        assert_eq!(total(&delays), 105);
+        assert!(delays.iter().any(|delay| *delay < MIN_DELAY));
+
+        let playback_options = PlaybackOptions {
+            merge_short_frames: true,
+            ..PlaybackOptions::default()
+        };
+        let delays = read_delays(&encode(&timestamps, playback_options, OutputFormat::Gif));
+        assert!(delays.len() < 150);
+        assert!(delays.iter().all(|delay| *delay >= MIN_DELAY));
+        // The last frame and the one before it may need to be longer than
+        // what's left
+        let total = total(&delays);
+        assert!(
+            (105..=105 + 2 * MIN_DELAY as u32).contains(&total),
+            "{} instead of 105",
+            total
+        );
+    }
+
+    #[test]
+    fn merging_short_frames_keeps_the_first_frame() {
+        let timestamps: Vec<Duration> = (0..3).map(|i| Duration::from_millis(i * 500)).collect();
+        let playback_options = PlaybackOptions {
+            merge_short_frames: true,
+            ..PlaybackOptions::default()
+        };
+        let webp = encode(
+            &timestamps,
+            playback_options,
+            OutputFormat::WebP(WebPQuality::Lossless),
+        );
+        let frames = read_webp(&webp);
+        assert_eq!(frames.len(), 3);
+        for (i, (delay, pixels)) in frames.iter().enumerate() {
+            let value = i as u8;
+            assert_eq!(*delay, 500);
+            assert!(pixels
+                .chunks_exact(4)
+                .all(|pixel| pixel == [value, value, value, 255]));
+        }
+    }
+
+    #[test]
+    fn regions_of_a_frame_are_shown_at_once() {
+        let timestamps: Vec<Duration> = (0..10).map(|i| Duration::from_millis(i * 100)).collect();
+        let frames = vec![FrameBuffer::new(CornersBackend::SIZE); timestamps.len()];
+        let backend = CornersBackend {
+            frame: frames[0].clone(),
+        };
+        let gif = encode_with_backend(
+            Box::new(backend),
+            &frames,
+            &timestamps,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        );
+        // Only the last sub-image of a frame has a delay
+        assert_eq!(read_delays(&gif), [0, 10].repeat(10));
+    }
+
+    #[test]
+    fn unchanged_pixels_are_transparent() {
+        // Everything is quantized to the first color, so after the first
+        // frame every pixel already shows what it's quantized to
+        let timestamps: Vec<Duration> = (0..4).map(|i| Duration::from_millis(i * 50)).collect();
+        let gif = encode(&timestamps, PlaybackOptions::default(), OutputFormat::Gif);
+        let mut decoder = gif::DecodeOptions::new().read_info(gif.as_slice()).unwrap();
+        let mut frame_count = 0;
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.transparent, Some(2));
+            let expected = if frame_count == 0 { 0 } else { 2 };
+            assert!(frame.buffer.iter().all(|index| *index == expected));
+            frame_count += 1;
+        }
+        assert_eq!(frame_count, timestamps.len());
+    }
+
+    #[test]
+    fn full_palettes_are_rejected() {
+        let backend = ChangedAreaBackend {
+            frame: FrameBuffer::new(SIZE),
+            previous: None,
+        };
+        let result = FrameEncoder::new(
+            Box::new(backend),
+            &DEFAULT_PALETTE,
+            SIZE,
+            Vec::new(),
+            None,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        );
+        assert!(matches!(result, Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn the_first_and_last_frames_can_be_held() {
+        let timestamps: Vec<Duration> = (0..10).map(|i| Duration::from_millis(i * 50)).collect();
+        let playback_options = PlaybackOptions {
+            loop_count: LoopCount::Finite(0),
+            final_hold: Duration::from_secs(2),
+            initial_delay: Duration::from_millis(500),
+            ..PlaybackOptions::default()
+        };
+        let gif = encode(&timestamps, playback_options, OutputFormat::Gif);
+        let delays = read_delays(&gif);
+        assert_eq!(delays.first(), Some(&55));
+        assert_eq!(delays.last(), Some(&205));
+        assert_eq!(total(&delays), 50 + 50 + 200);
+
+        // Gifs that play once don't have a repeat count
+        let has_repeat_count = |gif: &[u8]| gif.windows(11).any(|window| window == b"NETSCAPE2.0");
+        assert!(!has_repeat_count(&gif));
+        assert!(has_repeat_count(&encode(
+            &timestamps,
+            PlaybackOptions::default(),
+            OutputFormat::Gif
+        )));
+    }
+
+    #[test]
+    fn apngs_have_the_same_frames_and_delays() {
+        let timestamps: Vec<Duration> = (0..10).map(|i| Duration::from_millis(i * 50)).collect();
+        let playback_options = PlaybackOptions {
+            loop_count: LoopCount::Finite(2),
+            ..PlaybackOptions::default()
+        };
+        let gif_delays = read_delays(&encode(&timestamps, playback_options, OutputFormat::Gif));
+
+        for format in [OutputFormat::Apng, OutputFormat::IndexedApng] {
+            let apng = encode(&timestamps, playback_options, format);
+            let mut reader = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+            let animation_control = reader.info().animation_control.unwrap();
+            assert_eq!(animation_control.num_frames as usize, gif_delays.len());
+            assert_eq!(animation_control.num_plays, 3);
+
+            let mut buffer = vec![0; reader.output_buffer_size()];
+            let mut delays = Vec::new();
+            for _ in 0..animation_control.num_frames {
+                reader.next_frame(&mut buffer).unwrap();
+                let frame_control = reader.info().frame_control.unwrap();
+                assert_eq!(frame_control.delay_den, 100);
+                delays.push(frame_control.delay_num);
+            }
+            assert_eq!(delays, gif_delays);
+        }
+    }
+
+    #[test]
+    fn webps_have_the_same_frames_and_delays() {
+        let timestamps: Vec<Duration> = (0..10).map(|i| Duration::from_millis(i * 50)).collect();
+        let playback_options = PlaybackOptions {
+            loop_count: LoopCount::Finite(2),
+            ..PlaybackOptions::default()
+        };
+        let gif_delays = read_delays(&encode(&timestamps, playback_options, OutputFormat::Gif));
+
+        let webp = encode(
+            &timestamps,
+            playback_options,
+            OutputFormat::WebP(WebPQuality::Lossless),
+        );
+        let frames = read_webp(&webp);
+        let delays: Vec<u32> = frames.iter().map(|(delay, _)| *delay).collect();
+        let expected: Vec<u32> = gif_delays.iter().map(|delay| *delay as u32 * 10).collect();
+        assert_eq!(delays, expected);
+        for (i, (_, pixels)) in frames.iter().enumerate() {
+            let value = i as u8;
+            assert!(pixels
+                .chunks_exact(4)
+                .all(|pixel| pixel == [value, value, value, 255]));
+        }
+
+        // The loop count is the number of times the animation plays
+        let animation = webp
+            .windows(4)
+            .position(|fourcc| fourcc == b"ANIM")
+            .unwrap();
+        let loop_count = u16::from_le_bytes([webp[animation + 12], webp[animation + 13]]);
+        assert_eq!(loop_count, 3);
+    }
+
+    #[test]
+    fn webp_frames_show_the_changed_areas() {
+        let mut first = FrameBuffer::new(SIZE);
+        for (i, byte) in first.bytes.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        // Changes away from even offsets, which is where WebP frames start
+        let mut second = first.clone();
+        for y in 6..8 {
+            second.row_mut(y)[4 * 4..6 * 4].fill(200);
+        }
+        let frames = [first, second];
+        let timestamps = [Duration::ZERO, Duration::from_millis(100)];
+
+        for (quality, rounded_bits) in [(WebPQuality::Lossless, 0), (WebPQuality::Lossy(60), 2)] {
+            let webp = encode_frames(
+                &frames,
+                &timestamps,
+                PlaybackOptions::default(),
+                OutputFormat::WebP(quality),
+            );
+            let step = 1 << rounded_bits;
+            let round = |value: u8| ((value as u16 + step / 2) / step * step).min(256 - step) as u8;
+            let decoded = read_webp(&webp);
+            for (frame, (_, pixels)) in frames.iter().zip(&decoded) {
+                let expected: Vec<u8> = frame
+                    .bytes
+                    .chunks_exact(4)
+                    .flat_map(|pixel| [round(pixel[2]), round(pixel[1]), round(pixel[0]), 255])
+                    .collect();
+                assert_eq!(pixels, &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn frame_dumps_have_a_png_per_sub_image() {
+        let directory =
+            std::env::temp_dir().join(format!("gifencoder-frame-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let timestamps: Vec<Duration> = (0..4).map(|i| Duration::from_millis(i * 50)).collect();
+        let gif_delays = read_delays(&encode(
+            &timestamps,
+            PlaybackOptions::default(),
+            OutputFormat::Gif,
+        ));
+
+        let format = OutputFormat::FrameDump {
+            directory: directory.clone(),
+        };
+        let manifest = encode(&timestamps, PlaybackOptions::default(), format);
+        let manifest = String::from_utf8(manifest).unwrap();
+        let frames: Vec<&str> = manifest
+            .lines()
+            .filter(|line| line.contains("\"file\""))
+            .collect();
+        assert_eq!(frames.len(), gif_delays.len());
+        assert!(frames[1].contains("\"delay_cs\": 5, \"timestamp_ms\": 50.000"));
+
+        // Everything is quantized to the first color, so only the first frame
+        // shows anything
+        for i in 0..frames.len() {
+            let file = std::fs::File::open(directory.join(format!("frame_{:05}.png", i))).unwrap();
+            let mut reader = png::Decoder::new(file).read_info().unwrap();
+            let info = reader.info();
+            assert_eq!(
+                info.palette.as_deref(),
+                Some(&[0, 0, 0, 255, 255, 255, 0, 0, 0][..])
+            );
+            assert_eq!(info.trns.as_deref(), Some(&[255, 255, 0][..]));
+            let mut indices = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut indices).unwrap();
+            let expected = if i == 0 { 0 } else { 2 };
+            assert!(indices.iter().all(|index| *index == expected));
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
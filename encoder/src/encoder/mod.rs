@@ -1,6 +1,8 @@
+mod apng;
 pub mod capture_gif_encoder;
-mod compositor;
-mod diff;
-mod lut;
+pub mod encode;
+mod frame_dump;
+pub mod frame_encoder;
+pub mod options;
 pub mod palette;
-mod quantizer;
+pub mod webp;
@@ -0,0 +1,180 @@
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{BackendOptions, BackendType, DiffThreshold, Dither},
+    palette::Palette,
+    source::pacing::FrameRate,
+};
+
+use super::frame_encoder::{LoopCount, OutputFormat, PlaybackOptions};
+
+/// Everything that decides how frames are encoded. Missing fields in a
+/// serialized config fall back to their defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct EncoderOptions {
+    pub palette: Palette,
+    /// Frames whose mean CIELAB distance to the palette is above this get a
+    /// local color table. Only gifs and frame dumps have local palettes.
+    pub local_palette_threshold: Option<f64>,
+    pub backend: BackendOptions,
+    pub playback: PlaybackOptions,
+    /// How many frames per second are kept from sources that produce frames
+    /// whenever something changes, like a capture session. Other sources keep
+    /// their own timing.
+    pub frame_rate: FrameRate,
+    pub format: OutputFormat,
+}
+
+impl EncoderOptions {
+    pub fn builder() -> EncoderOptionsBuilder {
+        EncoderOptionsBuilder::default()
+    }
+}
+
+/// Builds `EncoderOptions`, starting from the defaults.
+#[derive(Clone, Debug, Default)]
+pub struct EncoderOptionsBuilder {
+    options: EncoderOptions,
+}
+
+/// Starts from existing options, like ones loaded from a config file.
+impl From<EncoderOptions> for EncoderOptionsBuilder {
+    fn from(options: EncoderOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl EncoderOptionsBuilder {
+    pub fn palette(mut self, palette: Palette) -> Self {
+        self.options.palette = palette;
+        self
+    }
+
+    pub fn local_palette_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.options.local_palette_threshold = threshold;
+        self
+    }
+
+    pub fn backend_type(mut self, backend_type: BackendType) -> Self {
+        self.options.backend.backend_type = backend_type;
+        self
+    }
+
+    pub fn diff_threshold(mut self, diff_threshold: DiffThreshold) -> Self {
+        self.options.backend.diff_threshold = diff_threshold;
+        self
+    }
+
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.options.backend.dither = dither;
+        self
+    }
+
+    /// Only meant for debugging the diffing.
+    pub fn disable_frame_diff(mut self, disable_frame_diff: bool) -> Self {
+        self.options.backend.disable_frame_diff = disable_frame_diff;
+        self
+    }
+
+    pub fn frame_rate(mut self, frame_rate: FrameRate) -> Self {
+        self.options.frame_rate = frame_rate;
+        self
+    }
+
+    pub fn merge_short_frames(mut self, merge_short_frames: bool) -> Self {
+        self.options.playback.merge_short_frames = merge_short_frames;
+        self
+    }
+
+    pub fn loop_count(mut self, loop_count: LoopCount) -> Self {
+        self.options.playback.loop_count = loop_count;
+        self
+    }
+
+    pub fn final_hold(mut self, final_hold: Duration) -> Self {
+        self.options.playback.final_hold = final_hold;
+        self
+    }
+
+    pub fn initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.options.playback.initial_delay = initial_delay;
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.options.format = format;
+        self
+    }
+
+    pub fn build(self) -> EncoderOptions {
+        self.options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::EncoderOptions;
+    use crate::{
+        backend::{BackendType, Dither},
+        encoder::{
+            frame_encoder::{LoopCount, OutputFormat},
+            webp::WebPQuality,
+        },
+        palette::{Palette, PaletteAlgorithm, PaletteSampling},
+    };
+
+    fn options() -> EncoderOptions {
+        EncoderOptions::builder()
+            .palette(Palette::Adaptive {
+                algorithm: PaletteAlgorithm::KMeans,
+                sampling: PaletteSampling::AllFrames,
+                max_colors: 64,
+            })
+            .backend_type(BackendType::Cpu)
+            .dither(Dither::Bayer4)
+            .loop_count(LoopCount::Finite(2))
+            .final_hold(Duration::from_secs(1))
+            .format(OutputFormat::WebP(WebPQuality::Lossy(80)))
+            .build()
+    }
+
+    #[test]
+    fn builder_keeps_unset_defaults() {
+        let options = options();
+        let defaults = EncoderOptions::default();
+        assert_eq!(options.backend.dither, Dither::Bayer4);
+        assert_eq!(options.playback.loop_count, LoopCount::Finite(2));
+        assert_eq!(
+            options.backend.diff_threshold,
+            defaults.backend.diff_threshold
+        );
+        assert_eq!(options.frame_rate, defaults.frame_rate);
+        assert_eq!(options.local_palette_threshold, None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn round_trips_through_json() {
+        let options = options();
+        let json = serde_json::to_string(&options).unwrap();
+        assert_eq!(
+            serde_json::from_str::<EncoderOptions>(&json).unwrap(),
+            options
+        );
+
+        // Missing fields fall back to their defaults
+        let options: EncoderOptions = serde_json::from_str(
+            r#"{ "format": "apng-indexed", "backend": { "dither": "blue-noise" } }"#,
+        )
+        .unwrap();
+        assert_eq!(options.format, OutputFormat::IndexedApng);
+        assert_eq!(options.backend.dither, Dither::BlueNoise);
+        assert_eq!(options.palette, Palette::default());
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::Write;
+
+use image::{codecs::webp::WebPEncoder, ColorType};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::Result,
+    frame::{DiffRect, FrameSize},
+};
+
+use super::frame_encoder::LoopCount;
+
+const RIFF_HEADER_LENGTH: usize = 12;
+
+// VP8X flags
+const ALPHA_FLAG: u8 = 0x10;
+const ANIMATION_FLAG: u8 = 0x02;
+
+/// How WebP frames are compressed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum WebPQuality {
+    /// Every color is kept as is.
+    #[default]
+    Lossless,
+    /// From 0 to 100, where lower qualities round colors further before
+    /// they're compressed, which makes for smaller files. Colors are rounded
+    /// as much as in libwebp's near lossless mode.
+    Lossy(u8),
+}
+
+impl WebPQuality {
+    /// The number of low bits rounded away from each color channel.
+    pub(crate) fn rounded_bits(self) -> u8 {
+        match self {
+            WebPQuality::Lossless => 0,
+            WebPQuality::Lossy(quality) if quality >= 100 => 0,
+            WebPQuality::Lossy(quality) => 5 - quality / 20,
+        }
+    }
+}
+
+struct WebPFrame {
+    rect: DiffRect,
+    // In milliseconds
+    duration: u32,
+    // A complete VP8L chunk
+    data: Vec<u8>,
+}
+
+/// Writes frames to an animated WebP. The RIFF header starts with the size of
+/// the file, so frames are compressed as they come in and the file is only
+/// written once it's finished.
+pub(crate) struct WebPWriter<W: Write> {
+    output: W,
+    frame_size: FrameSize,
+    loop_count: LoopCount,
+    frames: Vec<WebPFrame>,
+}
+
+impl<W: Write> WebPWriter<W> {
+    pub fn new(output: W, frame_size: FrameSize, loop_count: LoopCount) -> Self {
+        Self {
+            output,
+            frame_size,
+            loop_count,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Adds a frame of RGBA pixels covering the given area, which is shown for
+    /// `delay` centiseconds. WebP frames have to start at even offsets.
+    pub fn write_frame(&mut self, rect: &DiffRect, pixels: &[u8], delay: u16) -> Result<()> {
+        debug_assert!(
+            rect.left % 2 == 0 && rect.top % 2 == 0,
+            "WebP frames have to start at even offsets"
+        );
+        let mut encoded = Vec::new();
+        WebPEncoder::new_lossless(&mut encoded).encode(
+            pixels,
+            rect.width(),
+            rect.height(),
+            ColorType::Rgba8,
+        )?;
+        // The encoder writes a RIFF header followed by a single VP8L chunk
+        debug_assert_eq!(
+            &encoded[RIFF_HEADER_LENGTH..RIFF_HEADER_LENGTH + 4],
+            b"VP8L"
+        );
+        encoded.drain(..RIFF_HEADER_LENGTH);
+        self.frames.push(WebPFrame {
+            rect: *rect,
+            duration: delay as u32 * 10,
+            data: encoded,
+        });
+        Ok(())
+    }
+
+    /// Writes the whole WebP to the output.
+    pub fn finish(mut self) -> Result<()> {
+        let mut extended_header = Vec::with_capacity(10);
+        extended_header.push(ALPHA_FLAG | ANIMATION_FLAG);
+        extended_header.extend_from_slice(&[0; 3]);
+        extended_header.extend_from_slice(&u24(self.frame_size.width - 1));
+        extended_header.extend_from_slice(&u24(self.frame_size.height - 1));
+
+        // Unlike gifs, the loop count is the number of times the animation
+        // plays
+        let loop_count = match self.loop_count {
+            LoopCount::Infinite => 0,
+            LoopCount::Finite(count) => count.saturating_add(1),
+        };
+        let mut animation = Vec::with_capacity(6);
+        // A transparent background, in BGRA
+        animation.extend_from_slice(&[0; 4]);
+        animation.extend_from_slice(&loop_count.to_le_bytes());
+
+        let frames: Vec<Vec<u8>> = self.frames.iter().map(frame_chunk).collect();
+
+        let mut file_size = 4 + chunk_size(&extended_header) + chunk_size(&animation);
+        file_size += frames.iter().map(|frame| chunk_size(frame)).sum::<usize>();
+
+        self.output.write_all(b"RIFF")?;
+        self.output.write_all(&(file_size as u32).to_le_bytes())?;
+        self.output.write_all(b"WEBP")?;
+        write_chunk(&mut self.output, b"VP8X", &extended_header)?;
+        write_chunk(&mut self.output, b"ANIM", &animation)?;
+        for frame in &frames {
+            write_chunk(&mut self.output, b"ANMF", frame)?;
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Returns the data of an ANMF chunk for the frame.
+fn frame_chunk(frame: &WebPFrame) -> Vec<u8> {
+    let mut data = Vec::with_capacity(16 + frame.data.len());
+    data.extend_from_slice(&u24(frame.rect.left / 2));
+    data.extend_from_slice(&u24(frame.rect.top / 2));
+    data.extend_from_slice(&u24(frame.rect.width() - 1));
+    data.extend_from_slice(&u24(frame.rect.height() - 1));
+    data.extend_from_slice(&u24(frame.duration));
+    // Frames are alpha blended over the previous ones and never disposed, so
+    // transparent pixels keep the color shown by the previous frame
+    data.push(0);
+    data.extend_from_slice(&frame.data);
+    data
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let bytes = value.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+// Chunks are padded to an even size
+fn chunk_size(data: &[u8]) -> usize {
+    8 + data.len() + data.len() % 2
+}
+
+fn write_chunk<W: Write>(output: &mut W, fourcc: &[u8; 4], data: &[u8]) -> Result<()> {
+    output.write_all(fourcc)?;
+    output.write_all(&(data.len() as u32).to_le_bytes())?;
+    output.write_all(data)?;
+    if data.len() % 2 == 1 {
+        output.write_all(&[0])?;
+    }
+    Ok(())
+}
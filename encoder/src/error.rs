@@ -0,0 +1,89 @@
+use std::fmt;
+
+/// Everything that can go wrong while reading, diffing or encoding frames.
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Encoding(gif::EncodingError),
+    Png(png::EncodingError),
+    Image(image::ImageError),
+    Source(String),
+    Palette(String),
+    /// Options that can't be used together, or values out of range.
+    InvalidOptions(String),
+    /// The backend couldn't be set up on this device.
+    Backend(String),
+    /// The encoder thread panicked, with the panic message if there was one.
+    EncoderThread(String),
+    #[cfg(feature = "d3d")]
+    Windows(windows::core::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {}", error),
+            Error::Encoding(error) => write!(f, "Encoding error: {}", error),
+            Error::Png(error) => write!(f, "PNG encoding error: {}", error),
+            Error::Image(error) => write!(f, "Image error: {}", error),
+            Error::Source(message) => write!(f, "Source error: {}", message),
+            Error::Palette(message) => write!(f, "Palette error: {}", message),
+            Error::InvalidOptions(message) => write!(f, "Invalid options: {}", message),
+            Error::Backend(message) => write!(f, "Backend error: {}", message),
+            Error::EncoderThread(message) => write!(f, "The encoder thread panicked: {}", message),
+            #[cfg(feature = "d3d")]
+            Error::Windows(error) => write!(f, "Windows error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Encoding(error) => Some(error),
+            Error::Png(error) => Some(error),
+            Error::Image(error) => Some(error),
+            Error::Source(_) => None,
+            Error::Palette(_) => None,
+            Error::InvalidOptions(_) => None,
+            Error::Backend(_) => None,
+            Error::EncoderThread(_) => None,
+            #[cfg(feature = "d3d")]
+            Error::Windows(error) => Some(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<gif::EncodingError> for Error {
+    fn from(error: gif::EncodingError) -> Self {
+        Error::Encoding(error)
+    }
+}
+
+impl From<png::EncodingError> for Error {
+    fn from(error: png::EncodingError) -> Self {
+        Error::Png(error)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(error: image::ImageError) -> Self {
+        Error::Image(error)
+    }
+}
+
+#[cfg(feature = "d3d")]
+impl From<windows::core::Error> for Error {
+    fn from(error: windows::core::Error) -> Self {
+        Error::Windows(error)
+    }
+}
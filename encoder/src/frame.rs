@@ -0,0 +1,115 @@
+use zerocopy::{AsBytes, FromBytes};
+
+use crate::error::{Error, Result};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A frame of BGRA8 pixels.
+#[derive(Clone, Debug)]
+pub struct FrameBuffer {
+    pub size: FrameSize,
+    /// The number of bytes between the start of two rows.
+    pub stride: usize,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct DiffRect {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+pub const BYTES_PER_PIXEL: usize = 4;
+
+// Gifs can't be larger than this on either side.
+const MAX_DIMENSION: u32 = u16::MAX as u32;
+
+// Frames are stored as BGRA, so anything past 16384x16384 pixels would need
+// gigabytes of memory. A corrupt header shouldn't make us allocate that.
+const MAX_PIXELS: u64 = 1 << 28;
+
+impl FrameSize {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self { width, height }
+    }
+
+    /// Whether frames of this size are too large to encode.
+    pub(crate) fn is_too_large(&self) -> bool {
+        self.width > MAX_DIMENSION
+            || self.height > MAX_DIMENSION
+            || self.width as u64 * self.height as u64 > MAX_PIXELS
+    }
+
+    pub fn full_rect(&self) -> DiffRect {
+        DiffRect {
+            left: 0,
+            top: 0,
+            right: self.width,
+            bottom: self.height,
+        }
+    }
+}
+
+impl FrameBuffer {
+    pub fn new(size: FrameSize) -> Self {
+        let stride = size.width as usize * BYTES_PER_PIXEL;
+        Self {
+            size,
+            stride,
+            bytes: vec![0u8; stride * size.height as usize],
+        }
+    }
+
+    pub fn from_bytes(size: FrameSize, stride: usize, bytes: Vec<u8>) -> Result<Self> {
+        if stride < size.width as usize * BYTES_PER_PIXEL {
+            return Err(Error::Source(format!(
+                "A stride of {} bytes can't hold a row of {} pixels",
+                stride, size.width
+            )));
+        }
+        if bytes.len() < stride * size.height as usize {
+            return Err(Error::Source(format!(
+                "{} bytes are too few for a {}x{} frame with a stride of {} bytes",
+                bytes.len(),
+                size.width,
+                size.height,
+                stride
+            )));
+        }
+        Ok(Self {
+            size,
+            stride,
+            bytes,
+        })
+    }
+
+    pub fn row(&self, y: u32) -> &[u8] {
+        let begin = y as usize * self.stride;
+        &self.bytes[begin..begin + self.size.width as usize * BYTES_PER_PIXEL]
+    }
+
+    pub fn row_mut(&mut self, y: u32) -> &mut [u8] {
+        let begin = y as usize * self.stride;
+        let end = begin + self.size.width as usize * BYTES_PER_PIXEL;
+        &mut self.bytes[begin..end]
+    }
+}
+
+impl DiffRect {
+    pub fn is_valid(&self) -> bool {
+        self.right >= self.left && self.bottom >= self.top
+    }
+    pub fn width(&self) -> u32 {
+        self.right - self.left
+    }
+    pub fn height(&self) -> u32 {
+        self.bottom - self.top
+    }
+}
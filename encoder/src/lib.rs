@@ -1,11 +1,64 @@
+// `is_multiple_of` needs Rust 1.87, older toolchains build this crate too.
+#![allow(clippy::manual_is_multiple_of)]
+
 extern crate gif;
+#[cfg(feature = "d3d")]
+extern crate gifshaders;
+extern crate glob;
+extern crate image;
+extern crate png;
+#[cfg(feature = "d3d")]
 extern crate robmikh_common;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+#[cfg(feature = "d3d")]
 extern crate windows;
 extern crate zerocopy;
 
+mod backend;
+#[cfg(feature = "d3d")]
 mod capture;
 mod encoder;
+mod error;
+mod frame;
+mod palette;
+mod source;
+#[cfg(feature = "d3d")]
 mod util;
 
+pub use backend::{
+    cpu::{CpuBackend, FrameDiffer, PaletteIndexLUT},
+    create_backend, Backend, BackendOptions, BackendType, DiffThreshold, Dither,
+};
+#[cfg(feature = "d3d")]
+pub use backend::{create_backend_on_device, d3d::D3DBackend};
+#[cfg(feature = "d3d")]
+pub use capture::{frame_generator::CaptureFrameGeneratorSession, source::CaptureFrameSource};
 pub use encoder::capture_gif_encoder::CaptureGifEncoder;
+pub use encoder::encode::{encode_gif, encode_gif_to_vec, generate_palette, EncodeSummary};
+pub use encoder::frame_encoder::{FrameEncoder, LoopCount, OutputFormat, PlaybackOptions};
+pub use encoder::options::{EncoderOptions, EncoderOptionsBuilder};
 pub use encoder::palette::DEFAULT_PALETTE;
+pub use encoder::webp::WebPQuality;
+pub use error::{Error, Result};
+pub use frame::{DiffRect, FrameBuffer, FrameSize};
+pub use palette::{
+    create_palette_builder,
+    file::{load_palette_file, read_palette, save_palette_file, write_palette, PaletteFormat},
+    kmeans::KMeans,
+    mean_quantization_error,
+    median_cut::MedianCut,
+    octree::Octree,
+    ColorHistogram, Palette, PaletteAlgorithm, PaletteBuilder, PaletteSampling,
+};
+#[cfg(feature = "d3d")]
+pub use source::SourceTexture;
+pub use source::{
+    image_sequence::{FrameTiming, ImageSequenceSource},
+    pacing::FrameRate,
+    raw::{PixelFormat, RawVideoSource},
+    y4m::Y4mSource,
+    FrameSource, SourceFrame,
+};
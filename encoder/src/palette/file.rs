@@ -0,0 +1,362 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use crate::error::{Error, Result};
+
+/// The file formats palettes can be read from and written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// A GIMP palette (.gpl).
+    Gpl,
+    /// An Adobe color table (.act).
+    Act,
+    /// A JASC (Paint Shop Pro) palette (.pal).
+    Pal,
+    /// One RRGGBB hex color per line (.hex). Paint.NET's AARRGGBB lines
+    /// are also read, ignoring the alpha.
+    Hex,
+}
+
+impl PaletteFormat {
+    /// Picks the format based on the extension of the path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "gpl" => Some(PaletteFormat::Gpl),
+            "act" => Some(PaletteFormat::Act),
+            "pal" => Some(PaletteFormat::Pal),
+            "hex" | "txt" => Some(PaletteFormat::Hex),
+            _ => None,
+        }
+    }
+}
+
+/// Reads a palette file, picking the format based on its extension. Returns
+/// the palette as a list of RGB colors.
+pub fn load_palette_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let format = format_from_path(path.as_ref())?;
+    let file = File::open(path)?;
+    read_palette(BufReader::new(file), format)
+}
+
+/// Writes a palette file, picking the format based on its extension.
+pub fn save_palette_file<P: AsRef<Path>>(path: P, palette: &[u8]) -> Result<()> {
+    let format = format_from_path(path.as_ref())?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_palette(&mut writer, palette, format)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a palette in the given format as a list of RGB colors.
+pub fn read_palette<R: BufRead>(mut reader: R, format: PaletteFormat) -> Result<Vec<u8>> {
+    let palette = match format {
+        PaletteFormat::Act => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            read_act(&bytes)?
+        }
+        _ => {
+            let mut text = String::new();
+            reader.read_to_string(&mut text)?;
+            match format {
+                PaletteFormat::Gpl => read_gpl(&text)?,
+                PaletteFormat::Pal => read_pal(&text)?,
+                PaletteFormat::Hex => read_hex(&text)?,
+                PaletteFormat::Act => unreachable!(),
+            }
+        }
+    };
+    validate_size(&palette)?;
+    Ok(palette)
+}
+
+/// Writes a list of RGB colors as a palette in the given format.
+pub fn write_palette<W: Write>(
+    writer: &mut W,
+    palette: &[u8],
+    format: PaletteFormat,
+) -> Result<()> {
+    if palette.len() % 3 != 0 {
+        return Err(Error::Palette(format!(
+            "A palette must be a list of RGB colors, found {} bytes",
+            palette.len()
+        )));
+    }
+    validate_size(palette)?;
+    let colors = palette.chunks_exact(3);
+    match format {
+        PaletteFormat::Gpl => {
+            writeln!(writer, "GIMP Palette")?;
+            writeln!(writer, "Name: giffun")?;
+            writeln!(writer, "Columns: 16")?;
+            writeln!(writer, "#")?;
+            for (i, color) in colors.enumerate() {
+                writeln!(
+                    writer,
+                    "{:3} {:3} {:3}\tIndex {}",
+                    color[0], color[1], color[2], i
+                )?;
+            }
+        }
+        PaletteFormat::Act => {
+            let color_count = colors.len();
+            let mut bytes = vec![0u8; 256 * 3];
+            bytes[..color_count * 3].copy_from_slice(&palette[..color_count * 3]);
+            writer.write_all(&bytes)?;
+            // The color count is only needed for partial tables. No index is
+            // marked as transparent.
+            if color_count < 256 {
+                writer.write_all(&(color_count as u16).to_be_bytes())?;
+                writer.write_all(&0xFFFFu16.to_be_bytes())?;
+            }
+        }
+        PaletteFormat::Pal => {
+            // JASC palettes use Windows line endings.
+            write!(writer, "JASC-PAL\r\n0100\r\n{}\r\n", colors.len())?;
+            for color in colors {
+                write!(writer, "{} {} {}\r\n", color[0], color[1], color[2])?;
+            }
+        }
+        PaletteFormat::Hex => {
+            for color in colors {
+                writeln!(writer, "{:02x}{:02x}{:02x}", color[0], color[1], color[2])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_size(palette: &[u8]) -> Result<()> {
+    if palette.is_empty() {
+        return Err(Error::Palette("The palette has no colors".to_owned()));
+    }
+    if palette.len() > 256 * 3 {
+        return Err(Error::Palette(format!(
+            "The palette has {} colors, but gifs support at most 256",
+            palette.len() / 3
+        )));
+    }
+    Ok(())
+}
+
+fn format_from_path(path: &Path) -> Result<PaletteFormat> {
+    PaletteFormat::from_path(path).ok_or_else(|| {
+        Error::Palette(format!(
+            "Unknown palette format for \"{}\", expected a .gpl, .act, .pal or .hex file",
+            path.display()
+        ))
+    })
+}
+
+fn read_gpl(text: &str) -> Result<Vec<u8>> {
+    let mut lines = text.lines();
+    if lines.next().map(|line| line.trim()) != Some("GIMP Palette") {
+        return Err(Error::Palette("Missing GIMP Palette header".to_owned()));
+    }
+    let mut palette = Vec::new();
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        // Anything after the three components is the name of the color.
+        let components: Vec<&str> = line.split_whitespace().take(3).collect();
+        palette.extend_from_slice(&parse_rgb(&components, line)?);
+    }
+    Ok(palette)
+}
+
+fn read_act(bytes: &[u8]) -> Result<Vec<u8>> {
+    if bytes.len() < 256 * 3 {
+        return Err(Error::Palette(format!(
+            "An Adobe color table must be at least 768 bytes, found {}",
+            bytes.len()
+        )));
+    }
+    // Partial tables store the number of colors after the colors themselves.
+    let color_count = if bytes.len() >= 256 * 3 + 2 {
+        match u16::from_be_bytes([bytes[768], bytes[769]]) as usize {
+            0 => 256,
+            count => count.min(256),
+        }
+    } else {
+        256
+    };
+    Ok(bytes[..color_count * 3].to_vec())
+}
+
+fn read_pal(text: &str) -> Result<Vec<u8>> {
+    let mut lines = text.lines().map(|line| line.trim());
+    if lines.next() != Some("JASC-PAL") {
+        return Err(Error::Palette("Missing JASC-PAL header".to_owned()));
+    }
+    // The version, which is always 0100.
+    lines.next();
+    let color_count: usize = lines
+        .next()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| Error::Palette("Missing JASC-PAL color count".to_owned()))?;
+    if color_count == 0 || color_count > 256 {
+        return Err(Error::Palette(format!(
+            "A JASC palette has 1 to 256 colors, found a count of {}",
+            color_count
+        )));
+    }
+
+    let mut palette = Vec::with_capacity(color_count * 3);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let components: Vec<&str> = line.split_whitespace().collect();
+        palette.extend_from_slice(&parse_rgb(&components, line)?);
+    }
+    if palette.len() != color_count * 3 {
+        return Err(Error::Palette(format!(
+            "Expected {} colors in the JASC palette, found {}",
+            color_count,
+            palette.len() / 3
+        )));
+    }
+    Ok(palette)
+}
+
+fn read_hex(text: &str) -> Result<Vec<u8>> {
+    let mut palette = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let hex = line.strip_prefix('#').unwrap_or(line);
+        let value = if hex.len() == 6 || hex.len() == 8 {
+            u32::from_str_radix(hex, 16).ok()
+        } else {
+            None
+        };
+        let value = value.ok_or_else(|| invalid_color(line))?;
+        palette.extend_from_slice(&[(value >> 16) as u8, (value >> 8) as u8, value as u8]);
+    }
+    Ok(palette)
+}
+
+fn parse_rgb(components: &[&str], line: &str) -> Result<[u8; 3]> {
+    if components.len() != 3 {
+        return Err(invalid_color(line));
+    }
+    let mut color = [0u8; 3];
+    for (channel, component) in color.iter_mut().zip(components) {
+        *channel = component.parse().map_err(|_| invalid_color(line))?;
+    }
+    Ok(color)
+}
+
+fn invalid_color(line: &str) -> Error {
+    Error::Palette(format!("Invalid color: \"{}\"", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_palette, write_palette, PaletteFormat};
+    use crate::error::Error;
+
+    const FORMATS: [PaletteFormat; 4] = [
+        PaletteFormat::Gpl,
+        PaletteFormat::Act,
+        PaletteFormat::Pal,
+        PaletteFormat::Hex,
+    ];
+
+    fn round_trip(palette: &[u8], format: PaletteFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_palette(&mut bytes, palette, format).unwrap();
+        read_palette(bytes.as_slice(), format).unwrap()
+    }
+
+    fn read_error(bytes: &[u8], format: PaletteFormat) -> bool {
+        matches!(read_palette(bytes, format), Err(Error::Palette(_)))
+    }
+
+    #[test]
+    fn palettes_round_trip() {
+        let small = [0, 0, 0, 255, 255, 255, 12, 34, 56];
+        let full: Vec<u8> = (0..256 * 3).map(|i| (i * 7 % 256) as u8).collect();
+        for format in FORMATS {
+            assert_eq!(round_trip(&small, format), small, "{:?}", format);
+            assert_eq!(round_trip(&full, format), full, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn invalid_palettes_are_not_written() {
+        let too_many = vec![0; 257 * 3];
+        for format in FORMATS {
+            for palette in [&[][..], &[1, 2, 3, 4][..], &too_many[..]] {
+                let mut bytes = Vec::new();
+                let result = write_palette(&mut bytes, palette, format);
+                assert!(matches!(result, Err(Error::Palette(_))), "{:?}", format);
+                assert!(bytes.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_palettes_are_rejected() {
+        assert!(read_error(b"0 0 0\n", PaletteFormat::Gpl));
+        assert!(read_error(b"GIMP Palette\n0 0\n", PaletteFormat::Gpl));
+        assert!(read_error(b"GIMP Palette\n0 0 256\n", PaletteFormat::Gpl));
+        assert!(read_error(b"GIMP Palette\n", PaletteFormat::Gpl));
+
+        assert!(read_error(&[0; 767], PaletteFormat::Act));
+
+        assert!(read_error(b"0100\n1\n0 0 0\n", PaletteFormat::Pal));
+        assert!(read_error(b"JASC-PAL\n0100\n", PaletteFormat::Pal));
+        assert!(read_error(
+            b"JASC-PAL\n0100\n2\n0 0 0\n",
+            PaletteFormat::Pal
+        ));
+        assert!(read_error(b"JASC-PAL\n0100\n0\n", PaletteFormat::Pal));
+        assert!(read_error(
+            b"JASC-PAL\n0100\n18446744073709551615\n0 0 0\n",
+            PaletteFormat::Pal
+        ));
+
+        assert!(read_error(b"12345\n", PaletteFormat::Hex));
+        assert!(read_error(b"#12345g\n", PaletteFormat::Hex));
+        assert!(read_error(
+            "000000\n".repeat(257).as_bytes(),
+            PaletteFormat::Hex
+        ));
+    }
+
+    #[test]
+    fn act_color_counts_are_read() {
+        // Without a count, the table has all 256 colors
+        let mut bytes = vec![7; 256 * 3];
+        assert_eq!(
+            read_palette(bytes.as_slice(), PaletteFormat::Act)
+                .unwrap()
+                .len(),
+            256 * 3
+        );
+        bytes.extend_from_slice(&[0, 2, 0xFF, 0xFF]);
+        assert_eq!(
+            read_palette(bytes.as_slice(), PaletteFormat::Act).unwrap(),
+            [7; 6]
+        );
+    }
+
+    #[test]
+    fn paint_net_hex_colors_ignore_alpha() {
+        let text = b"; paint.net palette\nFF102030\n#405060\n";
+        assert_eq!(
+            read_palette(&text[..], PaletteFormat::Hex).unwrap(),
+            [0x10, 0x20, 0x30, 0x40, 0x50, 0x60]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    backend::cpu::{lab_to_i32, rgb2lab},
+    error::Result,
+};
+
+use super::{median_cut::MedianCut, pad_palette, ColorHistogram, PaletteBuilder};
+
+const MAX_ITERATIONS: usize = 16;
+
+/// Builds a palette by refining a median cut palette with k-means clustering
+/// in CIELAB space. Colors are compared with the same Lab values the LUT
+/// uses, so the clusters match how frames are quantized. This is the slowest
+/// builder, but the most accurate.
+pub struct KMeans {
+    max_colors: usize,
+    // Picks the starting centers
+    initial: MedianCut,
+}
+
+impl KMeans {
+    pub fn new(max_colors: usize) -> Result<Self> {
+        Ok(Self {
+            max_colors,
+            initial: MedianCut::new(max_colors)?,
+        })
+    }
+}
+
+impl PaletteBuilder for KMeans {
+    fn build(&self, histogram: &ColorHistogram) -> Vec<u8> {
+        // Colors with the same Lab value are indistinguishable to the LUT,
+        // so they can be clustered as one point. Points are kept in order so
+        // that the clusters don't depend on hashing.
+        let mut points: BTreeMap<[i32; 3], u64> = BTreeMap::new();
+        for (color, count) in histogram.colors() {
+            let lab = lab_to_i32(rgb2lab(color[0], color[1], color[2]));
+            *points.entry(lab).or_insert(0) += count;
+        }
+        let points: Vec<([f64; 3], u64)> = points
+            .into_iter()
+            .map(|(lab, count)| ([lab[0] as f64, lab[1] as f64, lab[2] as f64], count))
+            .collect();
+        if points.is_empty() {
+            return pad_palette(Vec::new(), self.max_colors);
+        }
+
+        // Start from the median cut palette, skipping its padding.
+        let mut centers: Vec<[f64; 3]> = Vec::with_capacity(self.max_colors);
+        for color in self.initial.build(histogram).chunks_exact(3) {
+            let lab = rgb2lab(color[0], color[1], color[2]);
+            let lab = [lab[0] as f64, lab[1] as f64, lab[2] as f64];
+            if !centers.contains(&lab) {
+                centers.push(lab);
+            }
+        }
+
+        let mut assignments = vec![usize::MAX; points.len()];
+        for _ in 0..MAX_ITERATIONS {
+            let mut changed = false;
+            for (point, assignment) in points.iter().zip(assignments.iter_mut()) {
+                let closest = closest_center(&centers, &point.0);
+                if closest != *assignment {
+                    *assignment = closest;
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+
+            // Move every center to the mean of its points. Centers without
+            // any points stay where they are.
+            let mut sums = vec![([0.0f64; 3], 0u64); centers.len()];
+            for (point, assignment) in points.iter().zip(assignments.iter()) {
+                let (sum, count) = &mut sums[*assignment];
+                for (sum, value) in sum.iter_mut().zip(point.0.iter()) {
+                    *sum += value * point.1 as f64;
+                }
+                *count += point.1;
+            }
+            for (center, (sum, count)) in centers.iter_mut().zip(sums.iter()) {
+                if *count > 0 {
+                    for channel in 0..3 {
+                        center[channel] = sum[channel] / *count as f64;
+                    }
+                }
+            }
+        }
+
+        let colors = centers.iter().map(|center| lab2rgb(*center)).collect();
+        pad_palette(colors, self.max_colors)
+    }
+}
+
+fn closest_center(centers: &[[f64; 3]], point: &[f64; 3]) -> usize {
+    let mut min_distance = f64::MAX;
+    let mut closest = 0;
+    for (i, center) in centers.iter().enumerate() {
+        let distance = (0..3).map(|c| (center[c] - point[c]).powi(2)).sum::<f64>();
+        if distance < min_distance {
+            min_distance = distance;
+            closest = i;
+        }
+    }
+    closest
+}
+
+fn compute_lab_channel(channel: f64) -> f64 {
+    let cubed = channel.powi(3);
+    if cubed > 0.008856 {
+        cubed
+    } else {
+        (channel - 16.0 / 116.0) / 7.787
+    }
+}
+
+fn compute_linear_channel(channel: f64) -> u8 {
+    let channel = channel / 100.0;
+    let result = if channel > 0.0031308 {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    } else {
+        12.92 * channel
+    };
+    (result * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// The inverse of `rgb2lab`, clamping colors that are outside of sRGB.
+fn lab2rgb(lab: [f64; 3]) -> [u8; 3] {
+    let y = (lab[0] + 16.0) / 116.0;
+    let x = lab[1] / 500.0 + y;
+    let z = y - lab[2] / 200.0;
+
+    // Observer= 2°, Illuminant= D65
+    let x = compute_lab_channel(x) * 95.0470;
+    let y = compute_lab_channel(y) * 100.0;
+    let z = compute_lab_channel(z) * 108.883;
+
+    let r = x * 3.2406 + y * -1.5372 + z * -0.4986;
+    let g = x * -0.9689 + y * 1.8758 + z * 0.0415;
+    let b = x * 0.0557 + y * -0.2040 + z * 1.0570;
+
+    [
+        compute_linear_channel(r),
+        compute_linear_channel(g),
+        compute_linear_channel(b),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KMeans;
+    use crate::{
+        error::Error,
+        palette::{mean_quantization_error, median_cut::MedianCut, ColorHistogram, PaletteBuilder},
+    };
+
+    fn histogram(color_count: u32) -> ColorHistogram {
+        let mut histogram = ColorHistogram::new();
+        for i in 0..color_count {
+            let color = [
+                (i * 37 % 256) as u8,
+                (i * 101 % 256) as u8,
+                (i * 7 % 256) as u8,
+            ];
+            histogram.add_color(color, (i % 5 + 1) as u64);
+        }
+        histogram
+    }
+
+    /// Colors scattered closely around a few centers.
+    fn clusters(centers: &[[u8; 3]]) -> ColorHistogram {
+        let mut histogram = ColorHistogram::new();
+        for (i, center) in centers.iter().enumerate() {
+            for offset in 0..27u8 {
+                let jitter = [offset % 3, offset / 3 % 3, offset / 9];
+                let color = [0, 1, 2].map(|c| center[c] + jitter[c] * 2);
+                histogram.add_color(color, (i + offset as usize % 4 + 1) as u64);
+            }
+        }
+        histogram
+    }
+
+    #[test]
+    fn palettes_have_max_colors_entries() {
+        for max_colors in [1, 2, 16, 256] {
+            let kmeans = KMeans::new(max_colors).unwrap();
+            for color_count in [0, 3, 1024] {
+                let palette = kmeans.build(&histogram(color_count));
+                assert_eq!(
+                    palette.len(),
+                    max_colors * 3,
+                    "{} of {}",
+                    max_colors,
+                    color_count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn centers_move_to_their_clusters() {
+        let centers = [
+            [20, 20, 20],
+            [200, 40, 40],
+            [40, 180, 60],
+            [60, 60, 220],
+            [230, 230, 100],
+        ];
+        let histogram = clusters(&centers);
+        let kmeans = KMeans::new(centers.len()).unwrap().build(&histogram);
+        let median_cut = MedianCut::new(centers.len()).unwrap().build(&histogram);
+
+        let kmeans_error = mean_quantization_error(&histogram, &kmeans);
+        let median_cut_error = mean_quantization_error(&histogram, &median_cut);
+        assert!(
+            kmeans_error <= median_cut_error + 1e-9,
+            "{} > {}",
+            kmeans_error,
+            median_cut_error
+        );
+        // Each cluster spans 4 steps on every channel around its center
+        for center in centers {
+            let middle = center.map(|value| value + 2);
+            assert!(
+                kmeans
+                    .chunks_exact(3)
+                    .any(|entry| (0..3).all(|c| entry[c].abs_diff(middle[c]) <= 1)),
+                "No entry near {:?} in {:?}",
+                middle,
+                kmeans
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_color_counts_are_rejected() {
+        assert!(matches!(KMeans::new(0), Err(Error::InvalidOptions(_))));
+        assert!(matches!(KMeans::new(257), Err(Error::InvalidOptions(_))));
+    }
+}
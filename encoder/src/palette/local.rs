@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use crate::{
+    backend::cpu::{find_closest_color, lab_to_i32, rgb2lab},
+    error::Result,
+    frame::{DiffRect, FrameBuffer, BYTES_PER_PIXEL},
+};
+
+use super::{lab_distance, median_cut::MedianCut, sample_step, ColorHistogram, PaletteBuilder};
+
+// Measuring how well a palette fits only needs a rough estimate.
+const MAX_ERROR_SAMPLES: usize = 64 * 64;
+
+// A local color table takes up to 768 bytes, which tiny regions (like a
+// blinking cursor) can't make up for.
+const MIN_PIXELS: u32 = 32 * 32;
+
+/// Gives frames whose colors are poorly served by the global palette a local
+/// color table of their own.
+pub(crate) struct LocalPalettes {
+    threshold: f64,
+    builder: MedianCut,
+    global_palette_lab: Vec<[i32; 3]>,
+}
+
+impl LocalPalettes {
+    /// Frames are given a local palette with up to `max_colors` colors when
+    /// the mean CIELAB distance to the global palette is above the threshold.
+    pub fn new(global_palette: &[u8], threshold: f64, max_colors: usize) -> Result<Self> {
+        Ok(Self {
+            threshold,
+            builder: MedianCut::new(max_colors)?,
+            global_palette_lab: palette_to_lab(global_palette),
+        })
+    }
+
+    /// Returns a local palette along with the indices into it for the given
+    /// area of the frame, or `None` if the global palette is good enough. The
+    /// indices must be the ones produced by the global palette.
+    pub fn quantize(
+        &self,
+        frame: &FrameBuffer,
+        rect: &DiffRect,
+        global_indices: &[u8],
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        if rect.width() * rect.height() < MIN_PIXELS {
+            return None;
+        }
+        let samples = sample_colors(frame, rect);
+        let global_error = mean_error(&samples, &self.global_palette_lab, global_indices);
+        if global_error <= self.threshold {
+            return None;
+        }
+
+        let mut histogram = ColorHistogram::new();
+        histogram.add_rect(frame, rect);
+        let palette = self.builder.build(&histogram);
+        let palette_lab = palette_to_lab(&palette);
+
+        // Most regions repeat the same colors many times over.
+        let mut cache = HashMap::new();
+        let left = rect.left as usize * BYTES_PER_PIXEL;
+        let right = rect.right as usize * BYTES_PER_PIXEL;
+        let mut indices = Vec::with_capacity(rect.width() as usize * rect.height() as usize);
+        for y in rect.top..rect.bottom {
+            let row = &frame.row(y)[left..right];
+            for pixel in row.chunks_exact(BYTES_PER_PIXEL) {
+                let index = *cache
+                    .entry([pixel[2], pixel[1], pixel[0]])
+                    .or_insert_with(|| {
+                        find_closest_color(
+                            &palette_lab,
+                            lab_to_i32(rgb2lab(pixel[2], pixel[1], pixel[0])),
+                        )
+                    });
+                indices.push(index);
+            }
+        }
+
+        // Only use the local palette if it's actually an improvement.
+        let local_error = mean_error(&samples, &palette_lab, &indices);
+        if local_error >= global_error {
+            return None;
+        }
+        Some((palette, indices))
+    }
+}
+
+struct Sample {
+    // The position of the pixel within the rect.
+    offset: usize,
+    lab: [i32; 3],
+}
+
+fn sample_colors(frame: &FrameBuffer, rect: &DiffRect) -> Vec<Sample> {
+    let step = sample_step(rect, MAX_ERROR_SAMPLES);
+    let width = rect.width() as usize;
+    let mut samples = Vec::new();
+    for y in (rect.top..rect.bottom).step_by(step) {
+        let row = frame.row(y);
+        for x in (rect.left..rect.right).step_by(step) {
+            let begin = x as usize * BYTES_PER_PIXEL;
+            let pixel = &row[begin..begin + BYTES_PER_PIXEL];
+            samples.push(Sample {
+                offset: (y - rect.top) as usize * width + (x - rect.left) as usize,
+                lab: lab_to_i32(rgb2lab(pixel[2], pixel[1], pixel[0])),
+            });
+        }
+    }
+    samples
+}
+
+fn mean_error(samples: &[Sample], palette_lab: &[[i32; 3]], indices: &[u8]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let total: f64 = samples
+        .iter()
+        .map(|sample| {
+            let index = indices[sample.offset] as usize;
+            lab_distance(sample.lab, palette_lab[index])
+        })
+        .sum();
+    total / samples.len() as f64
+}
+
+fn palette_to_lab(palette: &[u8]) -> Vec<[i32; 3]> {
+    palette
+        .chunks_exact(3)
+        .take(256)
+        .map(|color| lab_to_i32(rgb2lab(color[0], color[1], color[2])))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{palette_to_lab, LocalPalettes};
+    use crate::{
+        backend::cpu::{find_closest_color, lab_to_i32, rgb2lab},
+        frame::{DiffRect, FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+    };
+
+    const SIZE: FrameSize = FrameSize {
+        width: 64,
+        height: 64,
+    };
+    const BLACK_AND_WHITE: [u8; 6] = [0, 0, 0, 255, 255, 255];
+
+    /// A frame of reds and greens, which black and white serve poorly.
+    fn colorful_frame() -> FrameBuffer {
+        let mut frame = FrameBuffer::new(SIZE);
+        for y in 0..SIZE.height {
+            for (x, pixel) in frame
+                .row_mut(y)
+                .chunks_exact_mut(BYTES_PER_PIXEL)
+                .enumerate()
+            {
+                let color = if x < 32 { [0, 40, 200] } else { [30, 180, 20] };
+                pixel.copy_from_slice(&[color[0] + y as u8, color[1], color[2], 255]);
+            }
+        }
+        frame
+    }
+
+    /// The indices the backend would produce with the given palette.
+    fn global_indices(frame: &FrameBuffer, rect: &DiffRect, palette: &[u8]) -> Vec<u8> {
+        let palette_lab = palette_to_lab(palette);
+        let mut indices = Vec::new();
+        for y in rect.top..rect.bottom {
+            for x in rect.left..rect.right {
+                let begin = x as usize * BYTES_PER_PIXEL;
+                let pixel = &frame.row(y)[begin..begin + BYTES_PER_PIXEL];
+                let lab = lab_to_i32(rgb2lab(pixel[2], pixel[1], pixel[0]));
+                indices.push(find_closest_color(&palette_lab, lab));
+            }
+        }
+        indices
+    }
+
+    fn quantize(
+        local_palettes: &LocalPalettes,
+        frame: &FrameBuffer,
+        rect: &DiffRect,
+    ) -> Option<(Vec<u8>, Vec<u8>)> {
+        let indices = global_indices(frame, rect, &BLACK_AND_WHITE);
+        local_palettes.quantize(frame, rect, &indices)
+    }
+
+    #[test]
+    fn poorly_served_frames_get_a_local_palette() {
+        let local_palettes = LocalPalettes::new(&BLACK_AND_WHITE, 10.0, 255).unwrap();
+        let frame = colorful_frame();
+        let rect = SIZE.full_rect();
+        let (palette, indices) = quantize(&local_palettes, &frame, &rect).unwrap();
+        assert_eq!(palette.len(), 255 * 3);
+        assert_eq!(indices, global_indices(&frame, &rect, &palette));
+    }
+
+    #[test]
+    fn well_served_frames_keep_the_global_palette() {
+        let local_palettes = LocalPalettes::new(&BLACK_AND_WHITE, 10.0, 255).unwrap();
+        let mut frame = FrameBuffer::new(SIZE);
+        for (i, pixel) in frame.bytes.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+            let value = if i % 3 == 0 { 255 } else { 0 };
+            pixel.copy_from_slice(&[value, value, value, 255]);
+        }
+        assert!(quantize(&local_palettes, &frame, &SIZE.full_rect()).is_none());
+    }
+
+    #[test]
+    fn local_palettes_must_lower_the_error() {
+        // Any error is above the threshold, but a single color can't do
+        // better than black and white for a frame of black and white
+        let local_palettes = LocalPalettes::new(&BLACK_AND_WHITE, -1.0, 1).unwrap();
+        let mut frame = FrameBuffer::new(SIZE);
+        for y in 0..SIZE.height / 2 {
+            frame.row_mut(y).fill(255);
+        }
+        assert!(quantize(&local_palettes, &frame, &SIZE.full_rect()).is_none());
+
+        // While one color still beats black and white for a frame of blue
+        let mut frame = FrameBuffer::new(SIZE);
+        for pixel in frame.bytes.chunks_exact_mut(BYTES_PER_PIXEL) {
+            pixel.copy_from_slice(&[200, 0, 0, 255]);
+        }
+        let (palette, _) = quantize(&local_palettes, &frame, &SIZE.full_rect()).unwrap();
+        assert_eq!(palette, [0, 0, 200]);
+    }
+
+    #[test]
+    fn small_regions_keep_the_global_palette() {
+        let local_palettes = LocalPalettes::new(&BLACK_AND_WHITE, 10.0, 255).unwrap();
+        let rect = DiffRect {
+            left: 8,
+            top: 8,
+            right: 40,
+            bottom: 39,
+        };
+        assert!(quantize(&local_palettes, &colorful_frame(), &rect).is_none());
+    }
+}
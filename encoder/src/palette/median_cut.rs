@@ -0,0 +1,194 @@
+use crate::error::Result;
+
+use super::{pad_palette, validate_color_count, ColorHistogram, PaletteBuilder};
+
+/// Builds a palette by repeatedly splitting the box of colors with the most
+/// error at the weighted median of its widest channel.
+pub struct MedianCut {
+    max_colors: usize,
+}
+
+struct ColorBox {
+    colors: Vec<([u8; 3], u64)>,
+    // The sum of squared distances from the mean, weighted by count.
+    error: f64,
+    // The channel with the largest variance.
+    channel: usize,
+}
+
+impl MedianCut {
+    pub fn new(max_colors: usize) -> Result<Self> {
+        validate_color_count(max_colors)?;
+        Ok(Self { max_colors })
+    }
+}
+
+impl PaletteBuilder for MedianCut {
+    /// Returns the palette as a list of RGB colors, padded to `max_colors`.
+    fn build(&self, histogram: &ColorHistogram) -> Vec<u8> {
+        let mut boxes = vec![ColorBox::new(histogram.colors().collect())];
+        while boxes.len() < self.max_colors {
+            // Split the box that is represented the worst by its mean.
+            let (index, _) = match boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, color_box)| color_box.colors.len() > 1)
+                .max_by(|(_, a), (_, b)| a.error.total_cmp(&b.error))
+            {
+                Some(color_box) => color_box,
+                None => break,
+            };
+            let color_box = boxes.swap_remove(index);
+            let (first, second) = color_box.split();
+            boxes.push(first);
+            boxes.push(second);
+        }
+
+        let colors = boxes
+            .iter()
+            .filter(|color_box| !color_box.colors.is_empty())
+            .map(|color_box| color_box.mean())
+            .collect();
+        pad_palette(colors, self.max_colors)
+    }
+}
+
+impl ColorBox {
+    fn new(colors: Vec<([u8; 3], u64)>) -> Self {
+        let mut count = 0.0;
+        let mut sum = [0.0f64; 3];
+        let mut sum_squared = [0.0f64; 3];
+        for (color, weight) in &colors {
+            let weight = *weight as f64;
+            count += weight;
+            for channel in 0..3 {
+                let value = color[channel] as f64;
+                sum[channel] += value * weight;
+                sum_squared[channel] += value * value * weight;
+            }
+        }
+
+        let mut error = 0.0;
+        let mut channel = 0;
+        let mut max_variance = f64::MIN;
+        if count > 0.0 {
+            for i in 0..3 {
+                let variance = sum_squared[i] - sum[i] * sum[i] / count;
+                error += variance;
+                if variance > max_variance {
+                    max_variance = variance;
+                    channel = i;
+                }
+            }
+        }
+
+        Self {
+            colors,
+            error,
+            channel,
+        }
+    }
+
+    fn split(mut self) -> (ColorBox, ColorBox) {
+        let channel = self.channel;
+        // Colors with the same value in the channel are ordered by the others,
+        // so the same histogram is always split the same way.
+        self.colors
+            .sort_unstable_by_key(|(color, _)| (color[channel], *color));
+
+        // Find the weighted median, keeping at least one color on each side.
+        let total: u64 = self.colors.iter().map(|(_, count)| count).sum();
+        let mut running = 0;
+        let mut median = 1;
+        for (i, (_, count)) in self.colors.iter().enumerate() {
+            running += count;
+            if running * 2 >= total {
+                median = i + 1;
+                break;
+            }
+        }
+        let median = median.clamp(1, self.colors.len() - 1);
+
+        let second = self.colors.split_off(median);
+        (ColorBox::new(self.colors), ColorBox::new(second))
+    }
+
+    fn mean(&self) -> [u8; 3] {
+        let mut count = 0.0;
+        let mut sum = [0.0f64; 3];
+        for (color, weight) in &self.colors {
+            let weight = *weight as f64;
+            count += weight;
+            for channel in 0..3 {
+                sum[channel] += color[channel] as f64 * weight;
+            }
+        }
+        [
+            (sum[0] / count).round() as u8,
+            (sum[1] / count).round() as u8,
+            (sum[2] / count).round() as u8,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::MedianCut;
+    use crate::{
+        error::Error,
+        palette::{ColorHistogram, PaletteBuilder},
+    };
+
+    fn histogram(color_count: u32) -> ColorHistogram {
+        let mut histogram = ColorHistogram::new();
+        for i in 0..color_count {
+            let color = [
+                (i * 37 % 256) as u8,
+                (i * 101 % 256) as u8,
+                (i * 7 % 256) as u8,
+            ];
+            histogram.add_color(color, (i % 5 + 1) as u64);
+        }
+        histogram
+    }
+
+    #[test]
+    fn palettes_have_at_most_max_colors() {
+        for max_colors in [1, 2, 16, 255, 256] {
+            let palette = MedianCut::new(max_colors).unwrap().build(&histogram(4096));
+            assert_eq!(palette.len(), max_colors * 3);
+            let distinct: HashSet<&[u8]> = palette.chunks_exact(3).collect();
+            assert!(distinct.len() <= max_colors);
+        }
+    }
+
+    #[test]
+    fn few_colors_are_kept_exactly() {
+        let palette = MedianCut::new(16).unwrap().build(&histogram(5));
+        let distinct: HashSet<&[u8]> = palette.chunks_exact(3).collect();
+        assert_eq!(distinct.len(), 5);
+        for color in histogram(5).colors().map(|(color, _)| color) {
+            assert!(distinct.contains(&color[..]));
+        }
+    }
+
+    #[test]
+    fn palettes_are_padded_with_the_last_color() {
+        // Local palettes rely on always getting 255 entries
+        let palette = MedianCut::new(255).unwrap().build(&histogram(3));
+        assert_eq!(palette.len(), 255 * 3);
+        let last = &palette[2 * 3..3 * 3];
+        assert!(palette[3 * 3..].chunks_exact(3).all(|entry| entry == last));
+
+        let palette = MedianCut::new(255).unwrap().build(&ColorHistogram::new());
+        assert_eq!(palette, vec![0; 255 * 3]);
+    }
+
+    #[test]
+    fn invalid_color_counts_are_rejected() {
+        assert!(matches!(MedianCut::new(0), Err(Error::InvalidOptions(_))));
+        assert!(matches!(MedianCut::new(257), Err(Error::InvalidOptions(_))));
+    }
+}
@@ -0,0 +1,252 @@
+pub mod file;
+pub mod kmeans;
+pub(crate) mod local;
+pub mod median_cut;
+pub mod octree;
+
+use std::collections::HashMap;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::cpu::{find_closest_color, lab_to_i32, rgb2lab},
+    encoder::palette::DEFAULT_PALETTE,
+    error::{Error, Result},
+    frame::{DiffRect, FrameBuffer, BYTES_PER_PIXEL},
+};
+
+// Large frames are sampled sparsely so that building a histogram stays fast.
+const MAX_SAMPLES_PER_FRAME: usize = 512 * 512;
+
+/// The palette used to encode a gif.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum Palette {
+    /// A fixed list of 1 to 256 RGB colors.
+    Fixed(Vec<u8>),
+    /// A palette generated from the colors of the frames being encoded.
+    Adaptive {
+        algorithm: PaletteAlgorithm,
+        sampling: PaletteSampling,
+        /// The number of colors to generate, from 1 to 256.
+        max_colors: usize,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum PaletteAlgorithm {
+    MedianCut,
+    Octree,
+    #[cfg_attr(feature = "serde", serde(rename = "kmeans"))]
+    KMeans,
+}
+
+/// Which frames are sampled to generate an adaptive palette.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum PaletteSampling {
+    /// Only the first few frames are sampled. These frames are held in memory
+    /// until the palette is ready.
+    FirstFrames(usize),
+    /// Every frame is sampled before any are encoded. Sources that can't be
+    /// restarted are held in memory in their entirety.
+    AllFrames,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::Fixed(DEFAULT_PALETTE.to_vec())
+    }
+}
+
+/// Generates a palette from the colors that appear in a set of frames.
+pub trait PaletteBuilder {
+    /// Returns the palette as a list of RGB colors.
+    fn build(&self, histogram: &ColorHistogram) -> Vec<u8>;
+}
+
+/// Creates a palette builder of the given type that generates palettes with
+/// `max_colors` entries.
+pub fn create_palette_builder(
+    algorithm: PaletteAlgorithm,
+    max_colors: usize,
+) -> Result<Box<dyn PaletteBuilder>> {
+    let builder: Box<dyn PaletteBuilder> = match algorithm {
+        PaletteAlgorithm::MedianCut => Box::new(median_cut::MedianCut::new(max_colors)?),
+        PaletteAlgorithm::Octree => Box::new(octree::Octree::new(max_colors)?),
+        PaletteAlgorithm::KMeans => Box::new(kmeans::KMeans::new(max_colors)?),
+    };
+    Ok(builder)
+}
+
+pub(crate) fn validate_color_count(color_count: usize) -> Result<()> {
+    if !(1..=256).contains(&color_count) {
+        return Err(Error::InvalidOptions(format!(
+            "A palette must have between 1 and 256 colors, found {}",
+            color_count
+        )));
+    }
+    Ok(())
+}
+
+/// Counts how often each color appears in a set of frames.
+#[derive(Clone, Debug, Default)]
+pub struct ColorHistogram {
+    counts: HashMap<[u8; 3], u64>,
+}
+
+impl ColorHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the colors of a frame, ignoring alpha.
+    pub fn add_frame(&mut self, frame: &FrameBuffer) {
+        self.add_rect(frame, &frame.size.full_rect());
+    }
+
+    /// Adds the colors of part of a frame, ignoring alpha. The right and
+    /// bottom edges of the rect are exclusive.
+    pub fn add_rect(&mut self, frame: &FrameBuffer, rect: &DiffRect) {
+        let step = sample_step(rect, MAX_SAMPLES_PER_FRAME);
+        let left = rect.left as usize * BYTES_PER_PIXEL;
+        let right = rect.right as usize * BYTES_PER_PIXEL;
+        for y in (rect.top..rect.bottom).step_by(step) {
+            let row = &frame.row(y)[left..right];
+            for pixel in row.chunks_exact(BYTES_PER_PIXEL).step_by(step) {
+                self.add_color([pixel[2], pixel[1], pixel[0]], 1);
+            }
+        }
+    }
+
+    pub fn add_color(&mut self, color: [u8; 3], count: u64) {
+        *self.counts.entry(color).or_insert(0) += count;
+    }
+
+    /// The number of distinct colors.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Every distinct color along with how often it appeared, ordered by
+    /// color. The order of a `HashMap` changes from one run to the next, which
+    /// would make palettes built from the same frames differ.
+    pub fn colors(&self) -> impl Iterator<Item = ([u8; 3], u64)> {
+        let mut colors: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(color, count)| (*color, *count))
+            .collect();
+        colors.sort_unstable_by_key(|(color, _)| *color);
+        colors.into_iter()
+    }
+}
+
+/// The distance between sampled rows and columns that keeps the number of
+/// samples in a rect below `max_samples`.
+fn sample_step(rect: &DiffRect, max_samples: usize) -> usize {
+    let pixel_count = rect.width() as usize * rect.height() as usize;
+    if pixel_count > max_samples {
+        (pixel_count as f64 / max_samples as f64).sqrt().ceil() as usize
+    } else {
+        1
+    }
+}
+
+/// Turns a list of colors into a palette of exactly `max_colors` entries by
+/// repeating the last color. Repeated entries are never picked over the
+/// first occurrence, so they don't change how frames are quantized.
+fn pad_palette(colors: Vec<[u8; 3]>, max_colors: usize) -> Vec<u8> {
+    let last = colors.last().copied().unwrap_or([0, 0, 0]);
+    let mut palette: Vec<u8> = colors.iter().flatten().copied().collect();
+    while palette.len() < max_colors * 3 {
+        palette.extend_from_slice(&last);
+    }
+    palette
+}
+
+/// The mean CIELAB distance between the colors in the histogram and the
+/// palette entries they are quantized to, weighted by how often each color
+/// appears. Colors are matched to the palette the same way the LUT does it.
+pub fn mean_quantization_error(histogram: &ColorHistogram, palette: &[u8]) -> f64 {
+    let palette_lab: Vec<[i32; 3]> = palette
+        .chunks_exact(3)
+        .take(256)
+        .map(|color| lab_to_i32(rgb2lab(color[0], color[1], color[2])))
+        .collect();
+
+    let mut total_error = 0.0;
+    let mut total_count = 0;
+    for (color, count) in histogram.colors() {
+        let color_lab = lab_to_i32(rgb2lab(color[0], color[1], color[2]));
+        let index = find_closest_color(&palette_lab, color_lab) as usize;
+        let distance = lab_distance(color_lab, palette_lab[index]);
+        total_error += distance * count as f64;
+        total_count += count;
+    }
+    if total_count == 0 {
+        0.0
+    } else {
+        total_error / total_count as f64
+    }
+}
+
+fn lab_distance(a: [i32; 3], b: [i32; 3]) -> f64 {
+    let squared_distance: i32 = (0..3).map(|i| (a[i] - b[i]).pow(2)).sum();
+    (squared_distance as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create_palette_builder, ColorHistogram, PaletteAlgorithm};
+
+    fn histogram(colors: impl Iterator<Item = u32>) -> ColorHistogram {
+        let mut histogram = ColorHistogram::new();
+        for i in colors {
+            // Many colors share a count, and many share a channel value, so
+            // the builders have plenty of ties to break.
+            let color = [
+                (i * 37 % 256) as u8,
+                (i * 11 % 7 * 32) as u8,
+                (i % 4 * 64) as u8,
+            ];
+            histogram.add_color(color, (i % 3 + 1) as u64);
+        }
+        histogram
+    }
+
+    #[test]
+    fn palettes_do_not_depend_on_insertion_order() {
+        // Each histogram hashes with its own keys, so the two also differ in
+        // iteration order.
+        let forward = histogram(0..2000);
+        let backward = histogram((0..2000).rev());
+        for algorithm in [
+            PaletteAlgorithm::MedianCut,
+            PaletteAlgorithm::Octree,
+            PaletteAlgorithm::KMeans,
+        ] {
+            let builder = create_palette_builder(algorithm, 16).unwrap();
+            let palette = builder.build(&forward);
+            assert_eq!(palette, builder.build(&backward), "{:?}", algorithm);
+        }
+    }
+}
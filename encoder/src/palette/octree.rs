@@ -0,0 +1,189 @@
+use crate::error::Result;
+
+use super::{pad_palette, validate_color_count, ColorHistogram, PaletteBuilder};
+
+/// Builds a palette by inserting every color into an octree and merging the
+/// least common leaves until at most `max_colors` remain. The octree splits
+/// the color space evenly instead of where the colors are, so it is usually
+/// less accurate than the other builders.
+pub struct Octree {
+    max_colors: usize,
+}
+
+// The root is at level 0 and every color ends up in a leaf at level 8.
+const LEAF_LEVEL: usize = 8;
+const NO_CHILD: u32 = 0;
+
+#[derive(Clone, Default)]
+struct Node {
+    // Indices into the node arena, the root can't be anyone's child.
+    children: [u32; 8],
+    // The number of pixels in this subtree and the sum of their colors.
+    count: u64,
+    sum: [u64; 3],
+    is_leaf: bool,
+}
+
+impl Octree {
+    pub fn new(max_colors: usize) -> Result<Self> {
+        validate_color_count(max_colors)?;
+        Ok(Self { max_colors })
+    }
+}
+
+impl PaletteBuilder for Octree {
+    fn build(&self, histogram: &ColorHistogram) -> Vec<u8> {
+        let mut nodes = vec![Node::default()];
+        // The internal nodes on each level, which are the candidates for merging.
+        let mut levels: Vec<Vec<u32>> = vec![Vec::new(); LEAF_LEVEL];
+        levels[0].push(0);
+        let mut leaf_count = 0;
+
+        for (color, count) in histogram.colors() {
+            let mut index = 0;
+            for level in 0..LEAF_LEVEL {
+                nodes[index].add_color(color, count);
+
+                let child = child_index(color, level);
+                if nodes[index].children[child] == NO_CHILD {
+                    let child_node_index = nodes.len() as u32;
+                    nodes.push(Node::default());
+                    nodes[index].children[child] = child_node_index;
+                    if level + 1 < LEAF_LEVEL {
+                        levels[level + 1].push(child_node_index);
+                    } else {
+                        leaf_count += 1;
+                    }
+                }
+                index = nodes[index].children[child] as usize;
+            }
+
+            nodes[index].is_leaf = true;
+            nodes[index].add_color(color, count);
+        }
+
+        // Merge the deepest nodes first, starting with the least common ones.
+        // By the time we get to a level, all of its children are leaves.
+        for level in (0..LEAF_LEVEL).rev() {
+            if leaf_count <= self.max_colors {
+                break;
+            }
+            let mut candidates = std::mem::take(&mut levels[level]);
+            candidates.sort_by_key(|index| nodes[*index as usize].count);
+            for index in candidates {
+                if leaf_count <= self.max_colors {
+                    break;
+                }
+                let index = index as usize;
+                let children = std::mem::take(&mut nodes[index].children);
+                let child_count = children.iter().filter(|child| **child != NO_CHILD).count();
+                nodes[index].is_leaf = true;
+                leaf_count = leaf_count + 1 - child_count;
+            }
+        }
+
+        let mut colors = Vec::with_capacity(self.max_colors);
+        collect_leaves(&nodes, 0, &mut colors);
+        pad_palette(colors, self.max_colors)
+    }
+}
+
+impl Node {
+    fn add_color(&mut self, color: [u8; 3], count: u64) {
+        self.count += count;
+        for (sum, value) in self.sum.iter_mut().zip(color.iter()) {
+            *sum += *value as u64 * count;
+        }
+    }
+
+    fn mean(&self) -> Option<[u8; 3]> {
+        let half = self.count / 2;
+        let r = (self.sum[0] + half).checked_div(self.count)?;
+        let g = (self.sum[1] + half).checked_div(self.count)?;
+        let b = (self.sum[2] + half).checked_div(self.count)?;
+        Some([r as u8, g as u8, b as u8])
+    }
+}
+
+fn child_index(color: [u8; 3], level: usize) -> usize {
+    let shift = 7 - level;
+    let r = ((color[0] >> shift) & 1) as usize;
+    let g = ((color[1] >> shift) & 1) as usize;
+    let b = ((color[2] >> shift) & 1) as usize;
+    (r << 2) | (g << 1) | b
+}
+
+fn collect_leaves(nodes: &[Node], index: usize, colors: &mut Vec<[u8; 3]>) {
+    let node = &nodes[index];
+    if node.is_leaf {
+        colors.extend(node.mean());
+        return;
+    }
+    for child in node.children.iter().filter(|child| **child != NO_CHILD) {
+        collect_leaves(nodes, *child as usize, colors);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Octree;
+    use crate::{
+        error::Error,
+        palette::{ColorHistogram, PaletteBuilder},
+    };
+
+    fn histogram(color_count: u32) -> ColorHistogram {
+        let mut histogram = ColorHistogram::new();
+        for i in 0..color_count {
+            let color = [
+                (i * 37 % 256) as u8,
+                (i * 101 % 256) as u8,
+                (i * 7 % 256) as u8,
+            ];
+            histogram.add_color(color, (i % 5 + 1) as u64);
+        }
+        histogram
+    }
+
+    #[test]
+    fn palettes_have_max_colors_entries() {
+        for max_colors in [1, 2, 16, 255, 256] {
+            let octree = Octree::new(max_colors).unwrap();
+            for color_count in [0, 3, 4096] {
+                let palette = octree.build(&histogram(color_count));
+                assert_eq!(
+                    palette.len(),
+                    max_colors * 3,
+                    "{} of {}",
+                    max_colors,
+                    color_count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn few_colors_are_kept_exactly() {
+        let palette = Octree::new(16).unwrap().build(&histogram(5));
+        for color in histogram(5).colors().map(|(color, _)| color) {
+            assert!(palette.chunks_exact(3).any(|entry| entry == color));
+        }
+    }
+
+    #[test]
+    fn rare_colors_are_merged_first() {
+        let mut histogram = ColorHistogram::new();
+        histogram.add_color([0, 0, 0], 1000);
+        histogram.add_color([255, 255, 255], 1000);
+        histogram.add_color([1, 0, 0], 1);
+        histogram.add_color([254, 255, 255], 1);
+        let palette = Octree::new(2).unwrap().build(&histogram);
+        assert_eq!(palette, [0, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn invalid_color_counts_are_rejected() {
+        assert!(matches!(Octree::new(0), Err(Error::InvalidOptions(_))));
+        assert!(matches!(Octree::new(257), Err(Error::InvalidOptions(_))));
+    }
+}
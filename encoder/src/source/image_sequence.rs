@@ -0,0 +1,351 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::{FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+};
+
+use super::{FrameSource, SourceFrame};
+
+const SUPPORTED_EXTENSIONS: [&str; 3] = ["png", "bmp", "tga"];
+
+/// How long each frame of an image sequence is displayed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FrameTiming {
+    /// Every frame is displayed for the same amount of time.
+    FrameRate(f64),
+    /// Each frame is displayed for its own amount of time. If there are more
+    /// frames than delays, the last delay is used for the remaining frames.
+    Delays(Vec<Duration>),
+}
+
+/// A frame source that reads an ordered sequence of images from disk.
+pub struct ImageSequenceSource {
+    paths: Vec<PathBuf>,
+    frame_timing: FrameTiming,
+    frame_size: FrameSize,
+    next_index: usize,
+    next_timestamp: Duration,
+}
+
+impl ImageSequenceSource {
+    /// Creates a source from either a directory or a glob pattern. Images are
+    /// ordered by file name, with runs of digits compared by their value.
+    pub fn new<P: AsRef<Path>>(input: P, frame_timing: FrameTiming) -> Result<Self> {
+        let input = input.as_ref();
+        let mut paths = if input.is_dir() {
+            let mut paths = Vec::new();
+            for entry in std::fs::read_dir(input)? {
+                let path = entry?.path();
+                if path.is_file() && is_supported_image(&path) {
+                    paths.push(path);
+                }
+            }
+            paths
+        } else {
+            let pattern = input.to_string_lossy();
+            let paths = glob::glob(&pattern)
+                .map_err(|error| Error::Source(format!("Invalid glob pattern: {}", error)))?;
+            let mut matches = Vec::new();
+            for path in paths {
+                let path = path.map_err(std::io::Error::from)?;
+                if path.is_file() {
+                    matches.push(path);
+                }
+            }
+            matches
+        };
+        sort_paths_naturally(&mut paths);
+        Self::from_paths(paths, frame_timing)
+    }
+
+    /// Creates a source from a list of image paths, which are used in the
+    /// order given.
+    pub fn from_paths(paths: Vec<PathBuf>, frame_timing: FrameTiming) -> Result<Self> {
+        if paths.is_empty() {
+            return Err(Error::Source("No images were found".to_owned()));
+        }
+        match &frame_timing {
+            FrameTiming::FrameRate(frame_rate) => {
+                if !(*frame_rate > 0.0 && frame_rate.is_finite()) {
+                    return Err(Error::Source(format!("Invalid frame rate: {}", frame_rate)));
+                }
+            }
+            FrameTiming::Delays(delays) => {
+                if delays.is_empty() {
+                    return Err(Error::Source("No frame delays were given".to_owned()));
+                }
+            }
+        }
+
+        // The first image determines the size of the gif.
+        let (width, height) = image::image_dimensions(&paths[0])?;
+
+        Ok(Self {
+            paths,
+            frame_timing,
+            frame_size: FrameSize::new(width, height),
+            next_index: 0,
+            next_timestamp: Duration::ZERO,
+        })
+    }
+
+    fn frame_duration(&self, index: usize) -> Duration {
+        match &self.frame_timing {
+            FrameTiming::FrameRate(frame_rate) => Duration::from_secs_f64(1.0 / frame_rate),
+            FrameTiming::Delays(delays) => *delays.get(index).unwrap_or(delays.last().unwrap()),
+        }
+    }
+}
+
+impl FrameSource for ImageSequenceSource {
+    fn frame_size(&self) -> FrameSize {
+        self.frame_size
+    }
+
+    fn next_frame(&mut self) -> Result<Option<SourceFrame>> {
+        let index = self.next_index;
+        let path = match self.paths.get(index) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let image = image::open(path)?.to_rgba8();
+        let size = FrameSize::new(image.width(), image.height());
+        let mut bytes = image.into_raw();
+        for pixel in bytes.chunks_exact_mut(BYTES_PER_PIXEL) {
+            pixel.swap(0, 2);
+        }
+        let buffer = FrameBuffer::from_bytes(size, size.width as usize * BYTES_PER_PIXEL, bytes)?;
+
+        // Frame rates are applied relative to the first frame so that
+        // rounding errors don't accumulate.
+        let timestamp = match &self.frame_timing {
+            FrameTiming::FrameRate(frame_rate) => {
+                Duration::from_secs_f64(index as f64 / frame_rate)
+            }
+            FrameTiming::Delays(_) => self.next_timestamp,
+        };
+        self.next_timestamp = timestamp + self.frame_duration(index);
+        self.next_index += 1;
+
+        Ok(Some(SourceFrame { buffer, timestamp }))
+    }
+
+    fn restart(&mut self) -> Result<bool> {
+        self.next_index = 0;
+        self.next_timestamp = Duration::ZERO;
+        Ok(true)
+    }
+
+    fn end_timestamp(&self) -> Option<Duration> {
+        Some(self.next_timestamp)
+    }
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| {
+            SUPPORTED_EXTENSIONS
+                .iter()
+                .any(|supported| extension.eq_ignore_ascii_case(supported))
+        })
+        .unwrap_or(false)
+}
+
+/// Sorts paths by file name, comparing runs of digits by their value so that
+/// "frame2.png" comes before "frame10.png". Files with the same name in
+/// different directories are ordered by their full path.
+fn sort_paths_naturally(paths: &mut [PathBuf]) {
+    let file_name = |path: &Path| {
+        path.file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+    paths.sort_by(|a, b| {
+        natural_cmp(&file_name(a), &file_name(b))
+            .then_with(|| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()))
+    });
+}
+
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                // Compare by value first, ignoring leading zeros
+                let x_trimmed = x.trim_start_matches('0');
+                let y_trimmed = y.trim_start_matches('0');
+                let ordering = x_trimmed
+                    .len()
+                    .cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.peek().copied() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        number.push(c);
+        chars.next();
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cmp::Ordering,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    use super::{natural_cmp, sort_paths_naturally, FrameTiming, ImageSequenceSource};
+    use crate::source::FrameSource;
+
+    /// A directory of 2x2 images that is removed when dropped.
+    struct ImageDirectory(PathBuf);
+
+    impl ImageDirectory {
+        fn new(name: &str, file_names: &[&str]) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("gifencoder-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&directory).unwrap();
+            for (i, file_name) in file_names.iter().enumerate() {
+                let image = image::RgbaImage::from_pixel(2, 2, image::Rgba([i as u8, 0, 0, 255]));
+                image.save(directory.join(file_name)).unwrap();
+            }
+            Self(directory)
+        }
+    }
+
+    impl Drop for ImageDirectory {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).unwrap();
+        }
+    }
+
+    /// The red channel of the first pixel of every frame, which is the index
+    /// of the file it was read from, along with the frame's timestamp.
+    fn read_frames(source: &mut ImageSequenceSource) -> Vec<(u8, Duration)> {
+        let mut frames = Vec::new();
+        while let Some(frame) = source.next_frame().unwrap() {
+            frames.push((frame.buffer.bytes[2], frame.timestamp));
+        }
+        frames
+    }
+
+    #[test]
+    fn digits_are_compared_by_value() {
+        assert_eq!(natural_cmp("frame2.png", "frame10.png"), Ordering::Less);
+        assert_eq!(natural_cmp("frame10.png", "frame9.png"), Ordering::Greater);
+        assert_eq!(natural_cmp("frame002.png", "frame10.png"), Ordering::Less);
+        // Leading zeros only break ties
+        assert_eq!(natural_cmp("frame2.png", "frame02.png"), Ordering::Less);
+        assert_eq!(natural_cmp("a1b2", "a1b10"), Ordering::Less);
+        assert_eq!(natural_cmp("frame", "frame1"), Ordering::Less);
+        assert_eq!(natural_cmp("frame1.png", "frame1.png"), Ordering::Equal);
+    }
+
+    #[test]
+    fn paths_are_sorted_by_file_name() {
+        let mut paths: Vec<PathBuf> = [
+            "b/frame10.png",
+            "a/frame2.png",
+            "c/frame1.png",
+            "a/frame10.png",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        sort_paths_naturally(&mut paths);
+        let expected: Vec<PathBuf> = [
+            "c/frame1.png",
+            "a/frame2.png",
+            "a/frame10.png",
+            "b/frame10.png",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(paths, expected);
+    }
+
+    #[test]
+    fn directories_are_read_in_natural_order() {
+        let directory = ImageDirectory::new(
+            "natural-order",
+            &["frame1.png", "frame2.bmp", "frame10.png", "frame9.tga"],
+        );
+        std::fs::write(directory.0.join("notes.txt"), "not an image").unwrap();
+
+        let mut source =
+            ImageSequenceSource::new(&directory.0, FrameTiming::FrameRate(4.0)).unwrap();
+        assert_eq!(
+            (source.frame_size().width, source.frame_size().height),
+            (2, 2)
+        );
+        let expected: Vec<(u8, Duration)> = [0, 1, 3, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, index)| (*index, Duration::from_millis(i as u64 * 250)))
+            .collect();
+        assert_eq!(read_frames(&mut source), expected);
+
+        // Sampling a palette restarts the sequence
+        assert!(source.restart().unwrap());
+        assert_eq!(read_frames(&mut source), expected);
+
+        let pattern = directory.0.join("frame1*.png");
+        let source = ImageSequenceSource::new(&pattern, FrameTiming::FrameRate(4.0)).unwrap();
+        assert_eq!(source.paths.len(), 2);
+    }
+
+    #[test]
+    fn frames_can_have_their_own_delays() {
+        let directory = ImageDirectory::new("delays", &["a.png", "b.png", "c.png", "d.png"]);
+        let delays = vec![Duration::from_millis(100), Duration::from_millis(30)];
+        let mut source =
+            ImageSequenceSource::new(&directory.0, FrameTiming::Delays(delays)).unwrap();
+        // The last delay is used for the frames without one
+        let timestamps: Vec<Duration> = read_frames(&mut source)
+            .into_iter()
+            .map(|(_, timestamp)| timestamp)
+            .collect();
+        assert_eq!(timestamps, [0, 100, 130, 160].map(Duration::from_millis));
+
+        assert!(ImageSequenceSource::new(&directory.0, FrameTiming::Delays(Vec::new())).is_err());
+        assert!(ImageSequenceSource::new(&directory.0, FrameTiming::FrameRate(0.0)).is_err());
+        assert!(
+            ImageSequenceSource::new(Path::new("missing-*.png"), FrameTiming::FrameRate(1.0))
+                .is_err()
+        );
+    }
+}
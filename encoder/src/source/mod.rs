@@ -0,0 +1,70 @@
+pub mod image_sequence;
+pub mod pacing;
+pub mod raw;
+pub mod y4m;
+
+use std::time::Duration;
+
+#[cfg(feature = "d3d")]
+use windows::Win32::Graphics::Direct3D11::{ID3D11Device, ID3D11Texture2D};
+
+#[cfg(feature = "d3d")]
+use crate::error::Error;
+use crate::{
+    error::Result,
+    frame::{FrameBuffer, FrameSize},
+};
+
+pub struct SourceFrame {
+    pub buffer: FrameBuffer,
+    /// The time the frame was produced, relative to an arbitrary point that
+    /// is the same for every frame from the source.
+    pub timestamp: Duration,
+}
+
+/// A frame that was left on the GPU.
+#[cfg(feature = "d3d")]
+pub struct SourceTexture {
+    /// A BGRA8 texture the size of the source's frames, which is only valid
+    /// until the next frame is requested.
+    pub texture: ID3D11Texture2D,
+    pub timestamp: Duration,
+}
+
+/// Produces the frames that make up a gif.
+pub trait FrameSource: Send {
+    /// The size of the gif. Frames of a different size are padded or clamped.
+    fn frame_size(&self) -> FrameSize;
+
+    /// Blocks until the next frame is available. Returns `None` once the
+    /// source has ended.
+    fn next_frame(&mut self) -> Result<Option<SourceFrame>>;
+
+    /// Starts the source over from its first frame. Returns false if the
+    /// source doesn't support being read more than once.
+    fn restart(&mut self) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// The time the last frame stops being shown, once the source has ended.
+    /// Sources without one show their last frame as long as the one before.
+    fn end_timestamp(&self) -> Option<Duration> {
+        None
+    }
+
+    /// The device `next_texture` creates its textures on, for sources that
+    /// produce their frames on the GPU.
+    #[cfg(feature = "d3d")]
+    fn d3d_device(&self) -> Option<ID3D11Device> {
+        None
+    }
+
+    /// Like `next_frame`, but leaves the frame on the GPU. Only used with
+    /// sources that have a `d3d_device`.
+    #[cfg(feature = "d3d")]
+    fn next_texture(&mut self) -> Result<Option<SourceTexture>> {
+        Err(Error::Source(
+            "This source can only produce frames in memory".to_owned(),
+        ))
+    }
+}
@@ -0,0 +1,178 @@
+#[cfg(any(feature = "d3d", test))]
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(any(feature = "d3d", test))]
+use crate::error::{Error, Result};
+
+/// How many frames per second are taken from a source that produces frames
+/// whenever something changes, like a capture session.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(rename_all = "kebab-case")
+)]
+pub enum FrameRate {
+    /// At most this many frames per second, which doesn't need to be a whole
+    /// number.
+    PerSecond(f64),
+    /// Every frame is kept.
+    Unlimited,
+}
+
+impl Default for FrameRate {
+    fn default() -> Self {
+        FrameRate::PerSecond(30.0)
+    }
+}
+
+#[cfg(any(feature = "d3d", test))]
+struct PendingFrame<T> {
+    tick: u64,
+    // How far the frame is from its tick, in ticks
+    distance: f64,
+    frame: T,
+}
+
+/// Keeps the frame closest to each tick of the frame rate, counting from the
+/// first frame. Timestamps are left alone, so the gif still shows frames for
+/// as long as they were on screen.
+#[cfg(any(feature = "d3d", test))]
+pub(crate) struct FramePacer<T> {
+    // In seconds, or `None` to keep every frame
+    interval: Option<f64>,
+    start: Option<Duration>,
+    last_tick: Option<u64>,
+    // A frame from before its tick, which a later frame could still be
+    // closer to
+    pending: Option<PendingFrame<T>>,
+}
+
+#[cfg(any(feature = "d3d", test))]
+impl<T> FramePacer<T> {
+    pub fn new(frame_rate: FrameRate) -> Result<Self> {
+        let interval = match frame_rate {
+            FrameRate::PerSecond(frame_rate) => {
+                if !(frame_rate > 0.0 && frame_rate.is_finite()) {
+                    return Err(Error::InvalidOptions(format!(
+                        "Invalid frame rate: {}",
+                        frame_rate
+                    )));
+                }
+                Some(1.0 / frame_rate)
+            }
+            FrameRate::Unlimited => None,
+        };
+        Ok(Self {
+            interval,
+            start: None,
+            last_tick: None,
+            pending: None,
+        })
+    }
+
+    /// Returns the frames that are ready to be encoded now that a frame with
+    /// the given timestamp arrived, oldest first.
+    pub fn push(&mut self, timestamp: Duration, frame: T) -> impl Iterator<Item = T> {
+        let (ready, current) = self.pace(timestamp, frame);
+        ready.into_iter().chain(current)
+    }
+
+    /// Returns the frame still waiting for its tick, once no more frames are
+    /// coming.
+    pub fn flush(&mut self) -> Option<T> {
+        self.pending.take().map(|pending| pending.frame)
+    }
+
+    fn pace(&mut self, timestamp: Duration, frame: T) -> (Option<T>, Option<T>) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return (None, Some(frame)),
+        };
+        let start = *self.start.get_or_insert(timestamp);
+        let position = timestamp.saturating_sub(start).as_secs_f64() / interval;
+        let tick = position.round() as u64;
+        if self.last_tick.is_some_and(|last_tick| tick <= last_tick) {
+            return (None, None);
+        }
+        let distance = (position - tick as f64).abs();
+
+        let mut ready = None;
+        if let Some(pending) = self.pending.take() {
+            if pending.tick == tick {
+                if pending.distance < distance {
+                    self.last_tick = Some(tick);
+                    return (Some(pending.frame), None);
+                }
+            } else {
+                // Nothing after this frame can be closer to the pending tick
+                self.last_tick = Some(pending.tick);
+                ready = Some(pending.frame);
+            }
+        }
+
+        if position >= tick as f64 {
+            // Later frames can only be farther from the tick
+            self.last_tick = Some(tick);
+            (ready, Some(frame))
+        } else {
+            self.pending = Some(PendingFrame {
+                tick,
+                distance,
+                frame,
+            });
+            (ready, None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FramePacer, FrameRate};
+
+    fn pace(frame_rate: FrameRate, timestamps_ms: &[u64]) -> Vec<u64> {
+        let mut pacer = FramePacer::new(frame_rate).unwrap();
+        let mut kept: Vec<u64> = timestamps_ms
+            .iter()
+            .flat_map(|ms| {
+                pacer
+                    .push(Duration::from_millis(*ms), *ms)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        kept.extend(pacer.flush());
+        kept
+    }
+
+    #[test]
+    fn keeps_the_frame_closest_to_each_tick() {
+        // 60 Hz frames paced to 10 fps, with ticks every 100 ms
+        let timestamps: Vec<u64> = (0..30).map(|i| i * 50 / 3).collect();
+        assert_eq!(
+            pace(FrameRate::PerSecond(10.0), &timestamps),
+            [0, 100, 200, 300, 400, 483]
+        );
+
+        // Frames that miss a tick are only kept if nothing is closer
+        assert_eq!(
+            pace(FrameRate::PerSecond(10.0), &[0, 40, 90, 130, 160, 260, 330]),
+            [0, 90, 160, 330]
+        );
+    }
+
+    #[test]
+    fn supports_fractional_and_unlimited_rates() {
+        let timestamps = [0, 300, 390, 500, 790, 1210];
+        assert_eq!(
+            pace(FrameRate::PerSecond(2.5), &timestamps),
+            [0, 390, 790, 1210]
+        );
+        assert_eq!(pace(FrameRate::Unlimited, &timestamps), timestamps);
+        assert!(FramePacer::<u64>::new(FrameRate::PerSecond(0.0)).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    io::{ErrorKind, Read},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::{FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+};
+
+use super::{FrameSource, SourceFrame};
+
+/// The layout of the pixels in a raw video stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgra8,
+    Rgba8,
+}
+
+/// A frame source that reads headerless frames of tightly packed pixels, such
+/// as the output of `ffmpeg -f rawvideo -pix_fmt bgra`.
+pub struct RawVideoSource<R: Read> {
+    reader: R,
+    frame_size: FrameSize,
+    pixel_format: PixelFormat,
+    frame_rate: f64,
+    next_index: u64,
+}
+
+impl<R: Read> RawVideoSource<R> {
+    pub fn new(
+        reader: R,
+        frame_size: FrameSize,
+        pixel_format: PixelFormat,
+        frame_rate: f64,
+    ) -> Result<Self> {
+        if frame_size.width == 0 || frame_size.height == 0 {
+            return Err(Error::Source(format!(
+                "Invalid frame size: {}x{}",
+                frame_size.width, frame_size.height
+            )));
+        }
+        if frame_size.is_too_large() {
+            return Err(Error::Source(format!(
+                "The frame size is too large: {}x{}",
+                frame_size.width, frame_size.height
+            )));
+        }
+        if !(frame_rate > 0.0 && frame_rate.is_finite()) {
+            return Err(Error::Source(format!("Invalid frame rate: {}", frame_rate)));
+        }
+        Ok(Self {
+            reader,
+            frame_size,
+            pixel_format,
+            frame_rate,
+            next_index: 0,
+        })
+    }
+
+    fn timestamp(&self, index: u64) -> Duration {
+        Duration::from_secs_f64(index as f64 / self.frame_rate)
+    }
+}
+
+impl<R: Read + Send> FrameSource for RawVideoSource<R> {
+    fn frame_size(&self) -> FrameSize {
+        self.frame_size
+    }
+
+    fn next_frame(&mut self) -> Result<Option<SourceFrame>> {
+        let mut buffer = FrameBuffer::new(self.frame_size);
+        if !read_frame(&mut self.reader, &mut buffer.bytes)? {
+            return Ok(None);
+        }
+        if self.pixel_format == PixelFormat::Rgba8 {
+            for pixel in buffer.bytes.chunks_exact_mut(BYTES_PER_PIXEL) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        let timestamp = self.timestamp(self.next_index);
+        self.next_index += 1;
+
+        Ok(Some(SourceFrame { buffer, timestamp }))
+    }
+
+    fn end_timestamp(&self) -> Option<Duration> {
+        Some(self.timestamp(self.next_index))
+    }
+}
+
+/// Fills the buffer with the next frame. Returns false if the stream ended
+/// before the frame started, and fails if it ended partway through.
+fn read_frame<R: Read>(reader: &mut R, bytes: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < bytes.len() {
+        match reader.read(&mut bytes[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => {
+                return Err(Error::Source(format!(
+                    "The stream ended partway through a frame ({} of {} bytes)",
+                    read,
+                    bytes.len()
+                )))
+            }
+            Ok(length) => read += length,
+            Err(error) if error.kind() == ErrorKind::Interrupted => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{PixelFormat, RawVideoSource};
+    use crate::{error::Error, frame::FrameSize, source::FrameSource};
+
+    fn source(
+        bytes: &[u8],
+        width: u32,
+        height: u32,
+        pixel_format: PixelFormat,
+    ) -> RawVideoSource<&[u8]> {
+        RawVideoSource::new(bytes, FrameSize::new(width, height), pixel_format, 25.0).unwrap()
+    }
+
+    #[test]
+    fn pixels_are_read_as_bgra() {
+        let bytes = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut bgra = source(&bytes, 2, 1, PixelFormat::Bgra8);
+        assert_eq!(bgra.next_frame().unwrap().unwrap().buffer.bytes, bytes);
+
+        let mut rgba = source(&bytes, 2, 1, PixelFormat::Rgba8);
+        assert_eq!(
+            rgba.next_frame().unwrap().unwrap().buffer.bytes,
+            [3, 2, 1, 4, 7, 6, 5, 8]
+        );
+    }
+
+    #[test]
+    fn timestamps_follow_the_frame_rate() {
+        let bytes = [0; 12];
+        let mut source = source(&bytes, 1, 1, PixelFormat::Bgra8);
+        let mut timestamps = Vec::new();
+        while let Some(frame) = source.next_frame().unwrap() {
+            timestamps.push(frame.timestamp);
+        }
+        assert_eq!(timestamps, [0, 40, 80].map(Duration::from_millis));
+        assert_eq!(source.end_timestamp(), Some(Duration::from_millis(120)));
+    }
+
+    #[test]
+    fn short_final_frames_are_errors() {
+        let bytes = [0; 10];
+        let mut source = source(&bytes, 2, 1, PixelFormat::Bgra8);
+        assert!(source.next_frame().unwrap().is_some());
+        assert!(matches!(source.next_frame(), Err(Error::Source(_))));
+    }
+
+    #[test]
+    fn invalid_sizes_are_rejected() {
+        let new = |width, height| {
+            RawVideoSource::new(
+                &[][..],
+                FrameSize::new(width, height),
+                PixelFormat::Bgra8,
+                25.0,
+            )
+        };
+        assert!(matches!(new(0, 1), Err(Error::Source(_))));
+        assert!(matches!(new(65536, 1), Err(Error::Source(_))));
+        assert!(matches!(new(1, 65536), Err(Error::Source(_))));
+        assert!(matches!(new(16385, 16384), Err(Error::Source(_))));
+        assert!(new(65535, 2).is_ok());
+    }
+}
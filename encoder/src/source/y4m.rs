@@ -0,0 +1,479 @@
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read},
+    time::Duration,
+};
+
+use crate::{
+    error::{Error, Result},
+    frame::{FrameBuffer, FrameSize, BYTES_PER_PIXEL},
+};
+
+use super::{FrameSource, SourceFrame};
+
+// Headers are short, anything longer than this isn't a YUV4MPEG2 stream.
+const MAX_HEADER_LENGTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Colorspace {
+    Mono,
+    C420,
+    C422,
+    C444,
+    C444Alpha,
+}
+
+/// A frame source that reads a YUV4MPEG2 (.y4m) stream, such as the output
+/// of `ffmpeg -f yuv4mpegpipe`. Only 8-bit colorspaces are supported.
+pub struct Y4mSource<R: Read> {
+    reader: BufReader<R>,
+    frame_size: FrameSize,
+    frame_rate: (u64, u64),
+    colorspace: Colorspace,
+    full_range: bool,
+    frame_bytes: Vec<u8>,
+    next_index: u64,
+}
+
+impl<R: Read> Y4mSource<R> {
+    /// Reads the stream header. Frames are read as they are requested.
+    pub fn new(reader: R) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let header = read_line(&mut reader)?
+            .ok_or_else(|| Error::Source("The YUV4MPEG2 stream is empty".to_owned()))?;
+        let mut params = header.split(' ');
+        if params.next() != Some("YUV4MPEG2") {
+            return Err(Error::Source("Missing YUV4MPEG2 signature".to_owned()));
+        }
+
+        let mut width = None;
+        let mut height = None;
+        let mut frame_rate = None;
+        let mut colorspace = Colorspace::C420;
+        let mut full_range = false;
+        for param in params.filter(|param| !param.is_empty()) {
+            let mut chars = param.chars();
+            let tag = chars.next().unwrap();
+            let value = chars.as_str();
+            match tag {
+                'W' => width = Some(parse_value(param, value)?),
+                'H' => height = Some(parse_value(param, value)?),
+                'F' => {
+                    let (numerator, denominator) =
+                        value.split_once(':').ok_or_else(|| invalid_param(param))?;
+                    let numerator: u64 = parse_value(param, numerator)?;
+                    let denominator: u64 = parse_value(param, denominator)?;
+                    if numerator == 0 || denominator == 0 {
+                        return Err(invalid_param(param));
+                    }
+                    frame_rate = Some((numerator, denominator));
+                }
+                'C' => {
+                    colorspace = match value {
+                        "mono" => Colorspace::Mono,
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Colorspace::C420,
+                        "422" => Colorspace::C422,
+                        "444" => Colorspace::C444,
+                        "444alpha" => Colorspace::C444Alpha,
+                        _ => {
+                            return Err(Error::Source(format!(
+                                "Unsupported YUV4MPEG2 colorspace: {}",
+                                value
+                            )))
+                        }
+                    }
+                }
+                'X' if value == "COLORRANGE=FULL" => full_range = true,
+                // Interlacing, aspect ratio and other parameters don't
+                // change how the frames are decoded.
+                _ => {}
+            }
+        }
+
+        let (width, height) = match (width, height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => (width, height),
+            _ => {
+                return Err(Error::Source(
+                    "The YUV4MPEG2 header is missing the frame size".to_owned(),
+                ))
+            }
+        };
+        let frame_size = FrameSize::new(width, height);
+        if frame_size.is_too_large() {
+            return Err(Error::Source(format!(
+                "The YUV4MPEG2 frame size is too large: {}x{}",
+                width, height
+            )));
+        }
+        let frame_rate = frame_rate.ok_or_else(|| {
+            Error::Source("The YUV4MPEG2 header is missing the frame rate".to_owned())
+        })?;
+
+        let frame_bytes = vec![0u8; frame_length(frame_size, colorspace)];
+        Ok(Self {
+            reader,
+            frame_size,
+            frame_rate,
+            colorspace,
+            full_range,
+            frame_bytes,
+            next_index: 0,
+        })
+    }
+
+    /// The frame rate from the header, as a fraction.
+    pub fn frame_rate(&self) -> (u64, u64) {
+        self.frame_rate
+    }
+
+    fn timestamp(&self, index: u64) -> Duration {
+        let (numerator, denominator) = self.frame_rate;
+        let nanos = index as u128 * denominator as u128 * 1_000_000_000 / numerator as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+
+    fn convert_frame(&self) -> FrameBuffer {
+        let width = self.frame_size.width as usize;
+        let height = self.frame_size.height as usize;
+        let (chroma_width, chroma_height) = chroma_size(self.frame_size, self.colorspace);
+        let luma_length = width * height;
+        let chroma_length = chroma_width * chroma_height;
+
+        let (y_plane, rest) = self.frame_bytes.split_at(luma_length);
+        let (u_plane, rest) = rest.split_at(chroma_length);
+        let (v_plane, a_plane) = rest.split_at(chroma_length);
+
+        let mut frame = FrameBuffer::new(self.frame_size);
+        for y in 0..height {
+            let row = frame.row_mut(y as u32);
+            let chroma_y = y * chroma_height / height;
+            for (x, pixel) in row.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+                let luma = y_plane[y * width + x];
+                let (u, v) = if chroma_length > 0 {
+                    let chroma_index = chroma_y * chroma_width + x * chroma_width / width;
+                    (u_plane[chroma_index], v_plane[chroma_index])
+                } else {
+                    (128, 128)
+                };
+                let [r, g, b] = yuv_to_rgb(luma, u, v, self.full_range);
+                let alpha = if a_plane.is_empty() {
+                    255
+                } else {
+                    a_plane[y * width + x]
+                };
+                pixel.copy_from_slice(&[b, g, r, alpha]);
+            }
+        }
+        frame
+    }
+}
+
+impl<R: Read + Send> FrameSource for Y4mSource<R> {
+    fn frame_size(&self) -> FrameSize {
+        self.frame_size
+    }
+
+    fn next_frame(&mut self) -> Result<Option<SourceFrame>> {
+        let frame_header = match read_line(&mut self.reader)? {
+            Some(frame_header) => frame_header,
+            None => return Ok(None),
+        };
+        if !frame_header.starts_with("FRAME") {
+            return Err(Error::Source("Missing YUV4MPEG2 frame header".to_owned()));
+        }
+        self.reader.read_exact(&mut self.frame_bytes)?;
+        let buffer = self.convert_frame();
+
+        let timestamp = self.timestamp(self.next_index);
+        self.next_index += 1;
+
+        Ok(Some(SourceFrame { buffer, timestamp }))
+    }
+
+    fn end_timestamp(&self) -> Option<Duration> {
+        Some(self.timestamp(self.next_index))
+    }
+}
+
+/// Reads a line without its terminating newline, or `None` at the end of
+/// the stream.
+fn read_line<R: Read>(reader: &mut BufReader<R>) -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let length = reader
+        .by_ref()
+        .take(MAX_HEADER_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    if length == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(Error::Io(ErrorKind::UnexpectedEof.into()));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| Error::Source("Invalid YUV4MPEG2 header".to_owned()))
+}
+
+fn parse_value<T: std::str::FromStr>(param: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| invalid_param(param))
+}
+
+fn invalid_param(param: &str) -> Error {
+    Error::Source(format!("Invalid YUV4MPEG2 parameter: {}", param))
+}
+
+fn chroma_size(frame_size: FrameSize, colorspace: Colorspace) -> (usize, usize) {
+    let width = frame_size.width as usize;
+    let height = frame_size.height as usize;
+    match colorspace {
+        Colorspace::Mono => (0, 0),
+        Colorspace::C420 => (width.div_ceil(2), height.div_ceil(2)),
+        Colorspace::C422 => (width.div_ceil(2), height),
+        Colorspace::C444 | Colorspace::C444Alpha => (width, height),
+    }
+}
+
+fn frame_length(frame_size: FrameSize, colorspace: Colorspace) -> usize {
+    let luma_length = frame_size.width as usize * frame_size.height as usize;
+    let (chroma_width, chroma_height) = chroma_size(frame_size, colorspace);
+    let alpha_length = if colorspace == Colorspace::C444Alpha {
+        luma_length
+    } else {
+        0
+    };
+    luma_length + 2 * chroma_width * chroma_height + alpha_length
+}
+
+/// Converts BT.601 YCbCr to RGB. Unless the stream says otherwise, samples
+/// use the limited (16-235) range, which is what ffmpeg writes by default.
+fn yuv_to_rgb(y: u8, u: u8, v: u8, full_range: bool) -> [u8; 3] {
+    let (y, scale) = if full_range {
+        (y as f32, 1.0)
+    } else {
+        (y as f32 - 16.0, 255.0 / 219.0)
+    };
+    let chroma_scale = if full_range { 1.0 } else { 255.0 / 224.0 };
+    let u = (u as f32 - 128.0) * chroma_scale;
+    let v = (v as f32 - 128.0) * chroma_scale;
+    let y = y * scale;
+
+    let r = y + 1.402 * v;
+    let g = y - 0.344136 * u - 0.714136 * v;
+    let b = y + 1.772 * u;
+    [to_u8(r), to_u8(g), to_u8(b)]
+}
+
+fn to_u8(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{yuv_to_rgb, Y4mSource};
+    use crate::{
+        error::Error,
+        frame::{FrameBuffer, BYTES_PER_PIXEL},
+        source::FrameSource,
+    };
+
+    /// A stream with the given header parameters and frames, each made of
+    /// the concatenated planes.
+    fn stream(params: &str, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = format!("YUV4MPEG2 {}\n", params).into_bytes();
+        for frame in frames {
+            bytes.extend_from_slice(b"FRAME\n");
+            bytes.extend_from_slice(frame);
+        }
+        bytes
+    }
+
+    fn header_error(params: &str) -> bool {
+        matches!(
+            Y4mSource::new(stream(params, &[]).as_slice()),
+            Err(Error::Source(_))
+        )
+    }
+
+    fn decode(params: &str, planes: &[&[u8]]) -> FrameBuffer {
+        let bytes = stream(params, &[planes.concat()]);
+        let mut source = Y4mSource::new(bytes.as_slice()).unwrap();
+        let frame = source.next_frame().unwrap().unwrap();
+        assert!(source.next_frame().unwrap().is_none());
+        frame.buffer
+    }
+
+    fn pixel(frame: &FrameBuffer, x: u32, y: u32) -> [u8; 4] {
+        let begin = x as usize * BYTES_PER_PIXEL;
+        let bgra = &frame.row(y)[begin..begin + BYTES_PER_PIXEL];
+        [bgra[2], bgra[1], bgra[0], bgra[3]]
+    }
+
+    /// Checks that every pixel was converted from its own luma and the
+    /// chroma sample at `chroma_index(x, y)`.
+    fn assert_chroma(
+        frame: &FrameBuffer,
+        planes: &[&[u8]],
+        chroma_index: impl Fn(usize, usize) -> usize,
+    ) {
+        let width = frame.size.width as usize;
+        for y in 0..frame.size.height as usize {
+            for x in 0..width {
+                let index = chroma_index(x, y);
+                let [r, g, b] = yuv_to_rgb(
+                    planes[0][y * width + x],
+                    planes[1][index],
+                    planes[2][index],
+                    true,
+                );
+                assert_eq!(
+                    pixel(frame, x as u32, y as u32),
+                    [r, g, b, 255],
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn headers_are_parsed() {
+        let bytes = stream("W5 H3 F30000:1001 Ip A1:1 XYSCSS=420", &[]);
+        let source = Y4mSource::new(bytes.as_slice()).unwrap();
+        assert_eq!(
+            (source.frame_size().width, source.frame_size().height),
+            (5, 3)
+        );
+        assert_eq!(source.frame_rate(), (30000, 1001));
+
+        assert!(matches!(Y4mSource::new(&b""[..]), Err(Error::Source(_))));
+        assert!(matches!(
+            Y4mSource::new(&b"YUV4MPEG2 W2 H2 F1:1"[..]),
+            Err(Error::Io(_))
+        ));
+        assert!(matches!(
+            Y4mSource::new(&b"YUV4MPEG W2 H2 F1:1\n"[..]),
+            Err(Error::Source(_))
+        ));
+        assert!(header_error("H2 F1:1"));
+        assert!(header_error("W2 F1:1"));
+        assert!(header_error("W0 H2 F1:1"));
+        assert!(header_error("W2 H2"));
+        assert!(header_error("W2 H2 F1:0"));
+        assert!(header_error("W2 H2 F25"));
+        assert!(header_error("Wtwo H2 F1:1"));
+        assert!(header_error("W2 H2 F1:1 C420p10"));
+    }
+
+    #[test]
+    fn oversized_frames_are_rejected() {
+        assert!(header_error("W65536 H1 F1:1"));
+        assert!(header_error("W1 H65536 F1:1"));
+        assert!(header_error("W65535 H65535 F1:1"));
+        assert!(header_error("W16385 H16384 F1:1"));
+        assert!(header_error("W4294967296 H1 F1:1"));
+        assert!(Y4mSource::new(stream("W65535 H2 F1:1 Cmono", &[]).as_slice()).is_ok());
+    }
+
+    #[test]
+    fn chroma_420_covers_two_by_two_pixels() {
+        // Odd sizes round the chroma planes up
+        let luma: Vec<u8> = (0..15).map(|i| 40 + i * 10).collect();
+        let planes: [&[u8]; 3] = [
+            &luma,
+            &[60, 90, 120, 150, 180, 210],
+            &[200, 170, 140, 110, 80, 50],
+        ];
+        let frame = decode("W5 H3 F25:1 C420jpeg XCOLORRANGE=FULL", &planes);
+        assert_chroma(&frame, &planes, |x, y| y / 2 * 3 + x / 2);
+    }
+
+    #[test]
+    fn chroma_422_covers_two_pixels_of_a_row() {
+        let luma: Vec<u8> = (0..8).map(|i| 50 + i * 20).collect();
+        let planes: [&[u8]; 3] = [&luma, &[60, 90, 120, 150], &[200, 170, 140, 110]];
+        let frame = decode("W4 H2 F25:1 C422 XCOLORRANGE=FULL", &planes);
+        assert_chroma(&frame, &planes, |x, y| y * 2 + x / 2);
+    }
+
+    #[test]
+    fn chroma_444_has_a_sample_per_pixel() {
+        let planes: [&[u8]; 3] = [
+            &[10, 80, 150, 220],
+            &[60, 90, 120, 150],
+            &[200, 170, 140, 110],
+        ];
+        let frame = decode("W2 H2 F25:1 C444 XCOLORRANGE=FULL", &planes);
+        assert_chroma(&frame, &planes, |x, y| y * 2 + x);
+
+        let alpha = [0, 85, 170, 255];
+        let frame = decode(
+            "W2 H2 F25:1 C444alpha XCOLORRANGE=FULL",
+            &[planes[0], planes[1], planes[2], &alpha],
+        );
+        for (i, alpha) in alpha.iter().enumerate() {
+            let x = i as u32 % 2;
+            let y = i as u32 / 2;
+            let [r, g, b] = yuv_to_rgb(planes[0][i], planes[1][i], planes[2][i], true);
+            assert_eq!(pixel(&frame, x, y), [r, g, b, *alpha]);
+        }
+    }
+
+    #[test]
+    fn mono_frames_are_gray() {
+        let frame = decode("W3 H1 F25:1 Cmono XCOLORRANGE=FULL", &[&[0, 100, 255]]);
+        assert_eq!(pixel(&frame, 0, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 1, 0), [100, 100, 100, 255]);
+        assert_eq!(pixel(&frame, 2, 0), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn limited_range_is_expanded() {
+        // Limited range is the default, where 16 is black and 235 white
+        let planes: [&[u8]; 3] = [&[16, 235, 126], &[128, 128, 128], &[128, 128, 128]];
+        let frame = decode("W3 H1 F25:1 C444", &planes);
+        assert_eq!(pixel(&frame, 0, 0), [0, 0, 0, 255]);
+        assert_eq!(pixel(&frame, 1, 0), [255, 255, 255, 255]);
+        assert_eq!(pixel(&frame, 2, 0), [128, 128, 128, 255]);
+
+        let frame = decode("W3 H1 F25:1 C444 XCOLORRANGE=FULL", &planes);
+        assert_eq!(pixel(&frame, 0, 0), [16, 16, 16, 255]);
+        assert_eq!(pixel(&frame, 1, 0), [235, 235, 235, 255]);
+
+        // Chroma is scaled as well, so the most saturated limited range blue
+        // is as blue as it gets
+        assert_eq!(yuv_to_rgb(41, 240, 110, false), [0, 0, 255]);
+    }
+
+    #[test]
+    fn timestamps_follow_the_frame_rate() {
+        let frames = vec![vec![0; 1]; 4];
+        let bytes = stream("W1 H1 F30000:1001 Cmono", &frames);
+        let mut source = Y4mSource::new(bytes.as_slice()).unwrap();
+        let mut timestamps = Vec::new();
+        while let Some(frame) = source.next_frame().unwrap() {
+            timestamps.push(frame.timestamp);
+        }
+        assert_eq!(
+            timestamps,
+            [0, 33_366_666, 66_733_333, 100_100_000].map(Duration::from_nanos)
+        );
+        assert_eq!(
+            source.end_timestamp(),
+            Some(Duration::from_nanos(133_466_666))
+        );
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let mut bytes = stream("W2 H2 F25:1 Cmono", &[vec![0; 4]]);
+        bytes.extend_from_slice(b"FRAME\n\0\0");
+        let mut source = Y4mSource::new(bytes.as_slice()).unwrap();
+        assert!(source.next_frame().unwrap().is_some());
+        assert!(matches!(source.next_frame(), Err(Error::Io(_))));
+
+        let mut source = Y4mSource::new(&b"YUV4MPEG2 W1 H1 F25:1 Cmono\nFROM\n\0"[..]).unwrap();
+        assert!(matches!(source.next_frame(), Err(Error::Source(_))));
+    }
+}
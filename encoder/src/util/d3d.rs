@@ -104,3 +104,33 @@ pub fn read_from_buffer<T: FromBytes>(
 
     Ok(result)
 }
+
+pub fn read_slice_from_buffer<T: FromBytes>(
+    d3d_context: &ID3D11DeviceContext,
+    staging_buffer: &ID3D11Buffer,
+    count: usize,
+) -> Result<Vec<T>> {
+    let mut desc = D3D11_BUFFER_DESC::default();
+    unsafe {
+        staging_buffer.GetDesc(&mut desc as *mut _);
+    }
+
+    let size = std::mem::size_of::<T>();
+    assert!(size * count <= desc.ByteWidth as usize);
+
+    let resource: ID3D11Resource = staging_buffer.cast()?;
+    let mapped = unsafe { d3d_context.Map(Some(resource.clone()), 0, D3D11_MAP_READ, 0)? };
+
+    // Get a slice of bytes
+    let slice: &[u8] =
+        unsafe { std::slice::from_raw_parts(mapped.pData as *const _, size * count) };
+
+    let result = slice
+        .chunks_exact(size)
+        .map(|chunk| T::read_from(chunk).unwrap())
+        .collect();
+
+    unsafe { d3d_context.Unmap(Some(resource), 0) };
+
+    Ok(result)
+}
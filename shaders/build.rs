@@ -1,6 +1,7 @@
 use std::process::Command;
 
 fn main() {
+    println!("cargo:rerun-if-changed=src/Lab.hlsli");
     let shader_folder = format!("{}/{}", std::env::var("OUT_DIR").unwrap(), "shaders");
     ensure_generated_dirs(&shader_folder).unwrap();
 
@@ -2,6 +2,14 @@ Texture2D frameTexture : register(t0);
 SamplerState frameTextureSampler : register(s0);
 
 Texture3D<uint> lutTexture : register(t1);
+// Ordered dithering thresholds between 0 and 1, tiled over the frame
+Texture2D<float> ditherTexture : register(t2);
+
+cbuffer DitherParams : register(b0)
+{
+    // How far colors are moved by the thresholds, 0 when not dithering
+    float ditherSpread;
+};
 
 struct PS_INPUT
 {
@@ -12,7 +20,16 @@ struct PS_INPUT
 uint main(PS_INPUT input) : SV_TARGET
 {
     float4 unormColor = frameTexture.Sample(frameTextureSampler, input.texCoord);
-    uint3 color = { (uint)(unormColor.x * 255.0f), (uint)(unormColor.y * 255.0f), (uint)(unormColor.z * 255.0f) };
+
+    // The threshold only depends on the position, so unchanged pixels are
+    // always dithered the same way
+    uint ditherWidth;
+    uint ditherHeight;
+    ditherTexture.GetDimensions(ditherWidth, ditherHeight);
+    uint2 pixel = (uint2)input.position.xy;
+    float offset = (ditherTexture[pixel % uint2(ditherWidth, ditherHeight)] - 0.5f) * ditherSpread;
+
+    uint3 color = (uint3)clamp(round(unormColor.xyz * 255.0f + offset), 0.0f, 255.0f);
     uint paletteIndex = lutTexture[color.xyz];
     return paletteIndex;
 }
@@ -1,3 +1,5 @@
+#include "Lab.hlsli"
+
 struct DiffRect
 {
     uint left;
@@ -6,24 +8,103 @@ struct DiffRect
     uint bottom;
 };
 
+// Must match TILE_SIZE in the encoder's regions.rs
+#define TILE_SIZE 32
+
+// Must match DiffThreshold in the encoder
+#define THRESHOLD_EXACT 0
+#define THRESHOLD_CHANNEL 1
+#define THRESHOLD_DELTA_E 2
+
+cbuffer DiffParams : register(b0)
+{
+    uint thresholdMode;
+    uint channelThreshold;
+    float deltaEThreshold;
+    // Set for the first frame, which is only copied into the reference
+    uint copyAll;
+};
+
 RWStructuredBuffer<DiffRect> diffBuffer : register(u0);
+// The bounds of the changes within each tile, in row order
+RWStructuredBuffer<DiffRect> tileBuffer : register(u1);
+// The last color at each pixel that counted as a change, packed as RGBA. Small
+// changes aren't written back, so they can't add up unnoticed.
+RWTexture2D<uint> referenceTexture : register(u2);
 Texture2D<unorm float4> currentTexture : register(t0);
-Texture2D<unorm float4> previousTexture : register(t1);
+Texture3D<uint> lutTexture : register(t1);
+Texture1D<uint4> paletteTexture : register(t2);
+
+uint packColor(uint4 color)
+{
+    return color.x | (color.y << 8) | (color.z << 16) | (color.w << 24);
+}
+
+uint4 unpackColor(uint color)
+{
+    return uint4(color & 0xFF, (color >> 8) & 0xFF, (color >> 16) & 0xFF, color >> 24);
+}
+
+bool isChanged(uint4 currentColor, uint4 previousColor)
+{
+    if (thresholdMode == THRESHOLD_CHANNEL)
+    {
+        uint4 delta = max(currentColor, previousColor) - min(currentColor, previousColor);
+        return max(max(delta.x, delta.y), max(delta.z, delta.w)) > channelThreshold;
+    }
+    else if (thresholdMode == THRESHOLD_DELTA_E)
+    {
+        // Compare the palette colors the pixels are quantized to
+        uint currentIndex = lutTexture[currentColor.xyz];
+        uint previousIndex = lutTexture[previousColor.xyz];
+        if (currentIndex == previousIndex)
+        {
+            return false;
+        }
+        float3 currentLab = rgb2lab(paletteTexture[currentIndex].xyz);
+        float3 previousLab = rgb2lab(paletteTexture[previousIndex].xyz);
+        return computeDistance(currentLab, previousLab) > deltaEThreshold;
+    }
+    return any(currentColor != previousColor);
+}
 
 [numthreads(2, 2, 1)]
 void main(uint3 DTid : SV_DispatchThreadID)
 {
     uint2 position = DTid.xy;
 
-    float4 currentColor = currentTexture[position];
-    float4 previousColor = previousTexture[position];
+    uint width;
+    uint height;
+    currentTexture.GetDimensions(width, height);
+    if (position.x >= width || position.y >= height)
+    {
+        return;
+    }
+
+    // Reading the BGRA texture gives us the color in RGBA order
+    uint4 currentColor = (uint4)(currentTexture[position] * 255.0f + 0.5f);
+    if (copyAll != 0)
+    {
+        referenceTexture[position] = packColor(currentColor);
+        return;
+    }
+    uint4 previousColor = unpackColor(referenceTexture[position]);
 
-    if ((currentColor.x != previousColor.x) || (currentColor.y != previousColor.y) || (currentColor.z != previousColor.z) || (currentColor.w != previousColor.w))
+    if (isChanged(currentColor, previousColor))
     {
+        referenceTexture[position] = packColor(currentColor);
+
         uint value = 0;
         InterlockedMin(diffBuffer[0].left, position.x, value);
         InterlockedMin(diffBuffer[0].top, position.y, value);
         InterlockedMax(diffBuffer[0].right, position.x, value);
         InterlockedMax(diffBuffer[0].bottom, position.y, value);
+
+        uint tilesPerRow = (width + TILE_SIZE - 1) / TILE_SIZE;
+        uint tile = (position.y / TILE_SIZE) * tilesPerRow + (position.x / TILE_SIZE);
+        InterlockedMin(tileBuffer[tile].left, position.x, value);
+        InterlockedMin(tileBuffer[tile].top, position.y, value);
+        InterlockedMax(tileBuffer[tile].right, position.x, value);
+        InterlockedMax(tileBuffer[tile].bottom, position.y, value);
     }
 }
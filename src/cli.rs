@@ -1,16 +1,63 @@
-use clap::{App, Arg};
-use robmikh_common::desktop::displays::get_display_handle_from_index;
-use windows::{
-    core::Result,
-    Win32::{Foundation::HWND, Graphics::Gdi::HMONITOR},
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use gifencoder::{
+    load_palette_file, BackendType, DiffThreshold, Dither, EncoderOptions, EncoderOptionsBuilder,
+    Error, FrameRate, FrameSize, FrameTiming, LoopCount, OutputFormat, Palette, PaletteAlgorithm,
+    PaletteSampling, PixelFormat, Result, WebPQuality,
 };
+use robmikh_common::desktop::displays::get_display_handle_from_index;
+use windows::Win32::{Foundation::HWND, Graphics::Gdi::HMONITOR};
 
 use crate::util::window_info::get_window_from_query;
 
+const DEFAULT_FRAME_RATE: f64 = 30.0;
+const DEFAULT_PALETTE_FRAMES: &str = "10";
+const DEFAULT_PALETTE_COLORS: &str = "256";
+const FRAME_DUMP_MANIFEST: &str = "manifest.json";
+const PALETTE_ARGS: [&str; 5] = [
+    "palette-file",
+    "palette",
+    "palette-frames",
+    "palette-algorithm",
+    "colors",
+];
+
 pub struct CliOptions {
-    pub capture_type: CaptureType,
+    pub mode: Mode,
     pub output_file: String,
-    pub disable_frame_diff: bool,
+    pub encoder_options: EncoderOptions,
+}
+
+pub enum Mode {
+    Capture(CaptureOptions),
+    Encode(EncodeOptions),
+    ExportPalette(ExportPaletteOptions),
+}
+
+pub struct CaptureOptions {
+    pub capture_type: CaptureType,
+}
+
+pub struct EncodeOptions {
+    /// A path, glob pattern or "-" for stdin.
+    pub input: String,
+    pub input_format: InputFormat,
+}
+
+pub struct ExportPaletteOptions {
+    /// The images or YUV4MPEG2 stream an adaptive palette is generated from.
+    pub input: Option<String>,
+}
+
+pub enum InputFormat {
+    Images(FrameTiming),
+    Y4m,
+    Raw {
+        frame_size: FrameSize,
+        pixel_format: PixelFormat,
+        frame_rate: f64,
+    },
 }
 
 pub enum CaptureType {
@@ -29,36 +76,374 @@ pub fn parse_cli() -> Result<CliOptions> {
     }
 
     let matches = app.get_matches();
+    parse_matches(&matches)
+}
+
+fn parse_matches(matches: &ArgMatches) -> Result<CliOptions> {
+    // Encoding and exporting palettes take their own sets of arguments
+    if let Some(matches) = matches.subcommand_matches("encode") {
+        let mode = Mode::Encode(parse_encode_options(matches)?);
+        // Only captures are paced, other sources keep their own timing
+        return parse_encoding_cli(mode, matches, None);
+    }
+    if let Some(matches) = matches
+        .subcommand_matches("palette")
+        .and_then(|matches| matches.subcommand_matches("export"))
+    {
+        // Exporting a palette doesn't encode anything, so only the palette
+        // options apply
+        let input = matches.value_of("from").map(|value| value.to_owned());
+        return Ok(CliOptions {
+            mode: Mode::ExportPalette(ExportPaletteOptions { input }),
+            output_file: matches.value_of("OUTPUT FILE").unwrap().to_owned(),
+            encoder_options: EncoderOptions::builder()
+                .palette(parse_palette(matches)?)
+                .build(),
+        });
+    }
+
+    let capture_options = CaptureOptions {
+        capture_type: parse_capture_type(matches)?,
+    };
+    let frame_rate = matches
+        .value_of("fps")
+        .map(parse_capture_frame_rate)
+        .transpose()?;
+    parse_encoding_cli(Mode::Capture(capture_options), matches, frame_rate)
+}
+
+/// Parses the options shared by capturing and encoding. Options given on
+/// the command line override the ones from a config file.
+fn parse_encoding_cli(
+    mode: Mode,
+    matches: &ArgMatches,
+    frame_rate: Option<FrameRate>,
+) -> Result<CliOptions> {
+    let config = matches.value_of("config").map(load_config).transpose()?;
+    let config_has_format = config.as_ref().is_some_and(|config| config.has_format);
+    let mut builder = match config {
+        Some(config) => EncoderOptionsBuilder::from(config.options),
+        None => EncoderOptions::builder(),
+    };
+
+    if let Some(frame_rate) = frame_rate {
+        builder = builder.frame_rate(frame_rate);
+    }
+
+    if cfg!(feature = "debug") && matches.is_present("nodiff") {
+        builder = builder.disable_frame_diff(true);
+    }
+
+    if let Some(value) = matches.value_of("diff-threshold") {
+        builder = builder.diff_threshold(parse_diff_threshold(value)?);
+    }
+
+    if is_given(matches, "backend") {
+        builder = builder.backend_type(match matches.value_of("backend").unwrap() {
+            "cpu" => BackendType::Cpu,
+            "d3d" => BackendType::Direct3D,
+            _ => unreachable!(),
+        });
+    }
+
+    if is_given(matches, "dither") {
+        builder = builder.dither(match matches.value_of("dither").unwrap() {
+            "none" => Dither::None,
+            "floyd-steinberg" => Dither::FloydSteinberg,
+            "atkinson" => Dither::Atkinson,
+            "sierra" => Dither::Sierra,
+            "bayer2" => Dither::Bayer2,
+            "bayer4" => Dither::Bayer4,
+            "bayer8" => Dither::Bayer8,
+            "blue-noise" => Dither::BlueNoise,
+            _ => unreachable!(),
+        });
+    }
+
+    // Any of the palette options replaces the palette from the config
+    if PALETTE_ARGS.iter().any(|name| is_given(matches, name)) {
+        builder = builder.palette(parse_palette(matches)?);
+    }
 
+    if let Some(value) = matches.value_of("local-palettes") {
+        let threshold = parse_value(value, "Invalid local palette threshold value")?;
+        builder = builder.local_palette_threshold(Some(threshold));
+    }
+
+    if matches.is_present("merge-short-frames") {
+        builder = builder.merge_short_frames(true);
+    }
+    if is_given(matches, "loop") {
+        builder = builder.loop_count(parse_loop_count(matches.value_of("loop").unwrap())?);
+    }
+    if let Some(value) = matches.value_of("hold") {
+        builder = builder.final_hold(parse_milliseconds(value, "Invalid hold value")?);
+    }
+    if let Some(value) = matches.value_of("initial-delay") {
+        builder = builder.initial_delay(parse_milliseconds(value, "Invalid initial delay value")?);
+    }
+
+    let output_file = matches.value_of("OUTPUT FILE").unwrap();
+
+    // Without a format from either the command line or the config, the file
+    // extension picks one
+    let format = match matches.value_of("format") {
+        Some("gif") => Some(OutputFormat::Gif),
+        Some("apng") => Some(OutputFormat::Apng),
+        Some("apng-indexed") => Some(OutputFormat::IndexedApng),
+        Some("webp") => Some(OutputFormat::WebP(WebPQuality::Lossless)),
+        Some("png-frames") => Some(OutputFormat::FrameDump {
+            directory: PathBuf::new(),
+        }),
+        Some(_) => unreachable!(),
+        None if config_has_format => None,
+        None => Some(OutputFormat::from_path(output_file).unwrap_or_default()),
+    };
+    if let Some(format) = format {
+        builder = builder.format(format);
+    }
+    let mut encoder_options = builder.build();
+
+    if let Some(value) = matches.value_of("webp-quality") {
+        let value: u8 = parse_value(value, "Invalid WebP quality value")?;
+        if value > 100 {
+            return Err(invalid_options(
+                "The WebP quality must be between 0 and 100",
+            ));
+        }
+        if let OutputFormat::WebP(quality) = &mut encoder_options.format {
+            *quality = WebPQuality::Lossy(value);
+        }
+    }
+
+    // Frame dumps go to the output directory, with the manifest next to the
+    // frames
+    let output_file = match &mut encoder_options.format {
+        OutputFormat::FrameDump { directory } => {
+            *directory = PathBuf::from(output_file);
+            directory
+                .join(FRAME_DUMP_MANIFEST)
+                .to_string_lossy()
+                .into_owned()
+        }
+        _ => output_file.to_owned(),
+    };
+
+    Ok(CliOptions {
+        mode,
+        output_file,
+        encoder_options,
+    })
+}
+
+/// Encoder options loaded from a JSON config file.
+struct Config {
+    options: EncoderOptions,
+    /// Whether the config picks the output format, rather than leaving it to
+    /// the extension of the output file.
+    has_format: bool,
+}
+
+fn load_config(path: &str) -> Result<Config> {
+    let invalid_config = |error: serde_json::Error| {
+        invalid_options(&format!("Invalid config file {}: {}", path, error))
+    };
+    let contents = std::fs::read_to_string(path)?;
+    let value: serde_json::Value = serde_json::from_str(&contents).map_err(invalid_config)?;
+    let has_format = value.get("format").is_some();
+    // Fields the config leaves out keep their defaults
+    let options = serde_json::from_value(value).map_err(invalid_config)?;
+    Ok(Config {
+        options,
+        has_format,
+    })
+}
+
+/// Whether the argument was given on the command line, rather than taking
+/// its default value.
+fn is_given(matches: &ArgMatches, name: &str) -> bool {
+    matches.occurrences_of(name) > 0
+}
+
+fn parse_capture_type(matches: &ArgMatches) -> Result<CaptureType> {
     let capture_type = if let Some(value) = matches.value_of("display") {
-        let display_index: usize = value.parse().expect("Invalid display index value!");
-        let display_handle = get_display_handle_from_index(display_index)
-            .expect("Could not find a monitor with a matching index.");
+        let display_index: usize = parse_value(value, "Invalid display index value")?;
+        let display_handle = get_display_handle_from_index(display_index).ok_or_else(|| {
+            invalid_options(&format!("No monitor found with index {}", display_index))
+        })?;
         CaptureType::Monitor(display_handle)
     } else if let Some(window_query) = matches.value_of("window") {
         let window_info = get_window_from_query(window_query)?;
         CaptureType::Window(window_info.handle)
     } else {
         // Default to recording the primary monitor
-        let display_handle = get_display_handle_from_index(0).expect("No monitors detected!");
+        let display_handle = get_display_handle_from_index(0)
+            .ok_or_else(|| invalid_options("No monitors detected"))?;
         CaptureType::Monitor(display_handle)
     };
+    Ok(capture_type)
+}
 
-    let disable_frame_diff = if cfg!(feature = "debug") {
-        matches.is_present("nodiff")
+fn parse_capture_frame_rate(value: &str) -> Result<FrameRate> {
+    if value == "unlimited" {
+        return Ok(FrameRate::Unlimited);
+    }
+    let frame_rate: f64 = parse_value(value, "Invalid frame rate value")?;
+    if !(frame_rate > 0.0 && frame_rate.is_finite()) {
+        return Err(invalid_options("The frame rate must be a positive number"));
+    }
+    Ok(FrameRate::PerSecond(frame_rate))
+}
+
+fn parse_palette(matches: &ArgMatches) -> Result<Palette> {
+    // A palette file overrides --palette
+    if let Some(path) = matches.value_of("palette-file") {
+        return Ok(Palette::Fixed(load_palette_file(path)?));
+    }
+    let palette = match matches.value_of("palette").unwrap() {
+        "default" => Palette::default(),
+        "adaptive" => {
+            let sampling = match matches.value_of("palette-frames").unwrap() {
+                "all" => PaletteSampling::AllFrames,
+                value => PaletteSampling::FirstFrames(parse_value(
+                    value,
+                    "Invalid palette frame count value",
+                )?),
+            };
+            let algorithm = match matches.value_of("palette-algorithm").unwrap() {
+                "median-cut" => PaletteAlgorithm::MedianCut,
+                "octree" => PaletteAlgorithm::Octree,
+                "kmeans" => PaletteAlgorithm::KMeans,
+                _ => unreachable!(),
+            };
+            let max_colors: usize = parse_value(
+                matches.value_of("colors").unwrap(),
+                "Invalid color count value",
+            )?;
+            if !(2..=256).contains(&max_colors) {
+                return Err(invalid_options("The color count must be between 2 and 256"));
+            }
+            Palette::Adaptive {
+                algorithm,
+                sampling,
+                max_colors,
+            }
+        }
+        _ => unreachable!(),
+    };
+    Ok(palette)
+}
+
+/// A plain number is a per-channel delta, "de:" is followed by a CIELAB
+/// distance.
+fn parse_diff_threshold(value: &str) -> Result<DiffThreshold> {
+    if let Some(distance) = value.strip_prefix("de:") {
+        let distance: f32 = parse_value(distance, "Invalid CIELAB diff threshold value")?;
+        if distance < 0.0 {
+            return Err(invalid_options(
+                "The CIELAB diff threshold can't be negative",
+            ));
+        }
+        Ok(DiffThreshold::DeltaE(distance))
     } else {
-        false
+        let delta: u8 = parse_value(
+            value,
+            "Invalid diff threshold value, expected a number from 0 to 255",
+        )?;
+        Ok(DiffThreshold::Channel(delta))
+    }
+}
+
+fn parse_loop_count(value: &str) -> Result<LoopCount> {
+    let loop_count = match value {
+        "infinite" => LoopCount::Infinite,
+        "none" => LoopCount::Finite(0),
+        value => LoopCount::Finite(parse_value(
+            value,
+            "Invalid loop count value, expected a number from 0 to 65535",
+        )?),
     };
+    Ok(loop_count)
+}
 
-    let output_file = matches.value_of("OUTPUT FILE").unwrap();
+fn parse_milliseconds(value: &str, message: &str) -> Result<Duration> {
+    Ok(Duration::from_millis(parse_value(value, message)?))
+}
 
-    Ok(CliOptions {
-        capture_type,
-        output_file: output_file.to_owned(),
-        disable_frame_diff,
+fn parse_encode_options(matches: &ArgMatches) -> Result<EncodeOptions> {
+    let input = matches.value_of("INPUT").unwrap();
+
+    let frame_rate = matches
+        .value_of("fps")
+        .map(|value| parse_value(value, "Invalid frame rate value"))
+        .transpose()?
+        .unwrap_or(DEFAULT_FRAME_RATE);
+
+    // Streams from stdin are assumed to be YUV4MPEG2 unless told otherwise
+    let format = matches.value_of("input-format").unwrap_or_else(|| {
+        if input == "-" || input.to_lowercase().ends_with(".y4m") {
+            "y4m"
+        } else {
+            "images"
+        }
+    });
+    let input_format = match format {
+        "images" => InputFormat::Images(parse_frame_timing(matches, frame_rate)?),
+        "y4m" => InputFormat::Y4m,
+        "bgra" | "rgba" => {
+            let frame_size = parse_frame_size(matches.value_of("size").unwrap())?;
+            let pixel_format = if format == "bgra" {
+                PixelFormat::Bgra8
+            } else {
+                PixelFormat::Rgba8
+            };
+            InputFormat::Raw {
+                frame_size,
+                pixel_format,
+                frame_rate,
+            }
+        }
+        _ => unreachable!(),
+    };
+
+    Ok(EncodeOptions {
+        input: input.to_owned(),
+        input_format,
     })
 }
 
+fn parse_frame_timing(matches: &ArgMatches, frame_rate: f64) -> Result<FrameTiming> {
+    if let Some(value) = matches.value_of("delays") {
+        let delays = value
+            .split(',')
+            .map(|delay| {
+                let delay = parse_value(delay.trim(), "Invalid frame delay value")?;
+                Ok(Duration::from_millis(delay))
+            })
+            .collect::<Result<_>>()?;
+        Ok(FrameTiming::Delays(delays))
+    } else {
+        Ok(FrameTiming::FrameRate(frame_rate))
+    }
+}
+
+fn parse_frame_size(value: &str) -> Result<FrameSize> {
+    let (width, height) = value
+        .split_once('x')
+        .ok_or_else(|| invalid_options("Invalid frame size value, expected WIDTHxHEIGHT"))?;
+    let width = parse_value(width, "Invalid frame width value")?;
+    let height = parse_value(height, "Invalid frame height value")?;
+    Ok(FrameSize::new(width, height))
+}
+
+fn parse_value<T: FromStr>(value: &str, message: &str) -> Result<T> {
+    value.parse().map_err(|_| invalid_options(message))
+}
+
+fn invalid_options(message: &str) -> Error {
+    Error::InvalidOptions(message.to_owned())
+}
+
 fn build_cli_app() -> App<'static, 'static> {
     let mut app = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
@@ -89,7 +474,186 @@ fn build_cli_app() -> App<'static, 'static> {
                 .help("A shortcut to record the primary display.")
                 .takes_value(false)
                 .conflicts_with_all(&["window", "display"]),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .short("f")
+                .long("fps")
+                .value_name("frame rate")
+                .help("The most frames recorded per second, which can be fractional (e.g. 12.5), or \"unlimited\" to keep every frame. When the screen changes more often, the frame closest to each tick is kept. Defaults to 30.")
+                .takes_value(true),
+        )
+        .arg(build_output_format_arg())
+        .subcommand(build_encode_subcommand())
+        .subcommand(build_palette_subcommand());
+    app = add_encoder_args(app);
+
+    app
+}
+
+fn build_encode_subcommand() -> App<'static, 'static> {
+    let subcommand = SubCommand::with_name("encode")
+        .about("Encodes a sequence of images (PNG, BMP or TGA) or a video stream into a gif.")
+        .arg(
+            Arg::with_name("INPUT")
+                .help("A directory or glob pattern (e.g. \"frames/*.png\") that matches the images to encode, or a video file. Images are ordered by file name. Use \"-\" to read a video stream from stdin.")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("fps")
+                .short("f")
+                .long("fps")
+                .value_name("frame rate")
+                .help("The number of images or raw video frames displayed per second. Defaults to 30.")
+                .takes_value(true)
+                .conflicts_with("delays"),
+        )
+        .arg(
+            Arg::with_name("delays")
+                .long("delays")
+                .value_name("milliseconds")
+                .help("A comma separated list of how long each image is displayed. The last value is used for any remaining images.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("input-format")
+                .long("input-format")
+                .value_name("format")
+                .help("The format of the input. Defaults to y4m for stdin and .y4m files, and to images otherwise. YUV4MPEG2 streams use the frame rate from their header.")
+                .takes_value(true)
+                .possible_values(&["images", "y4m", "bgra", "rgba"]),
+        )
+        .arg(
+            Arg::with_name("size")
+                .short("s")
+                .long("size")
+                .value_name("WIDTHxHEIGHT")
+                .help("The size of the frames in a raw bgra or rgba video stream.")
+                .takes_value(true)
+                .required_ifs(&[("input-format", "bgra"), ("input-format", "rgba")]),
+        )
+        .arg(build_output_format_arg());
+    add_encoder_args(subcommand)
+}
+
+fn build_output_format_arg() -> Arg<'static, 'static> {
+    Arg::with_name("format")
+        .long("format")
+        .value_name("format")
+        .help("The format of the output file. APNGs and WebPs are full color, unless APNGs are indexed to the palette. png-frames writes every frame as an indexed PNG to the output directory, along with a manifest.json. Defaults to apng for .png and .apng files, to webp for .webp files, and to gif otherwise.")
+        .takes_value(true)
+        .possible_values(&["gif", "apng", "apng-indexed", "webp", "png-frames"])
+}
+
+fn build_palette_subcommand() -> App<'static, 'static> {
+    let export = SubCommand::with_name("export")
+        .about("Writes the default palette, a palette file or an adaptive palette to a palette file. The format (GPL, ACT, PAL or hex) is based on the file extension.")
+        .arg(
+            Arg::with_name("from")
+                .long("from")
+                .value_name("input")
+                .help("A directory or glob pattern that matches the images to generate an adaptive palette from, or a YUV4MPEG2 file. Use \"-\" to read a YUV4MPEG2 stream from stdin.")
+                .takes_value(true)
+                .required_if("palette", "adaptive"),
         );
+    let export = add_palette_args(export).arg(
+        Arg::with_name("OUTPUT FILE")
+            .help("The palette file to write (.gpl, .act, .pal or .hex).")
+            .required(true),
+    );
+    SubCommand::with_name("palette")
+        .about("Imports and exports palettes.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(export)
+}
+
+/// Adds the arguments shared by recording and encoding.
+fn add_encoder_args(mut app: App<'static, 'static>) -> App<'static, 'static> {
+    app = app.arg(
+        Arg::with_name("config")
+            .long("config")
+            .value_name("path")
+            .help("A JSON file with the encoder options, like the palette, dithering and output format. Options given on the command line override it.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("backend")
+            .short("b")
+            .long("backend")
+            .value_name("backend")
+            .help("The backend used to diff and quantize frames.")
+            .takes_value(true)
+            .possible_values(&["d3d", "cpu"])
+            .default_value("d3d"),
+    );
+    app = add_palette_args(app).arg(
+        Arg::with_name("local-palettes")
+            .long("local-palettes")
+            .value_name("threshold")
+            .help("Gives frames their own palette when the mean CIELAB distance between their colors and the palette is above the threshold. A threshold of around 5 catches photos and videos.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("diff-threshold")
+            .long("diff-threshold")
+            .value_name("threshold")
+            .help("Ignores pixels that changed by at most this much since they were last encoded. Either the largest change in any channel (0-255), or \"de:\" followed by a CIELAB distance between the palette colors the pixels are quantized to, like \"de:12\". Keeps noise and subtle gradients out of the gif.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("dither")
+            .long("dither")
+            .value_name("dither")
+            .help("Dithers the colors that aren't in the palette, which avoids banding in gradients and shadows. Error diffusion (floyd-steinberg, atkinson and sierra) is done on the CPU. Ordered dithering (bayer and blue-noise) dithers unchanged areas the same way in every frame, so it doesn't get in the way of frame diffing.")
+            .takes_value(true)
+            .possible_values(&[
+                "none",
+                "floyd-steinberg",
+                "atkinson",
+                "sierra",
+                "bayer2",
+                "bayer4",
+                "bayer8",
+                "blue-noise",
+            ])
+            .default_value("none"),
+    )
+    .arg(
+        Arg::with_name("webp-quality")
+            .long("webp-quality")
+            .value_name("quality")
+            .help("Rounds the colors of WebP frames before they're compressed, from 0 (smallest) to 100. WebPs are lossless without it.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("merge-short-frames")
+            .long("merge-short-frames")
+            .help("Merges frames that would be shown for less than 2 hundredths of a second into the next frame. Most browsers slow those frames down to 10 hundredths of a second, which makes fast recordings play back too slowly.")
+            .takes_value(false),
+    )
+    .arg(
+        Arg::with_name("loop")
+            .long("loop")
+            .value_name("count")
+            .help("How many more times the gif plays after the first time: a number, \"infinite\", or \"none\" to play once and stop on the last frame.")
+            .takes_value(true)
+            .default_value("infinite"),
+    )
+    .arg(
+        Arg::with_name("hold")
+            .long("hold")
+            .value_name("milliseconds")
+            .help("How much longer the last frame is shown for.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("initial-delay")
+            .long("initial-delay")
+            .value_name("milliseconds")
+            .help("How long the first frame is shown for before the rest of the gif plays.")
+            .takes_value(true),
+    );
     if cfg!(feature = "debug") {
         app = app.arg(
             Arg::with_name("nodiff")
@@ -99,12 +663,110 @@ fn build_cli_app() -> App<'static, 'static> {
                 .required(false),
         );
     }
-    app = app.arg(
+    app.arg(
         Arg::with_name("OUTPUT FILE")
-            .help("The output file that will contain the gif.")
+            .help("The output file that will contain the gif, APNG or WebP, or the directory png-frames are written to. Use \"-\" to write to stdout.")
             .default_value("recording.gif")
             .required(false),
-    );
+    )
+}
 
-    app
+/// Adds the arguments that pick a palette.
+fn add_palette_args(app: App<'static, 'static>) -> App<'static, 'static> {
+    app.arg(
+        Arg::with_name("palette-file")
+            .long("palette-file")
+            .value_name("path")
+            .help("A palette file (GPL, ACT, PAL or hex) with up to 256 colors. Overrides --palette.")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("palette")
+            .long("palette")
+            .value_name("palette")
+            .help("The palette used for every frame. An adaptive palette is generated from the colors of the frames.")
+            .takes_value(true)
+            .possible_values(&["default", "adaptive"])
+            .default_value("default"),
+    )
+    .arg(
+        Arg::with_name("palette-frames")
+            .long("palette-frames")
+            .value_name("count")
+            .help("The number of frames sampled to generate an adaptive palette, or \"all\" to sample every frame before encoding.")
+            .takes_value(true)
+            .default_value(DEFAULT_PALETTE_FRAMES),
+    )
+    .arg(
+        Arg::with_name("palette-algorithm")
+            .long("palette-algorithm")
+            .value_name("algorithm")
+            .help("The algorithm used to generate an adaptive palette. Octree is the least accurate and k-means (in CIELAB) the most accurate, but also the slowest.")
+            .takes_value(true)
+            .possible_values(&["median-cut", "octree", "kmeans"])
+            .default_value("median-cut"),
+    )
+    .arg(
+        Arg::with_name("colors")
+            .long("colors")
+            .value_name("count")
+            .help("The number of colors in an adaptive palette, from 2 to 256. Smaller palettes make for much smaller gifs of terminals and diagrams.")
+            .takes_value(true)
+            .default_value(DEFAULT_PALETTE_COLORS),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use gifencoder::{
+        Dither, EncoderOptions, FrameRate, LoopCount, OutputFormat, Palette, PaletteAlgorithm,
+        PaletteSampling, WebPQuality,
+    };
+
+    use super::{build_cli_app, parse_matches, CliOptions};
+
+    fn parse(args: &[&str]) -> CliOptions {
+        let args = std::iter::once("giffun").chain(args.iter().copied());
+        parse_matches(&build_cli_app().get_matches_from(args)).unwrap()
+    }
+
+    #[test]
+    fn config_files_round_trip() {
+        let options = EncoderOptions::builder()
+            .palette(Palette::Adaptive {
+                algorithm: PaletteAlgorithm::Octree,
+                sampling: PaletteSampling::FirstFrames(5),
+                max_colors: 32,
+            })
+            .dither(Dither::BlueNoise)
+            .frame_rate(FrameRate::PerSecond(12.5))
+            .loop_count(LoopCount::Finite(3))
+            .final_hold(Duration::from_millis(750))
+            .format(OutputFormat::WebP(WebPQuality::Lossy(80)))
+            .build();
+        let path = std::env::temp_dir().join(format!("giffun-config-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string(&options).unwrap()).unwrap();
+        let config = path.to_str().unwrap();
+
+        // The config's format wins over the file extension
+        let parsed = parse(&["encode", "frames", "--config", config, "out.gif"]);
+        assert_eq!(parsed.encoder_options, options);
+
+        // Options on the command line override the config
+        let parsed = parse(&[
+            "encode", "frames", "--config", config, "--dither", "bayer8", "--loop", "none",
+            "out.gif",
+        ]);
+        assert_eq!(parsed.encoder_options.backend.dither, Dither::Bayer8);
+        assert_eq!(
+            parsed.encoder_options.playback.loop_count,
+            LoopCount::Finite(0)
+        );
+        assert_eq!(parsed.encoder_options.palette, options.palette);
+        assert_eq!(parsed.encoder_options.format, options.format);
+
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -2,10 +2,20 @@ mod capture;
 mod cli;
 mod util;
 
-use std::path::Path;
+use std::{
+    fs::{create_dir_all, File},
+    io::{stdin, stdout, Read, Write},
+    path::Path,
+};
 
-use cli::{parse_cli, CaptureType};
-use gifencoder::{CaptureGifEncoder, DEFAULT_PALETTE};
+use cli::{
+    parse_cli, CaptureOptions, CaptureType, EncodeOptions, ExportPaletteOptions, InputFormat, Mode,
+};
+use gifencoder::{
+    encode_gif, generate_palette, save_palette_file, CaptureFrameSource, CaptureGifEncoder,
+    EncodeSummary, EncoderOptions, Error, FrameSource, FrameTiming, ImageSequenceSource,
+    OutputFormat, Palette, RawVideoSource, Result, Y4mSource,
+};
 use robmikh_common::{
     desktop::{
         capture::{create_capture_item_for_monitor, create_capture_item_for_window},
@@ -14,7 +24,6 @@ use robmikh_common::{
     universal::d3d::create_d3d_device,
 };
 use windows::{
-    core::Result,
     Graphics::SizeInt32,
     System::{DispatcherQueueController, VirtualKey},
     Win32::{
@@ -25,10 +34,10 @@ use windows::{
 
 use crate::util::{dwm::get_window_rect, hotkey::pump_messages};
 
-fn run<P: AsRef<Path>>(
-    capture_type: CaptureType,
-    output_file_path: P,
-    disable_frame_diff: bool,
+fn run(
+    capture_options: CaptureOptions,
+    output: Box<dyn Write + Send>,
+    options: &EncoderOptions,
 ) -> Result<()> {
     unsafe {
         RoInitialize(RO_INIT_MULTITHREADED)?;
@@ -37,7 +46,7 @@ fn run<P: AsRef<Path>>(
         DispatcherQueueController::create_dispatcher_queue_controller_for_current_thread()?;
 
     // Get the capture item
-    let (capture_item, capture_size) = match capture_type {
+    let (capture_item, capture_size) = match capture_options.capture_type {
         CaptureType::Window(window) => {
             let item = create_capture_item_for_window(window)?;
             let window_rect = get_window_rect(window)?;
@@ -56,35 +65,31 @@ fn run<P: AsRef<Path>>(
 
     // Check to see if we're using the debug layer
     if cfg!(feature = "debug") {
-        println!("Using the D3D11 debug layer...");
+        eprintln!("Using the D3D11 debug layer...");
     }
 
     // Init d3d11
     let d3d_device = create_d3d_device()?;
 
-    // Create our palette
-    let palette = &DEFAULT_PALETTE;
+    // Setup capture
+    let source =
+        CaptureFrameSource::new(&d3d_device, capture_item, capture_size, options.frame_rate)?;
+    let capture_session = source.session();
 
     // Create our encoder
-    let mut encoder = CaptureGifEncoder::new(
-        &d3d_device,
-        palette,
-        capture_item,
-        capture_size,
-        output_file_path,
-        disable_frame_diff,
-    )?;
+    let mut encoder = CaptureGifEncoder::new(source, output, options)?;
 
     // Record
     let mut is_recording = false;
-    println!("Press SHIFT+CTRL+R to start/stop the recording...");
+    eprintln!("Press SHIFT+CTRL+R to start/stop the recording...");
     pump_messages(
         MOD_SHIFT | MOD_CONTROL,
         VirtualKey::R.0 as u32,
         || -> Result<bool> {
             Ok(if !is_recording {
                 is_recording = true;
-                println!("Starting recording...");
+                eprintln!("Starting recording...");
+                capture_session.start()?;
                 encoder.start()?;
                 false
             } else {
@@ -92,18 +97,152 @@ fn run<P: AsRef<Path>>(
             })
         },
     )?;
-    println!("Stopping recording...");
-    encoder.stop()?;
+    eprintln!("Stopping recording...");
+    capture_session.stop()?;
+    if let Some(summary) = encoder.stop()? {
+        print_summary(&summary);
+    }
+
+    Ok(())
+}
+
+fn encode(
+    encode_options: EncodeOptions,
+    output: Box<dyn Write + Send>,
+    options: &EncoderOptions,
+) -> Result<()> {
+    let input = encode_options.input;
+    eprintln!("Encoding...");
+    let summary = match encode_options.input_format {
+        InputFormat::Images(frame_timing) => {
+            let source = ImageSequenceSource::new(&input, frame_timing)?;
+            Some(encode_gif(source, output, options)?)
+        }
+        InputFormat::Y4m => {
+            let source = Y4mSource::new(open_input(&input)?)?;
+            encode_stream(source, output, options)?
+        }
+        InputFormat::Raw {
+            frame_size,
+            pixel_format,
+            frame_rate,
+        } => {
+            let source =
+                RawVideoSource::new(open_input(&input)?, frame_size, pixel_format, frame_rate)?;
+            encode_stream(source, output, options)?
+        }
+    };
+    if let Some(summary) = summary {
+        print_summary(&summary);
+    }
+    eprintln!("Done!");
 
     Ok(())
 }
 
+fn open_input(input: &str) -> Result<Box<dyn Read + Send>> {
+    let reader: Box<dyn Read + Send> = if input == "-" {
+        Box::new(stdin())
+    } else {
+        Box::new(File::open(input)?)
+    };
+    Ok(reader)
+}
+
+/// Status messages go to stderr, so they don't end up in the output when it's
+/// written to stdout.
+fn open_output(output: &str) -> Result<Box<dyn Write + Send>> {
+    let writer: Box<dyn Write + Send> = if output == "-" {
+        Box::new(stdout())
+    } else {
+        Box::new(File::create(output)?)
+    };
+    Ok(writer)
+}
+
+/// Streams are read on the encoder thread, the same way captured frames are.
+fn encode_stream<S: FrameSource + 'static>(
+    source: S,
+    output: Box<dyn Write + Send>,
+    options: &EncoderOptions,
+) -> Result<Option<EncodeSummary>> {
+    let mut encoder = CaptureGifEncoder::new(source, output, options)?;
+    encoder.start()?;
+    encoder.stop()
+}
+
+fn export_palette<P: AsRef<Path>>(
+    export_options: ExportPaletteOptions,
+    output_file_path: P,
+    palette: &Palette,
+) -> Result<()> {
+    let colors = match palette {
+        Palette::Fixed(colors) => colors.clone(),
+        Palette::Adaptive {
+            algorithm,
+            sampling,
+            max_colors,
+        } => {
+            // The cli requires an input for adaptive palettes
+            let input = export_options.input.ok_or_else(|| {
+                Error::InvalidOptions("Adaptive palettes need an input to sample".to_owned())
+            })?;
+            eprintln!("Generating a palette...");
+            if input == "-" || input.to_lowercase().ends_with(".y4m") {
+                let mut source = Y4mSource::new(open_input(&input)?)?;
+                generate_palette(&mut source, *algorithm, *sampling, *max_colors)?
+            } else {
+                // The frame rate doesn't matter, the frames are never encoded
+                let mut source = ImageSequenceSource::new(&input, FrameTiming::FrameRate(1.0))?;
+                generate_palette(&mut source, *algorithm, *sampling, *max_colors)?
+            }
+        }
+    };
+    save_palette_file(&output_file_path, &colors)?;
+    eprintln!(
+        "Wrote {} colors to \"{}\"",
+        colors.len() / 3,
+        output_file_path.as_ref().display()
+    );
+    Ok(())
+}
+
+fn print_summary(summary: &EncodeSummary) {
+    if let Some(palette_error) = summary.palette_error {
+        eprintln!(
+            "Generated a palette with a mean quantization error of {:.2} (CIELAB distance)",
+            palette_error
+        );
+    }
+    if let Some([r, g, b]) = summary.dropped_color {
+        eprintln!(
+            "Warning: the palette has 256 colors, so its last color (#{:02x}{:02x}{:02x}) was dropped to make room for transparency. Use a palette of at most 255 colors to pick which one.",
+            r, g, b
+        );
+    }
+}
+
 fn main() -> Result<()> {
     let cli_options = parse_cli()?;
-    run(
-        cli_options.capture_type,
-        &cli_options.output_file,
-        cli_options.disable_frame_diff,
-    )?;
+    if let OutputFormat::FrameDump { directory } = &cli_options.encoder_options.format {
+        create_dir_all(directory)?;
+    }
+    match cli_options.mode {
+        Mode::Capture(capture_options) => run(
+            capture_options,
+            open_output(&cli_options.output_file)?,
+            &cli_options.encoder_options,
+        )?,
+        Mode::Encode(encode_options) => encode(
+            encode_options,
+            open_output(&cli_options.output_file)?,
+            &cli_options.encoder_options,
+        )?,
+        Mode::ExportPalette(export_options) => export_palette(
+            export_options,
+            &cli_options.output_file,
+            &cli_options.encoder_options.palette,
+        )?,
+    }
     Ok(())
 }
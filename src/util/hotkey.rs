@@ -32,11 +32,11 @@ impl Drop for HotKey {
     }
 }
 
-pub fn pump_messages<F: FnMut() -> Result<bool>>(
+pub fn pump_messages<E: From<windows::core::Error>, F: FnMut() -> std::result::Result<bool, E>>(
     modifiers: HOT_KEY_MODIFIERS,
     key: u32,
     mut hot_key_callback: F,
-) -> Result<()> {
+) -> std::result::Result<(), E> {
     let _hot_key = HotKey::new(modifiers, key)?;
     unsafe {
         let mut message = MSG::default();
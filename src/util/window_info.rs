@@ -1,6 +1,6 @@
 use std::io::Write;
 
-use windows::core::Result;
+use gifencoder::{Error, Result};
 use windows::Win32::Foundation::HWND;
 use windows::Win32::UI::WindowsAndMessaging::{
     GetClassNameW, GetWindowTextW, GetWindowThreadProcessId,
@@ -51,30 +51,32 @@ fn truncate_to_first_null_char(input: &mut String) {
 pub fn get_window_from_query(query: &str) -> Result<WindowInfo> {
     let windows = find_window(query);
     let window = if windows.len() == 0 {
-        println!("No window matching '{}' found!", query);
-        std::process::exit(1);
+        return Err(Error::InvalidOptions(format!(
+            "No window matching '{}' found",
+            query
+        )));
     } else if windows.len() == 1 {
         &windows[0]
     } else {
-        println!(
+        eprintln!(
             "{} windows found matching '{}', please select one:",
             windows.len(),
             query
         );
-        println!("    Num       PID    Window Title");
+        eprintln!("    Num       PID    Window Title");
         for (i, window) in windows.iter().enumerate() {
             let mut pid = 0;
             unsafe { GetWindowThreadProcessId(window.handle, &mut pid) };
-            println!("    {:>3}    {:>6}    {}", i, pid, window.title);
+            eprintln!("    {:>3}    {:>6}    {}", i, pid, window.title);
         }
         let index: usize;
         loop {
-            print!("Please make a selection (q to quit): ");
-            std::io::stdout().flush().unwrap();
+            eprint!("Please make a selection (q to quit): ");
+            std::io::stderr().flush()?;
             let mut input = String::new();
-            std::io::stdin().read_line(&mut input).unwrap();
+            std::io::stdin().read_line(&mut input)?;
             if input.to_lowercase().contains("q") {
-                std::process::exit(0);
+                return Err(Error::InvalidOptions("No window was selected".to_owned()));
             }
             let input = input.trim();
             let selection: Option<usize> = match input.parse::<usize>() {
@@ -91,7 +93,7 @@ pub fn get_window_from_query(query: &str) -> Result<WindowInfo> {
                 index = selection;
                 break;
             } else {
-                println!("Invalid input, '{}'!", input);
+                eprintln!("Invalid input, '{}'!", input);
                 continue;
             };
         }